@@ -23,7 +23,7 @@ async fn use_forever() -> Result<()> {
     let handle = mux_server::run("0.0.0.0:1234", service).await?;
     info!("DataDiscarder services now being allocated on demand");
 
-    let _ = handle.await?;
+    handle.await?;
 
     Ok(())
 }
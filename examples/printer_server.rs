@@ -10,7 +10,7 @@ async fn serve_one_resource() -> Result<()> {
     let handle = mux_server::run("0.0.0.0:1234", service).await?;
     info!("Letting the one resource printer service run forever");
 
-    let _ = handle.await?;
+    handle.await?;
     info!("Done serving one resource");
 
     Ok(())
@@ -38,7 +38,7 @@ async fn serve_many_resources() -> Result<()> {
     let handle = mux_server::run("0.0.0.0:1235", service).await?;
     info!("Letting the many resources printers allocator run forever");
 
-    let _ = handle.await?;
+    handle.await?;
     info!("Done serving many resource");
 
     Ok(())
@@ -53,8 +53,8 @@ async fn main() -> Result<()> {
     info!("Running server");
 
     let (single_result, multi_result) = tokio::join!(serve_one_resource(), serve_many_resources());
-    let _ = single_result?;
-    let _ = multi_result?;
+    single_result?;
+    multi_result?;
 
     Ok(())
 }
@@ -1,18 +1,38 @@
 use std::{
+    collections::{HashMap, VecDeque},
     fmt::{Debug, Display},
     pin::Pin,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
     task::{Context, Poll},
+    time::Duration,
 };
 
-use futures::TryFutureExt;
+use futures::{
+    future::{AbortHandle, Abortable},
+    TryFutureExt,
+};
 use futures_core::Future;
-use serde::{de::DeserializeOwned, Serialize};
-use tokio::sync::{AcquireError, OwnedSemaphorePermit, Semaphore};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::{
+    sync::{mpsc, oneshot, watch, AcquireError, OwnedSemaphorePermit, Semaphore},
+    task::JoinHandle,
+    time::Instant,
+};
 use tower::{buffer::Buffer, Service};
-use tracing::{debug, error, info_span, Instrument};
+use tracing::{debug, error, info_span, warn, Instrument};
 
-use crate::{mux_server, resource_filter::Describable};
+use crate::{
+    journal::{Event, Journal},
+    metrics,
+    mux_server::{self, Activity, SessionEnd},
+    resource_filter::Describable,
+    selection::{Candidate, FirstFree, SelectionStrategy},
+    tagged::random_id,
+    transport::{PeerIdentity, TransportConfig},
+};
 
 pub struct Resource<S, Req, D>
 where
@@ -26,6 +46,18 @@ where
     inner: Buffer<S, Req>,
     semaphore: Arc<Semaphore>,
     description: D,
+    id: String,
+    index: usize,
+    usage: Arc<Mutex<ResourceUsage>>,
+}
+
+/// Bookkeeping used by selection strategies.
+/// Kept in tokio's time, such that it follows along when time is paused in tests.
+#[derive(Debug, Default)]
+struct ResourceUsage {
+    last_released: Option<Instant>,
+    total_held: Duration,
+    leases: usize,
 }
 
 impl<S, Req, D> Debug for Resource<S, Req, D>
@@ -39,6 +71,7 @@ where
 {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Resource")
+            .field("id", &self.id)
             .field("semaphore", &self.semaphore)
             .field("description", &self.description)
             .finish()
@@ -54,16 +87,23 @@ where
     S::Error: Send + Sync + Into<tower::BoxError>,
     D: PartialEq,
 {
-    fn new(resource: S) -> Self {
+    /// The index is the resource's position in the allocator,
+    /// used as its identity if the resource does not provide one.
+    fn new(index: usize, resource: S) -> Self {
         let description = resource.describe();
+        let id = resource.id().unwrap_or_else(|| index.to_string());
         Self {
             inner: Buffer::new(resource, 32),
             semaphore: Arc::new(Semaphore::new(1)),
             description,
+            id,
+            index,
+            usage: Default::default(),
         }
     }
 
-    async fn acquire(&self) -> (Buffer<S, Req>, OwnedSemaphorePermit) {
+    /// Wait for exclusive use of the resource.
+    async fn acquire(self) -> (Self, OwnedSemaphorePermit) {
         let owned_semaphore = match self
             .semaphore
             .clone()
@@ -75,7 +115,27 @@ where
             Err(_) => unreachable!("The semaphore will never close"),
         };
 
-        (self.inner.clone(), owned_semaphore)
+        (self, owned_semaphore)
+    }
+
+    /// Get exclusive use of the resource if it's free right now.
+    fn try_acquire(&self) -> Option<OwnedSemaphorePermit> {
+        self.semaphore.clone().try_acquire_owned().ok()
+    }
+
+    fn is_free(&self) -> bool {
+        self.semaphore.available_permits() > 0
+    }
+
+    fn candidate(&self) -> Candidate<'_> {
+        let usage = lock(&self.usage);
+        Candidate {
+            id: &self.id,
+            index: self.index,
+            last_released: usage.last_released.map(Instant::into_std),
+            total_held: usage.total_held,
+            leases: usage.leases,
+        }
     }
 }
 
@@ -93,6 +153,9 @@ where
             inner: self.inner.clone(),
             semaphore: self.semaphore.clone(),
             description: self.description.clone(),
+            id: self.id.clone(),
+            index: self.index,
+            usage: self.usage.clone(),
         }
     }
 }
@@ -109,6 +172,300 @@ where
 {
     num_times_called: usize,
     resources: Vec<Resource<S, Req, D>>,
+    lease_retries: usize,
+    // Where and how leased resources are served.
+    lease_bind: String,
+    lease_transport: TransportConfig,
+    quarantine: Option<Duration>,
+    journal: Option<Journal>,
+    // Allocations currently waiting for a resource, by the id the client gave them.
+    waiting: Arc<Mutex<HashMap<u64, AbortHandle>>>,
+    // If set, clients prefer the resource they had last time for this long.
+    affinity_memory: Option<Duration>,
+    // The resource each client label had last, most recent allocation last.
+    last_resource: Arc<Mutex<VecDeque<(String, String)>>>,
+    clients_remembered: usize,
+    strategy: Arc<dyn SelectionStrategy>,
+    match_policy: MatchPolicy,
+    max_waiters: Option<usize>,
+    max_waiters_per_description: Option<usize>,
+    queue: Arc<Mutex<QueueCounts>>,
+    drain: Arc<Drain>,
+    // Transfer tickets handed out, and the lease each is for.
+    tickets: Arc<Mutex<HashMap<u64, u64>>>,
+    transfer_timeout: Duration,
+    idle_timeout: Option<Duration>,
+    accept_timeout: Duration,
+    // Why recent leases were closed by the allocator, by lease.
+    closed: Arc<Mutex<VecDeque<(u64, CloseReason)>>>,
+    events: Arc<EventFeed<D>>,
+    access_policy: Option<AccessPolicy<D>>,
+    // Keeps the resources counted in the metrics while the allocator is around.
+    _counted: Vec<metrics::GaugeGuard>,
+}
+
+/// Decides whether a client may use resources matching a description.
+#[allow(clippy::type_complexity)]
+struct AccessPolicy<D>(Arc<dyn Fn(Option<&PeerIdentity>, &D) -> bool + Send + Sync>);
+
+impl<D> Clone for AccessPolicy<D> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<D> AccessPolicy<D> {
+    // Whether the client whose request is being served may use resources matching the
+    // description.
+    fn allows(&self, description: &D) -> bool {
+        let peer = PeerIdentity::current();
+        let allowed = (self.0)(peer.as_ref(), description);
+        if !allowed {
+            debug!(?peer, "Access denied");
+        }
+        allowed
+    }
+}
+
+impl<D> Debug for AccessPolicy<D> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("AccessPolicy")
+    }
+}
+
+/// How many close reasons are remembered for clients to ask about.
+const CLOSE_REASONS_KEPT: usize = 1024;
+
+/// How many clients' last resources are remembered by default,
+/// see [`AllocatorService::with_affinity_memory`].
+const CLIENTS_REMEMBERED: usize = 1024;
+
+/// How many recent events are kept for subscribers which fell behind.
+const EVENTS_KEPT: usize = 1024;
+
+/// Recent events, numbered in the order they happened, for subscribers to follow.
+#[derive(Debug)]
+struct EventFeed<D> {
+    events: Mutex<VecDeque<(u64, AllocatorEvent<D>)>>,
+    // The number of the latest event.
+    latest: watch::Sender<u64>,
+}
+
+impl<D: Clone> EventFeed<D> {
+    fn new() -> Self {
+        Self {
+            events: Default::default(),
+            latest: watch::Sender::new(0),
+        }
+    }
+
+    fn publish(&self, event: AllocatorEvent<D>) {
+        // Numbered while holding the lock, such that events are kept in order.
+        let mut events = lock(&self.events);
+        let number = *self.latest.borrow() + 1;
+        if events.len() >= EVENTS_KEPT {
+            events.pop_front();
+        }
+        events.push_back((number, event));
+        self.latest.send_replace(number);
+    }
+
+    /// Wait for events after the given one which pass the filter.
+    /// Without a starting point, tells where the feed is right away.
+    async fn next(
+        &self,
+        after: Option<u64>,
+        filter: impl Fn(&AllocatorEvent<D>) -> bool,
+    ) -> EventBatch<D> {
+        let mut latest = self.latest.subscribe();
+        let now = *latest.borrow_and_update();
+        let Some(after) = after else {
+            return EventBatch {
+                events: vec![],
+                last: now,
+                missed: 0,
+            };
+        };
+        // A cursor from the future, e.g. from before the allocator restarted.
+        let mut after = after.min(now);
+
+        loop {
+            // Can't fail, the sender lives as long as `self`.
+            let _ = latest.wait_for(|latest| *latest > after).await;
+
+            let events = lock(&self.events);
+            let oldest = events.front().map_or(after + 1, |(number, _)| *number);
+            let missed = oldest.saturating_sub(after + 1);
+            let matching = events
+                .iter()
+                .filter(|(number, event)| *number > after && filter(event))
+                .map(|(_, event)| event.clone())
+                .collect::<Vec<_>>();
+            after = events.back().map_or(after, |(number, _)| *number);
+
+            if !matching.is_empty() || missed > 0 {
+                return EventBatch {
+                    events: matching,
+                    last: after,
+                    missed,
+                };
+            }
+        }
+    }
+}
+
+/// What a graceful shutdown needs to know about an allocator.
+#[derive(Debug)]
+struct Drain {
+    shutting_down: AtomicBool,
+    // Leases which have not ended yet.
+    leases: watch::Sender<HashMap<u64, LeaseHandle>>,
+}
+
+impl Drain {
+    fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::SeqCst)
+    }
+}
+
+/// Shuts down an allocator gracefully, see [`AllocatorService::shutdown_handle`].
+#[derive(Debug, Clone)]
+pub struct ShutdownHandle {
+    drain: Arc<Drain>,
+    waiting: Arc<Mutex<HashMap<u64, AbortHandle>>>,
+}
+
+impl ShutdownHandle {
+    /// Shut down the allocator:
+    ///
+    /// 1. New allocations are rejected with [`AllocatorError::ShuttingDown`].
+    /// 2. Allocations waiting for a resource are answered with the same.
+    /// 3. Active leases may finish within the grace period.
+    /// 4. Leases still active after that are closed.
+    ///
+    /// Returns how many leases had to be closed.
+    ///
+    /// The allocator does not own the listener it's served on, which keeps accepting
+    /// connections until its task is aborted. To free the address, e.g. for serving
+    /// another allocator there, abort the handle [`mux_server::run`] gave once this returns.
+    pub async fn shutdown(&self, grace: Duration) -> usize {
+        self.drain.shutting_down.store(true, Ordering::SeqCst);
+
+        let waiting = lock(&self.waiting).drain().collect::<Vec<_>>();
+        debug!(waiting = waiting.len(), "Shutting down allocator");
+        for (_, handle) in waiting {
+            handle.abort();
+        }
+
+        let mut leases = self.drain.leases.subscribe();
+        let finished = tokio::time::timeout(grace, leases.wait_for(HashMap::is_empty))
+            .await
+            .is_ok();
+        if finished {
+            return 0;
+        }
+
+        let mut closed = 0;
+        for lease in self.drain.leases.borrow().values() {
+            let close = LeaseCommand::Close(Some(CloseReason::ShuttingDown));
+            closed += usize::from(lease.commands.send(close).is_ok());
+        }
+        warn!(%closed, "Grace period over, closed remaining leases");
+        closed
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.drain.is_shutting_down()
+    }
+}
+
+/// How many allocations are waiting for a resource, in total and by description.
+#[derive(Debug, Default)]
+struct QueueCounts {
+    total: usize,
+    per_description: HashMap<String, usize>,
+}
+
+/// The wait queue for one allocation's description, with the limits on it.
+struct WaitQueue {
+    counts: Arc<Mutex<QueueCounts>>,
+    description: String,
+    max_waiters: Option<usize>,
+    max_waiters_per_description: Option<usize>,
+    // How many are waiting, as told to the metrics.
+    depth: metrics::Gauge,
+}
+
+impl WaitQueue {
+    /// Take a place in the queue, unless it's full.
+    fn enter(&self) -> Option<QueuePlace> {
+        let mut queue = lock(&self.counts);
+        let for_description = queue
+            .per_description
+            .get(&self.description)
+            .copied()
+            .unwrap_or_default();
+        if self.max_waiters.is_some_and(|max| queue.total >= max)
+            || self
+                .max_waiters_per_description
+                .is_some_and(|max| for_description >= max)
+        {
+            return None;
+        }
+
+        queue.total += 1;
+        *queue
+            .per_description
+            .entry(self.description.clone())
+            .or_default() += 1;
+        Some(QueuePlace {
+            queue: self.counts.clone(),
+            description: self.description.clone(),
+            _depth: self.depth.track(),
+        })
+    }
+
+    /// Make sure the allocation has a place in the queue before it waits,
+    /// taking one the first time.
+    fn join(&self, place: &mut Option<QueuePlace>) -> Result<(), AllocatorError> {
+        if place.is_none() {
+            *place = Some(self.enter().ok_or(AllocatorError::QueueFull)?);
+        }
+        Ok(())
+    }
+}
+
+/// An allocation which may be cancelled while it waits, see [`AllocatorRequest::Cancel`].
+/// Forgotten when dropped, however the allocation ended.
+struct Registered {
+    waiting: Arc<Mutex<HashMap<u64, AbortHandle>>>,
+    id: u64,
+}
+
+impl Drop for Registered {
+    fn drop(&mut self) {
+        lock(&self.waiting).remove(&self.id);
+    }
+}
+
+/// A place in the wait queue, given back when dropped.
+struct QueuePlace {
+    queue: Arc<Mutex<QueueCounts>>,
+    description: String,
+    _depth: metrics::GaugeGuard,
+}
+
+impl Drop for QueuePlace {
+    fn drop(&mut self) {
+        let mut queue = lock(&self.queue);
+        queue.total = queue.total.saturating_sub(1);
+        if let Some(count) = queue.per_description.get_mut(&self.description) {
+            *count = count.saturating_sub(1);
+            if *count == 0 {
+                queue.per_description.remove(&self.description);
+            }
+        }
+    }
 }
 
 impl<S, Req, D> AllocatorService<S, Req, D>
@@ -118,20 +475,611 @@ where
     Req: Send + 'static,
     S::Future: Send,
     S::Error: Send + Sync + Into<tower::BoxError>,
-    D: PartialEq + Clone,
+    D: PartialEq + Clone + Debug,
 {
     pub fn new(resources: Vec<S>) -> Self {
+        Self::new_with_strategy(resources, FirstFree)
+    }
+
+    /// Create an allocator which uses the given strategy to choose
+    /// among several free resources matching a request.
+    /// See [`crate::selection`] for the built-in ones.
+    pub fn new_with_strategy(
+        resources: Vec<S>,
+        strategy: impl SelectionStrategy + 'static,
+    ) -> Self {
+        let resources = resources
+            .into_iter()
+            .enumerate()
+            .map(|(index, resource)| Resource::new(index, resource))
+            .collect::<Vec<_>>();
+        let counted = resources
+            .iter()
+            .map(|resource| {
+                let description = format!("{:?}", resource.description);
+                metrics::gauge(metrics::RESOURCES_TOTAL, &[("description", &description)]).track()
+            })
+            .collect();
+
         Self {
             num_times_called: 0,
-            resources: resources.into_iter().map(Resource::new).collect(),
+            resources,
+            lease_retries: 0,
+            lease_bind: "0.0.0.0:0".to_string(),
+            lease_transport: TransportConfig::default(),
+            quarantine: None,
+            journal: None,
+            waiting: Default::default(),
+            affinity_memory: None,
+            last_resource: Default::default(),
+            clients_remembered: CLIENTS_REMEMBERED,
+            strategy: Arc::new(strategy),
+            match_policy: MatchPolicy::default(),
+            max_waiters: None,
+            max_waiters_per_description: None,
+            queue: Default::default(),
+            drain: Arc::new(Drain {
+                shutting_down: AtomicBool::new(false),
+                leases: watch::Sender::new(HashMap::new()),
+            }),
+            tickets: Default::default(),
+            transfer_timeout: Duration::from_secs(60),
+            idle_timeout: None,
+            accept_timeout: mux_server::DEFAULT_ACCEPT_TIMEOUT,
+            closed: Default::default(),
+            events: Arc::new(EventFeed::new()),
+            access_policy: None,
+            _counted: counted,
         }
     }
+
+    /// How many extra times to try starting the server for a leased resource
+    /// if the first attempt fails (e.g. because binding failed).
+    /// Defaults to no retries.
+    pub fn with_lease_retries(mut self, retries: usize) -> Self {
+        self.lease_retries = retries;
+        self
+    }
+
+    /// Where to serve leased resources, each on an address of its own.
+    /// Defaults to any free TCP port.
+    /// Use `unix:DIR` for Unix domain sockets in the given directory,
+    /// or `memory:0` when the allocator is served in memory (see [`mux_server::run`]).
+    pub fn with_lease_bind(mut self, bind: &str) -> Self {
+        self.lease_bind = bind.to_string();
+        self
+    }
+
+    /// How connections to leased resources are set up.
+    /// Clients must be configured the same way,
+    /// see [`crate::allocator_client::AllocatorClientService::new_with`].
+    pub fn with_lease_transport(mut self, config: TransportConfig) -> Self {
+        self.lease_transport = config;
+        self
+    }
+
+    /// Keep a resource out of rotation for the given duration if its lease server
+    /// could not be started.
+    /// By default the resource is released right away.
+    pub fn with_quarantine(mut self, duration: Duration) -> Self {
+        self.quarantine = Some(duration);
+        self
+    }
+
+    /// Append allocation events to the given journal.
+    /// See [`crate::journal`] for reading it back.
+    pub fn with_journal(mut self, journal: Journal) -> Self {
+        self.journal = Some(journal);
+        self
+    }
+
+    /// Remember which resource each client (by label) had last,
+    /// and let later allocations from the same client prefer it.
+    /// Other matching resources are settled for if the preferred one is not free
+    /// within `fallback_after`.
+    ///
+    /// An [`Affinity`] given along with an allocation takes precedence.
+    /// Only the clients which allocated most recently are remembered,
+    /// see [`Self::with_clients_remembered`].
+    pub fn with_affinity_memory(mut self, fallback_after: Duration) -> Self {
+        self.affinity_memory = Some(fallback_after);
+        self
+    }
+
+    /// How many clients to remember the last resource of,
+    /// forgetting those which allocated least recently first.
+    /// Defaults to 1024.
+    pub fn with_clients_remembered(mut self, clients: usize) -> Self {
+        self.clients_remembered = clients;
+        self
+    }
+
+    /// Whether to wait for the best-scoring resource when only lesser matches are free,
+    /// see [`Describable::score`].
+    /// Defaults to [`MatchPolicy::BestFree`].
+    ///
+    /// A policy given along with an allocation takes precedence.
+    pub fn with_match_policy(mut self, policy: MatchPolicy) -> Self {
+        self.match_policy = policy;
+        self
+    }
+
+    /// At most this many allocations may wait for a resource at the same time.
+    /// Allocations beyond it are rejected right away with [`AllocatorError::QueueFull`].
+    /// By default there is no limit.
+    pub fn with_max_waiters(mut self, max: usize) -> Self {
+        self.max_waiters = Some(max);
+        self
+    }
+
+    /// Like [`AllocatorService::with_max_waiters`], but counted separately for each
+    /// requested description.
+    pub fn with_max_waiters_per_description(mut self, max: usize) -> Self {
+        self.max_waiters_per_description = Some(max);
+        self
+    }
+
+    /// How long a lease is kept for a transfer after its holder left,
+    /// waiting for the ticket to be redeemed.
+    /// Defaults to a minute.
+    pub fn with_transfer_timeout(mut self, timeout: Duration) -> Self {
+        self.transfer_timeout = timeout;
+        self
+    }
+
+    /// Close leases which have had no traffic for the given duration, freeing their resource.
+    /// The client is told why, see [`AllocatorError::LeaseClosed`].
+    /// By default leases are kept as long as the client is connected.
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// How long to wait for a client to connect to a resource leased out to it.
+    /// If it does not connect in time the resource is freed, and the client is told why
+    /// (see [`AllocatorError::LeaseClosed`]).
+    /// Defaults to [`mux_server::DEFAULT_ACCEPT_TIMEOUT`].
+    ///
+    /// A timeout given along with an allocation takes precedence.
+    pub fn with_accept_timeout(mut self, timeout: Duration) -> Self {
+        self.accept_timeout = timeout;
+        self
+    }
+
+    /// Only grant allocations the given policy allows, others are rejected with
+    /// [`AllocatorError::AccessDenied`].
+    /// The policy is given who the client is, if it connected with mutual TLS
+    /// (see [`crate::transport::PeerIdentity`]), and the description asked for.
+    /// Transferring, redeeming and releasing a lease is checked too, against the
+    /// description of the leased resource.
+    /// By default every client may allocate anything.
+    pub fn with_access_policy(
+        mut self,
+        policy: impl Fn(Option<&PeerIdentity>, &D) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.access_policy = Some(AccessPolicy(Arc::new(policy)));
+        self
+    }
+
+    /// A handle for shutting down the allocator gracefully, once it's being served.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            drain: self.drain.clone(),
+            waiting: self.waiting.clone(),
+        }
+    }
+
+    // The description of the resource a lease is for, while the lease is open.
+    fn lease_description(&self, lease: u64) -> Option<D>
+    where
+        D: Clone,
+    {
+        let leases = self.drain.leases.borrow();
+        let handle = leases.get(&lease)?;
+        self.resources
+            .iter()
+            .find(|resource| resource.id == handle.resource)
+            .map(|resource| resource.description.clone())
+    }
+
+    // Whether the access policy turns away the client acting on a lease of a resource
+    // with the given description. Asked while serving the request, since only then is
+    // the client known. Unknown leases are left to fail as such.
+    fn denied(&self, description: Option<D>) -> impl FnOnce() -> bool + Send + 'static
+    where
+        D: Send + 'static,
+    {
+        let access_policy = self.access_policy.clone();
+        move || {
+            access_policy
+                .zip(description)
+                .is_some_and(|(policy, description)| !policy.allows(&description))
+        }
+    }
+
+    fn transfer(
+        &self,
+        lease: u64,
+    ) -> Pin<Box<dyn Future<Output = Result<AllocatorResponse<D>, AllocatorError>> + Send>>
+    where
+        D: Send + 'static,
+    {
+        let denied = self.denied(self.lease_description(lease));
+        let drain = self.drain.clone();
+        let tickets = self.tickets.clone();
+
+        Box::pin(async move {
+            if denied() {
+                return Ok(Err(AllocatorError::AccessDenied));
+            }
+            let leases = drain.leases.borrow();
+            let Some(handle) = leases.get(&lease) else {
+                return Ok(Err(AllocatorError::UnknownLease));
+            };
+            if handle.commands.send(LeaseCommand::Transfer).is_err() {
+                return Ok(Err(AllocatorError::UnknownLease));
+            }
+
+            let ticket = random_id();
+            lock(&tickets).insert(ticket, lease);
+            debug!(%lease, "Transfer ticket handed out");
+            Ok(Ok(AllocatorReply::Ticket(TransferTicket {
+                ticket,
+                resource: handle.resource.clone(),
+            })))
+        })
+    }
+
+    fn redeem(
+        &self,
+        ticket: u64,
+        client: Option<String>,
+    ) -> Pin<Box<dyn Future<Output = Result<AllocatorResponse<D>, AllocatorError>> + Send>>
+    where
+        D: Send + 'static,
+    {
+        let description = lock(&self.tickets)
+            .get(&ticket)
+            .and_then(|lease| self.lease_description(*lease));
+        let denied = self.denied(description.clone());
+        let drain = self.drain.clone();
+        let tickets = self.tickets.clone();
+        let events = self.events.clone();
+
+        Box::pin(async move {
+            if denied() {
+                return Ok(Err(AllocatorError::AccessDenied));
+            }
+            let redeemed = lock(&tickets).remove(&ticket).and_then(|lease| {
+                let leases = drain.leases.borrow();
+                let handle = leases.get(&lease)?;
+                let description = description?;
+                let (reply, answer) = oneshot::channel();
+                handle
+                    .commands
+                    .send(LeaseCommand::Redeem {
+                        client: client.clone(),
+                        reply,
+                    })
+                    .ok()?;
+                Some((lease, handle.resource.clone(), description, answer))
+            });
+            let Some((lease, resource, description, answer)) = redeemed else {
+                return Ok(Err(AllocatorError::UnknownTicket));
+            };
+            let (port, address) = match answer.await {
+                Ok(Ok(served)) => served,
+                Ok(Err(e)) => return Ok(Err(e)),
+                Err(_) => return Ok(Err(AllocatorError::UnknownTicket)),
+            };
+            events.publish(AllocatorEvent::Granted {
+                description,
+                resource: resource.clone(),
+                client,
+            });
+            Ok(Ok(AllocatorReply::Granted(Grant {
+                port,
+                address,
+                resource,
+                lease,
+            })))
+        })
+    }
+
+    fn release(
+        &self,
+        lease: u64,
+    ) -> Pin<Box<dyn Future<Output = Result<AllocatorResponse<D>, AllocatorError>> + Send>>
+    where
+        D: Send + 'static,
+    {
+        let denied = self.denied(self.lease_description(lease));
+        let drain = self.drain.clone();
+
+        Box::pin(async move {
+            if denied() {
+                return Ok(Err(AllocatorError::AccessDenied));
+            }
+            let closing = drain
+                .leases
+                .borrow()
+                .get(&lease)
+                .is_some_and(|handle| handle.commands.send(LeaseCommand::Close(None)).is_ok());
+            let mut leases = drain.leases.subscribe();
+            if !closing {
+                return Ok(Err(AllocatorError::UnknownLease));
+            }
+            // Leases are forgotten only after their resource is free.
+            let _ = leases.wait_for(|leases| !leases.contains_key(&lease)).await;
+            debug!(%lease, "Lease released");
+            Ok(Ok(AllocatorReply::Done))
+        })
+    }
+
+    fn discover(&self) -> Vec<DescriptionSummary<D>> {
+        let mut descriptions: Vec<DescriptionSummary<D>> = vec![];
+        for resource in &self.resources {
+            let position = descriptions
+                .iter()
+                .position(|summary| summary.description == resource.description)
+                .unwrap_or_else(|| {
+                    descriptions.push(DescriptionSummary {
+                        description: resource.description.clone(),
+                        total: 0,
+                        free: 0,
+                    });
+                    descriptions.len() - 1
+                });
+            let summary = &mut descriptions[position];
+            summary.total += 1;
+            summary.free += usize::from(resource.is_free());
+        }
+        descriptions
+    }
 }
 
-#[derive(Debug)]
+/// What clients send to the allocator.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum AllocatorRequest<D> {
+    /// Ask for a resource.
+    Allocate(Allocation<D>),
+    /// Stop waiting for the allocation with the given id.
+    /// The allocation is answered with [`AllocatorError::Cancelled`].
+    Cancel(u64),
+    /// List the distinct descriptions of the resources served,
+    /// answered with [`AllocatorReply::Descriptions`].
+    Discover,
+    /// Ask for a ticket which hands the given lease over to whoever redeems it,
+    /// answered with [`AllocatorReply::Ticket`].
+    Transfer(u64),
+    /// Take over the lease a transfer ticket was handed out for,
+    /// answered with [`AllocatorReply::Granted`].
+    Redeem { ticket: u64, client: Option<String> },
+    /// End the given lease. Answered with [`AllocatorReply::Done`] once the resource is free.
+    Release(u64),
+    /// Ask why the allocator closed the given lease,
+    /// answered with [`AllocatorReply::Closed`].
+    WhyClosed(u64),
+    /// Wait for events after the given one, about resources matching the filter if any.
+    /// Answered with [`AllocatorReply::Events`], right away if no event is given.
+    Events {
+        filter: Option<D>,
+        after: Option<u64>,
+    },
+}
+
+/// A request for a resource.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Allocation<D> {
+    /// Chosen by the client, such that the allocation may be cancelled later.
+    pub id: u64,
+    /// Describes the resource wanted.
+    pub description: D,
+    /// Who is asking. Used for bookkeeping, and for remembering affinity.
+    pub client: Option<String>,
+    pub options: AllocationOptions,
+}
+
+/// Optional hints on how to allocate.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AllocationOptions {
+    pub affinity: Option<Affinity>,
+    /// Overrides the allocator's policy, see [`AllocatorService::with_match_policy`].
+    pub match_policy: Option<MatchPolicy>,
+    /// Overrides the allocator's timeout, see [`AllocatorService::with_accept_timeout`].
+    pub accept_timeout: Option<Duration>,
+}
+
+/// Prefer a specific resource.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Affinity {
+    /// The identity of the preferred resource, see [`Describable::id`].
+    pub resource: String,
+    /// Settle for any matching resource if the preferred one is not free within this time.
+    pub fallback_after: Duration,
+}
+
+/// What to do when the best-scoring matches for a request are busy,
+/// but lesser matches are free.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MatchPolicy {
+    /// Take the best-scoring match which is free right now.
+    /// If none are free, take whichever match is freed first.
+    #[default]
+    BestFree,
+    /// Only lease out the best-scoring matches, waiting for one of them if needed.
+    BestOnly,
+    /// Wait this long for one of the best-scoring matches before settling for a lesser one.
+    PreferBest { settle_after: Duration },
+}
+
+/// A resource leased out to a client.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Grant {
+    /// The port where the allocated service waits for a connection.
+    pub port: u16,
+    /// Where the allocated service waits for a connection, if not by port
+    /// (e.g. a Unix domain socket).
+    pub address: Option<String>,
+    /// The identity of the resource, see [`Describable::id`].
+    pub resource: String,
+    /// Identifies the lease when talking to the allocator about it later.
+    pub lease: u64,
+}
+
+/// Hands a lease over to another client, see [`crate::mux_client::MuxClient::transfer`].
+///
+/// The ticket may be passed on to another process as a string,
+/// see its [`Display`] and [`FromStr`](std::str::FromStr) implementations.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransferTicket {
+    pub ticket: u64,
+    /// The identity of the resource, see [`Describable::id`].
+    pub resource: String,
+}
+
+impl Display for TransferTicket {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:016x}@{}", self.ticket, self.resource)
+    }
+}
+
+impl std::str::FromStr for TransferTicket {
+    type Err = tower::BoxError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (ticket, resource) = s
+            .split_once('@')
+            .ok_or_else(|| format!("Not a transfer ticket: {s}"))?;
+        Ok(Self {
+            ticket: u64::from_str_radix(ticket, 16)?,
+            resource: resource.to_string(),
+        })
+    }
+}
+
+/// How many resources with a given description the allocator serves.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DescriptionSummary<D> {
+    pub description: D,
+    pub total: usize,
+    /// Resources not leased out (or quarantined) right now.
+    pub free: usize,
+}
+
+/// Something that happened to a resource, see [`AllocatorRequest::Events`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AllocatorEvent<D> {
+    /// The resource was leased out, or handed over to a client redeeming a transfer ticket.
+    Granted {
+        description: D,
+        resource: String,
+        client: Option<String>,
+    },
+    /// A lease ended, the resource is free again.
+    Released { description: D, resource: String },
+    /// Leasing out the resource failed, so it is kept out of the pool for a while.
+    /// See [`AllocatorService::with_quarantine`].
+    Quarantined {
+        description: D,
+        resource: String,
+        duration: Duration,
+    },
+    /// The resource was added back to the pool after its quarantine.
+    /// This is the only way resources are added, the pool is fixed when the allocator is made,
+    /// see [`AllocatorRequest::Discover`] for what it serves.
+    Restored { description: D, resource: String },
+}
+
+impl<D> AllocatorEvent<D> {
+    /// The description of the resource the event is about.
+    pub fn description(&self) -> &D {
+        match self {
+            Self::Granted { description, .. }
+            | Self::Released { description, .. }
+            | Self::Quarantined { description, .. }
+            | Self::Restored { description, .. } => description,
+        }
+    }
+}
+
+/// Events sent to a subscriber, see [`AllocatorRequest::Events`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EventBatch<D> {
+    pub events: Vec<AllocatorEvent<D>>,
+    /// The latest event considered, ask for events after this one next.
+    pub last: u64,
+    /// How many events were forgotten before they could be sent,
+    /// because the subscriber fell behind.
+    pub missed: u64,
+}
+
+/// What the allocator answers when a request went well.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AllocatorReply<D> {
+    /// Tells where the allocated service waits for a connection.
+    Granted(Grant),
+    /// There were no matching resources.
+    NoMatch,
+    /// The request was handled, and there is nothing more to tell.
+    Done,
+    /// The distinct descriptions served, in the order first seen among the resources.
+    Descriptions(Vec<DescriptionSummary<D>>),
+    /// Redeem this to take over a lease.
+    Ticket(TransferTicket),
+    /// Why the allocator closed a lease, or `None` if it did not (or has forgotten).
+    Closed(Option<CloseReason>),
+    /// What happened since last asked.
+    Events(EventBatch<D>),
+}
+
+/// Why the allocator closed a lease on its own.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CloseReason {
+    /// There was no traffic for this long, see [`AllocatorService::with_idle_timeout`].
+    Idle(Duration),
+    /// The allocator shut down, see [`ShutdownHandle`].
+    ShuttingDown,
+    /// The client did not connect within this long,
+    /// see [`AllocatorService::with_accept_timeout`].
+    NotConnected(Duration),
+}
+
+impl Display for CloseReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Idle(duration) => write!(f, "idle for {duration:?}"),
+            Self::ShuttingDown => write!(f, "allocator shutting down"),
+            Self::NotConnected(duration) => write!(f, "client did not connect within {duration:?}"),
+        }
+    }
+}
+
+/// The response the allocator sends back to clients.
+/// `Err(_)` tells the client why the request failed.
+pub type AllocatorResponse<D> = std::result::Result<AllocatorReply<D>, AllocatorError>;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum AllocatorError {
     SemaphoreProblem,
     ListenerProblem(String),
+    Cancelled,
+    /// Too many allocations are waiting already, try again later.
+    /// See [`AllocatorService::with_max_waiters`].
+    QueueFull,
+    /// The allocator is shutting down, see [`ShutdownHandle`].
+    ShuttingDown,
+    /// The lease has ended, or never existed.
+    UnknownLease,
+    /// The ticket was already redeemed, expired, or never existed.
+    UnknownTicket,
+    /// The allocator closed the lease.
+    LeaseClosed(CloseReason),
+    /// A subscriber fell behind, and this many events were lost.
+    EventsMissed(u64),
+    /// The client may not allocate what it asked for,
+    /// see [`AllocatorService::with_access_policy`].
+    AccessDenied,
 }
 
 impl From<AcquireError> for AllocatorError {
@@ -148,7 +1096,7 @@ impl Display for AllocatorError {
 
 impl std::error::Error for AllocatorError {}
 
-impl<S, Req, D> Service<D> for AllocatorService<S, Req, D>
+impl<S, Req, D> Service<AllocatorRequest<D>> for AllocatorService<S, Req, D>
 where
     S: Service<Req> + Send + 'static,
     S: Describable<D>,
@@ -158,9 +1106,9 @@ where
     S::Error: Send + Sync + Into<tower::BoxError>,
     D: Debug + Send + Clone + PartialEq + Sync + 'static,
 {
-    // The port where the allocated service waits for a connection,
-    // or `None` if there were no matching resources.
-    type Response = Option<u16>;
+    // Failures are part of the response such that the client is told about them,
+    // instead of tearing down the multiplexed connection.
+    type Response = AllocatorResponse<D>;
     type Error = AllocatorError;
 
     #[allow(clippy::type_complexity)]
@@ -170,57 +1118,829 @@ where
         Poll::Ready(Ok(()))
     }
 
-    fn call(&mut self, request: D) -> Self::Future {
+    fn call(&mut self, request: AllocatorRequest<D>) -> Self::Future {
+        let Allocation {
+            id: allocation_id,
+            description: request,
+            client,
+            options,
+        } = match request {
+            AllocatorRequest::Allocate(allocation) => allocation,
+            AllocatorRequest::Cancel(allocation_id) => {
+                if let Some(handle) = lock(&self.waiting).remove(&allocation_id) {
+                    debug!(%allocation_id, "Cancelling allocation");
+                    handle.abort();
+                }
+                return Box::pin(async { Ok(Ok(AllocatorReply::Done)) });
+            }
+            AllocatorRequest::Discover => {
+                let descriptions = self.discover();
+                return Box::pin(async { Ok(Ok(AllocatorReply::Descriptions(descriptions))) });
+            }
+            AllocatorRequest::Transfer(lease) => return self.transfer(lease),
+            AllocatorRequest::Redeem { ticket, client } => return self.redeem(ticket, client),
+            AllocatorRequest::Release(lease) => return self.release(lease),
+            AllocatorRequest::WhyClosed(lease) => {
+                let reason = lock(&self.closed)
+                    .iter()
+                    .find(|(closed, _)| *closed == lease)
+                    .map(|(_, reason)| reason.clone());
+                return Box::pin(async { Ok(Ok(AllocatorReply::Closed(reason))) });
+            }
+            AllocatorRequest::Events { filter, after } => {
+                let events = self.events.clone();
+                return Box::pin(async move {
+                    let batch = events
+                        .next(after, |event| {
+                            filter.as_ref().is_none_or(|filter| {
+                                S::score(event.description(), filter).is_some()
+                            })
+                        })
+                        .await;
+                    Ok(Ok(AllocatorReply::Events(batch)))
+                });
+            }
+        };
         self.num_times_called += 1;
 
+        if self.drain.is_shutting_down() {
+            debug!("Shutting down, rejecting allocation");
+            return Box::pin(async { Ok(Err(AllocatorError::ShuttingDown)) });
+        }
+
         // This will first filter any resources not matching the request of the caller.
-        // Then we store futures in vector.
-        // The futures will try to acquire the resource by getting the semaphore permit.
-        //
-        // By using `select_ok` on this (further below) we can "race to the finish".
-        // I.e. we're fine with getting hold of the first matching resource, it does not
-        // matter much which it actually is.
-        let matching_services = self
+        // The remaining ones are grouped by score, best first, see `acquire_any`.
+        let mut scored = self
             .resources
             .iter()
-            .filter(|resource| resource.description == request)
-            .cloned()
-            .map(|resource| Box::pin(async move { Ok(resource.acquire().await) }))
+            .filter_map(|resource| Some((S::score(&resource.description, &request)?, resource)))
             .collect::<Vec<_>>();
+        scored.sort_by_key(|(score, _)| std::cmp::Reverse(*score));
+        let mut tiers: Vec<Vec<Resource<S, Req, D>>> = vec![];
+        let mut last_score = None;
+        for (score, resource) in scored {
+            match tiers.last_mut() {
+                Some(tier) if last_score == Some(score) => tier.push(resource.clone()),
+                _ => tiers.push(vec![resource.clone()]),
+            }
+            last_score = Some(score);
+        }
+        let match_policy = options.match_policy.unwrap_or(self.match_policy);
+
+        // An explicit affinity wins over a remembered one.
+        let affinity = options.affinity.or_else(|| {
+            let fallback_after = self.affinity_memory?;
+            let client = client.as_ref()?;
+            let resource = lock(&self.last_resource)
+                .iter()
+                .rev()
+                .find(|(remembered, _)| remembered == client)?
+                .1
+                .clone();
+            Some(Affinity {
+                resource,
+                fallback_after,
+            })
+        });
+        let preferred = affinity.and_then(|affinity| {
+            let resource = tiers
+                .iter()
+                .flatten()
+                .find(|resource| resource.id == affinity.resource)?;
+            Some((resource.clone(), affinity.fallback_after))
+        });
 
         let id = self.num_times_called;
         let label = format!("#{id}-{:?}", request);
+        let lease_retries = self.lease_retries;
+        let lease_bind = self.lease_bind.clone();
+        let lease_transport = self.lease_transport.clone();
+        let quarantine = self.quarantine;
+        let journal = self.journal.clone();
+        let drain = self.drain.clone();
+        let tickets = self.tickets.clone();
+        let transfer_timeout = self.transfer_timeout;
+        let idle_timeout = self.idle_timeout;
+        let accept_timeout = options.accept_timeout.unwrap_or(self.accept_timeout);
+        let closed = self.closed.clone();
+        let events = self.events.clone();
+        let last_resource = self.last_resource.clone();
+        let clients_remembered = self.clients_remembered;
+        let strategy = self.strategy.clone();
+        let access_policy = self.access_policy.clone();
+
+        let description = format!("{request:?}");
+        let labels = [("description", description.as_str())];
+        metrics::counter(metrics::ALLOCATION_REQUESTS, &labels).add(1);
+        let start = Instant::now();
+
+        record(
+            &journal,
+            Event::RequestReceived {
+                request: id,
+                client: client.clone(),
+                description: description.clone(),
+            },
+        );
+
+        // Allocations which can't be granted right away take a place in the queue
+        // once they have to wait, or are turned away if it's full.
+        let queue = WaitQueue {
+            counts: self.queue.clone(),
+            description: description.clone(),
+            max_waiters: self.max_waiters,
+            max_waiters_per_description: self.max_waiters_per_description,
+            depth: metrics::gauge(metrics::ALLOCATION_QUEUE_DEPTH, &labels),
+        };
+
+        // Registered right away, such that a cancellation arriving before the
+        // allocation starts waiting is not lost.
+        let (abort_handle, abort_registration) = AbortHandle::new_pair();
+        let registered = (!tiers.is_empty()).then(|| {
+            lock(&self.waiting).insert(allocation_id, abort_handle);
+            Registered {
+                waiting: self.waiting.clone(),
+                id: allocation_id,
+            }
+        });
 
         Box::pin(
             async move {
-                if matching_services.is_empty() {
-                    Ok(None)
-                } else {
-                    match futures::future::select_ok(matching_services).await {
-                        Ok(((resource, semaphore_permit), _)) => {
-                            let (handle, port) = match mux_server::once("0.0.0.0:0", resource).await
-                            {
-                                Ok((handle, port)) => (handle, port),
-                                Err(e) => todo!("Handle server error {:?}", e),
-                            };
-
-                            tokio::spawn(async move {
-                                match handle.await {
-                                    Ok(()) => debug!("Session done"),
-                                    Err(e) => error!(?e, "Problem awaiting MuxServer"),
-                                };
-                                // This assures the semaphore was moved into this scope,
-                                // and that it drops when the work is done.
-                                drop(semaphore_permit)
-                            });
-
-                            Ok(Some(port))
-                        }
-                        Err(e) => Err(e),
+                // Who the client is is only known while serving its request, not when called.
+                if let Some(policy) = access_policy {
+                    if !policy.allows(&request) {
+                        record(
+                            &journal,
+                            Event::Failed {
+                                request: id,
+                                client,
+                                reason: AllocatorError::AccessDenied.to_string(),
+                            },
+                        );
+                        return Ok(Err(AllocatorError::AccessDenied));
                     }
                 }
+
+                if tiers.is_empty() {
+                    return Ok(Ok(AllocatorReply::NoMatch));
+                }
+
+                let acquired = Abortable::new(
+                    acquire_any(tiers, preferred, strategy, match_policy, &queue),
+                    abort_registration,
+                )
+                .await;
+                drop(registered);
+
+                let (resource, semaphore_permit) = match acquired {
+                    Ok(Ok(acquired)) if !drain.is_shutting_down() => acquired,
+                    Ok(Err(e)) => {
+                        debug!("Queue full, rejecting allocation");
+                        let labels = [("description", queue.description.as_str())];
+                        metrics::counter(metrics::ALLOCATION_REJECTED, &labels).add(1);
+                        record(
+                            &journal,
+                            Event::Failed {
+                                request: id,
+                                client,
+                                reason: e.to_string(),
+                            },
+                        );
+                        return Ok(Err(e));
+                    }
+                    _ => {
+                        // Aborted by a shutdown, or by the client.
+                        let e = if drain.is_shutting_down() {
+                            AllocatorError::ShuttingDown
+                        } else {
+                            AllocatorError::Cancelled
+                        };
+                        record(
+                            &journal,
+                            Event::Failed {
+                                request: id,
+                                client,
+                                reason: e.to_string(),
+                            },
+                        );
+                        return Ok(Err(e));
+                    }
+                };
+
+                let resource_id = resource.id.clone();
+                let resource_description = resource.description.clone();
+                let usage = resource.usage.clone();
+                let session = match serve_lease(
+                    resource.inner.clone(),
+                    &lease_bind,
+                    &lease_transport,
+                    lease_retries,
+                    accept_timeout,
+                )
+                .await
+                {
+                    Ok(lease) => lease,
+                    Err(e) => {
+                        record(
+                            &journal,
+                            Event::Failed {
+                                request: id,
+                                client,
+                                reason: e.to_string(),
+                            },
+                        );
+                        release_failed(
+                            semaphore_permit,
+                            quarantine,
+                            &events,
+                            resource_description,
+                            resource_id,
+                        );
+                        return Ok(Err(e));
+                    }
+                };
+                let port = session.port;
+                let address = session.address.clone();
+
+                // Tracked such that a shutdown can wait for it, unless the shutdown
+                // already got that far.
+                let lease = random_id();
+                let (commands_tx, commands) = mpsc::unbounded_channel();
+                let mut tracked = false;
+                drain.leases.send_modify(|leases| {
+                    if !drain.is_shutting_down() {
+                        leases.insert(
+                            lease,
+                            LeaseHandle {
+                                resource: resource_id.clone(),
+                                commands: commands_tx,
+                            },
+                        );
+                        tracked = true;
+                    }
+                });
+                if !tracked {
+                    session.handle.abort();
+                    record(
+                        &journal,
+                        Event::Failed {
+                            request: id,
+                            client,
+                            reason: AllocatorError::ShuttingDown.to_string(),
+                        },
+                    );
+                    return Ok(Err(AllocatorError::ShuttingDown));
+                }
+
+                // Counted against the resource's own description, like the resources are,
+                // whatever the request asked for.
+                let granted_description = format!("{resource_description:?}");
+                let labels = [("description", granted_description.as_str())];
+                metrics::histogram(metrics::ALLOCATION_WAIT, &labels).observe(start.elapsed());
+                let leased = metrics::gauge(metrics::RESOURCES_LEASED, &labels).track();
+                let hold = metrics::histogram(metrics::LEASE_HOLD, &labels);
+                let granted = Instant::now();
+                lock(&usage).leases += 1;
+                record(
+                    &journal,
+                    Event::Granted {
+                        request: id,
+                        client: client.clone(),
+                        resource: resource_id.clone(),
+                        port,
+                    },
+                );
+                events.publish(AllocatorEvent::Granted {
+                    description: resource_description.clone(),
+                    resource: resource_id.clone(),
+                    client: client.clone(),
+                });
+                if let Some(client) = client {
+                    let mut last_resource = lock(&last_resource);
+                    last_resource.retain(|(remembered, _)| *remembered != client);
+                    last_resource.push_back((client, resource_id.clone()));
+                    while last_resource.len() > clients_remembered {
+                        last_resource.pop_front();
+                    }
+                }
+
+                let active_resource = resource_id.clone();
+                let active = ActiveLease {
+                    request: id,
+                    lease,
+                    resource: resource_id.clone(),
+                    inner: resource.inner,
+                    lease_bind,
+                    lease_transport,
+                    lease_retries,
+                    transfer_timeout,
+                    idle_timeout,
+                    accept_timeout,
+                    closed: closed.clone(),
+                    journal: journal.clone(),
+                };
+                tokio::spawn(async move {
+                    let event = active.run(session, commands).await;
+
+                    events.publish(AllocatorEvent::Released {
+                        description: resource_description,
+                        resource: active_resource,
+                    });
+                    // This assures the semaphore was moved into this scope,
+                    // and that it drops when the work is done.
+                    drop(semaphore_permit);
+                    drop(leased);
+                    hold.observe(granted.elapsed());
+                    {
+                        let mut usage = lock(&usage);
+                        usage.last_released = Some(Instant::now());
+                        usage.total_held += granted.elapsed();
+                    }
+                    record(&journal, event);
+                    lock(&tickets).retain(|_, ticketed| *ticketed != lease);
+                    drain.leases.send_modify(|leases| {
+                        leases.remove(&lease);
+                    });
+                });
+
+                Ok(Ok(AllocatorReply::Granted(Grant {
+                    port,
+                    address,
+                    resource: resource_id,
+                    lease,
+                })))
             }
             .instrument(info_span!("handshake-fut", %label)),
         )
     }
 }
+
+/// Commands for an active lease.
+#[derive(Debug)]
+enum LeaseCommand {
+    /// A transfer ticket was handed out, keep the resource for whoever redeems it.
+    Transfer,
+    /// Serve the resource to the client redeeming a transfer ticket.
+    Redeem {
+        client: Option<String>,
+        reply: oneshot::Sender<std::result::Result<(u16, Option<String>), AllocatorError>>,
+    },
+    /// End the lease. The reason is given if the client did not ask for it.
+    Close(Option<CloseReason>),
+}
+
+/// How to reach an active lease.
+#[derive(Debug)]
+struct LeaseHandle {
+    resource: String,
+    commands: mpsc::UnboundedSender<LeaseCommand>,
+}
+
+/// A resource leased out, served to one client session at a time.
+struct ActiveLease<S, Req>
+where
+    S: Service<Req> + Send + 'static,
+    Req: Send + 'static,
+    S::Future: Send,
+    S::Error: Send + Sync,
+{
+    request: usize,
+    lease: u64,
+    resource: String,
+    inner: Buffer<S, Req>,
+    lease_bind: String,
+    lease_transport: TransportConfig,
+    lease_retries: usize,
+    transfer_timeout: Duration,
+    idle_timeout: Option<Duration>,
+    accept_timeout: Duration,
+    closed: Arc<Mutex<VecDeque<(u64, CloseReason)>>>,
+    journal: Option<Journal>,
+}
+
+impl<S, Req> ActiveLease<S, Req>
+where
+    S: Service<Req> + Send + 'static,
+    Req: Send + 'static + Clone + DeserializeOwned,
+    S::Response: Serialize + Send,
+    S::Future: Send,
+    S::Error: Send + Sync + Into<tower::BoxError>,
+{
+    /// Serve the lease until it ends, returning how it ended.
+    ///
+    /// The lease usually ends with its session, but a pending transfer keeps it
+    /// alive for a while after the holder left, such that the resource is never
+    /// free in between holders.
+    async fn run(
+        self,
+        session: LeaseSession,
+        mut commands: mpsc::UnboundedReceiver<LeaseCommand>,
+    ) -> Event {
+        let request = self.request;
+        let resource = self.resource.clone();
+        self.watch_connected(session.connected);
+
+        let mut handle = Some(session.handle);
+        let mut activity = session.activity;
+        let mut transfer_pending = false;
+        // When the holder left with a transfer pending, how long to wait for a redeem.
+        let mut transfer_deadline = None;
+
+        loop {
+            let in_session = handle.is_some();
+            let session_end = async {
+                match handle.as_mut() {
+                    Some(handle) => handle.await,
+                    None => std::future::pending().await,
+                }
+            };
+            let transfer_expired = async {
+                match transfer_deadline {
+                    Some(deadline) => tokio::time::sleep_until(deadline).await,
+                    None => std::future::pending().await,
+                }
+            };
+            // Wakes up when the session would become idle, if nothing happens until then.
+            let idle_check = async {
+                match self.idle_timeout {
+                    Some(timeout) if in_session => {
+                        tokio::time::sleep(timeout.saturating_sub(activity.idle_for())).await
+                    }
+                    _ => std::future::pending().await,
+                }
+            };
+
+            tokio::select! {
+                end = session_end => {
+                    handle = None;
+                    if transfer_pending {
+                        debug!(lease = self.lease, "Holder left, keeping the lease for the transfer");
+                        transfer_deadline =
+                            Some(Instant::now() + self.transfer_timeout);
+                        continue;
+                    }
+
+                    return match end {
+                        Ok(SessionEnd::NotConnected) => {
+                            self.remember(CloseReason::NotConnected(self.accept_timeout));
+                            Event::Expired { request, resource }
+                        }
+                        Ok(SessionEnd::Failed(reason)) => {
+                            debug!(%reason, "Session failed");
+                            Event::SessionFailed { request, resource, reason }
+                        }
+                        Ok(SessionEnd::Done) => {
+                            debug!("Session done");
+                            Event::Released { request, resource }
+                        }
+                        Err(e) => {
+                            error!(?e, "Problem awaiting MuxServer");
+                            Event::SessionFailed {
+                                request,
+                                resource,
+                                reason: e.to_string(),
+                            }
+                        }
+                    };
+                }
+                () = transfer_expired => {
+                    warn!(lease = self.lease, "Transfer ticket not redeemed in time");
+                    return Event::Expired { request, resource };
+                }
+                () = idle_check => {
+                    let Some(timeout) = self.idle_timeout else {
+                        continue;
+                    };
+                    if activity.idle_for() < timeout {
+                        continue;
+                    }
+                    warn!(lease = self.lease, ?timeout, "Lease idle, reclaiming it");
+                    return self.close(handle.take(), CloseReason::Idle(timeout)).await;
+                }
+                command = commands.recv() => match command {
+                    Some(LeaseCommand::Transfer) => transfer_pending = true,
+                    Some(LeaseCommand::Redeem { client, reply }) => {
+                        if !transfer_pending {
+                            let _ = reply.send(Err(AllocatorError::UnknownTicket));
+                            continue;
+                        }
+
+                        match serve_lease(
+                            self.inner.clone(),
+                            &self.lease_bind,
+                            &self.lease_transport,
+                            self.lease_retries,
+                            self.accept_timeout,
+                        )
+                        .await
+                        {
+                            Ok(session) => {
+                                // The previous holder may not have left yet.
+                                if let Some(previous) = handle.replace(session.handle) {
+                                    previous.abort();
+                                }
+                                activity = session.activity;
+                                transfer_pending = false;
+                                transfer_deadline = None;
+                                record(
+                                    &self.journal,
+                                    Event::Transferred {
+                                        request,
+                                        client,
+                                        resource: resource.clone(),
+                                        port: session.port,
+                                    },
+                                );
+                                self.watch_connected(session.connected);
+                                let _ = reply.send(Ok((session.port, session.address)));
+                            }
+                            Err(e) => {
+                                let _ = reply.send(Err(e));
+                            }
+                        }
+                    }
+                    Some(LeaseCommand::Close(Some(reason))) => {
+                        return self.close(handle.take(), reason).await;
+                    }
+                    Some(LeaseCommand::Close(None)) | None => {
+                        if let Some(handle) = handle.take() {
+                            handle.abort();
+                            let _ = handle.await;
+                        }
+                        debug!(lease = self.lease, "Lease released");
+                        return Event::Released { request, resource };
+                    }
+                },
+            }
+        }
+    }
+
+    /// Close the lease on the allocator's initiative.
+    async fn close(&self, handle: Option<JoinHandle<SessionEnd>>, reason: CloseReason) -> Event {
+        self.remember(reason.clone());
+
+        if let Some(handle) = handle {
+            handle.abort();
+            let _ = handle.await;
+        }
+        Event::Closed {
+            request: self.request,
+            resource: self.resource.clone(),
+            reason: reason.to_string(),
+        }
+    }
+
+    /// Remember why the lease ended, such that the client may ask.
+    fn remember(&self, reason: CloseReason) {
+        let mut closed = lock(&self.closed);
+        if closed.len() >= CLOSE_REASONS_KEPT {
+            closed.pop_front();
+        }
+        closed.push_back((self.lease, reason));
+    }
+
+    fn watch_connected(&self, connected: oneshot::Receiver<()>) {
+        let journal = self.journal.clone();
+        let request = self.request;
+        let resource = self.resource.clone();
+        tokio::spawn(async move {
+            if connected.await.is_ok() {
+                record(&journal, Event::Connected { request, resource });
+            }
+        });
+    }
+}
+
+/// Acquire one of the candidate resources, given grouped by score with the best first.
+/// There must be at least one candidate.
+///
+/// If there is a preferred resource it is waited for alone at first,
+/// and the rest are only considered after the given fallback time.
+///
+/// Otherwise the best-scoring free candidate is taken, with the strategy choosing among
+/// equally good ones. If none are free they are raced for, i.e. we're fine with getting
+/// hold of the first one which is freed, it does not matter much which it actually is.
+/// The match policy decides whether lesser candidates take part.
+///
+/// Whatever the reason for waiting, a place in the queue is taken first,
+/// and held until a resource is acquired.
+/// Fails with [`AllocatorError::QueueFull`] if there is none to be had.
+async fn acquire_any<S, Req, D>(
+    mut tiers: Vec<Vec<Resource<S, Req, D>>>,
+    preferred: Option<(Resource<S, Req, D>, Duration)>,
+    strategy: Arc<dyn SelectionStrategy>,
+    match_policy: MatchPolicy,
+    queue: &WaitQueue,
+) -> std::result::Result<(Resource<S, Req, D>, OwnedSemaphorePermit), AllocatorError>
+where
+    S: Service<Req> + Send + 'static,
+    S: Describable<D>,
+    Req: Send + 'static,
+    S::Future: Send,
+    S::Error: Send + Sync + Into<tower::BoxError>,
+    D: Clone + PartialEq,
+{
+    let mut place = None;
+
+    if let Some((preferred, fallback_after)) = preferred {
+        if let Some(permit) = preferred.try_acquire() {
+            return Ok((preferred, permit));
+        }
+        queue.join(&mut place)?;
+        if let Ok(acquired) = tokio::time::timeout(fallback_after, preferred.acquire()).await {
+            return Ok(acquired);
+        }
+        debug!("Preferred resource not free in time, falling back");
+    }
+
+    match match_policy {
+        MatchPolicy::BestFree => {}
+        MatchPolicy::BestOnly => tiers.truncate(1),
+        MatchPolicy::PreferBest { settle_after } => {
+            let best = tiers.first().cloned().unwrap_or_default();
+            if let Some(acquired) = try_select(&best, strategy.as_ref()) {
+                return Ok(acquired);
+            }
+            queue.join(&mut place)?;
+            if let Ok(acquired) = tokio::time::timeout(settle_after, race(best)).await {
+                return Ok(acquired);
+            }
+            debug!("No best match freed in time, settling");
+        }
+    }
+
+    for tier in &tiers {
+        if let Some(acquired) = try_select(tier, strategy.as_ref()) {
+            return Ok(acquired);
+        }
+    }
+
+    queue.join(&mut place)?;
+    Ok(race(tiers.into_iter().flatten().collect()).await)
+}
+
+/// Wait for whichever of the candidates is freed first, of which there must be at least one.
+async fn race<S, Req, D>(
+    candidates: Vec<Resource<S, Req, D>>,
+) -> (Resource<S, Req, D>, OwnedSemaphorePermit)
+where
+    S: Service<Req> + Send + 'static,
+    S: Describable<D>,
+    Req: Send + 'static,
+    S::Future: Send,
+    S::Error: Send + Sync + Into<tower::BoxError>,
+    D: Clone + PartialEq,
+{
+    let (acquired, _, _) = futures::future::select_all(
+        candidates
+            .into_iter()
+            .map(|resource| Box::pin(resource.acquire())),
+    )
+    .await;
+    acquired
+}
+
+/// Let the strategy choose among the candidates which are free right now, if any.
+fn try_select<S, Req, D>(
+    candidates: &[Resource<S, Req, D>],
+    strategy: &dyn SelectionStrategy,
+) -> Option<(Resource<S, Req, D>, OwnedSemaphorePermit)>
+where
+    S: Service<Req> + Send + 'static,
+    S: Describable<D>,
+    Req: Send + 'static,
+    S::Future: Send,
+    S::Error: Send + Sync + Into<tower::BoxError>,
+    D: Clone + PartialEq,
+{
+    let mut free = candidates
+        .iter()
+        .filter(|resource| resource.is_free())
+        .collect::<Vec<_>>();
+
+    // Something else might grab a resource between checking and acquiring it,
+    // in that case let the strategy choose again.
+    while !free.is_empty() {
+        let chosen = {
+            let stats = free
+                .iter()
+                .map(|resource| resource.candidate())
+                .collect::<Vec<_>>();
+            strategy.select(&stats).min(free.len() - 1)
+        };
+
+        let resource = free.remove(chosen);
+        if let Some(permit) = resource.try_acquire() {
+            return Some((resource.clone(), permit));
+        }
+    }
+
+    None
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+fn record(journal: &Option<Journal>, event: Event) {
+    if let Some(journal) = journal {
+        journal.record(event);
+    }
+}
+
+/// The server for a leased resource, serving a single client session.
+struct LeaseSession {
+    handle: JoinHandle<SessionEnd>,
+    port: u16,
+    address: Option<String>,
+    /// Notified when the client connects.
+    connected: oneshot::Receiver<()>,
+    activity: Activity,
+}
+
+/// Start the server for a leased resource, trying again up to `retries` times.
+async fn serve_lease<S, Req>(
+    resource: Buffer<S, Req>,
+    bind: &str,
+    config: &TransportConfig,
+    retries: usize,
+    accept_timeout: Duration,
+) -> std::result::Result<LeaseSession, AllocatorError>
+where
+    S: Service<Req> + Send + 'static,
+    Req: Send + 'static + Clone + DeserializeOwned,
+    S::Response: Serialize + Send,
+    S::Future: Send,
+    S::Error: Send + Sync + Into<tower::BoxError>,
+{
+    let mut attempt = 0;
+    loop {
+        let (connected_tx, connected) = oneshot::channel();
+        let activity = Activity::new();
+        match mux_server::once_notify(
+            bind,
+            resource.clone(),
+            config,
+            accept_timeout,
+            connected_tx,
+            activity.clone(),
+        )
+        .await
+        {
+            Ok((handle, port, address)) => {
+                return Ok(LeaseSession {
+                    handle,
+                    port,
+                    address,
+                    connected,
+                    activity,
+                })
+            }
+            Err(e) if attempt < retries => {
+                attempt += 1;
+                warn!(?e, %attempt, "Problem starting lease server, retrying");
+            }
+            Err(e) => {
+                error!(?e, "Problem starting lease server, giving up");
+                return Err(AllocatorError::ListenerProblem(e.to_string()));
+            }
+        }
+    }
+}
+
+/// Give back the permit of a resource which could not be leased out,
+/// possibly after keeping it in quarantine for a while.
+fn release_failed<D>(
+    semaphore_permit: OwnedSemaphorePermit,
+    quarantine: Option<Duration>,
+    events: &Arc<EventFeed<D>>,
+    description: D,
+    resource: String,
+) where
+    D: Clone + Send + Sync + 'static,
+{
+    match quarantine {
+        Some(duration) => {
+            warn!(?duration, "Quarantining resource");
+            events.publish(AllocatorEvent::Quarantined {
+                description: description.clone(),
+                resource: resource.clone(),
+                duration,
+            });
+            let events = events.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(duration).await;
+                debug!("Quarantine over");
+                events.publish(AllocatorEvent::Restored {
+                    description,
+                    resource,
+                });
+                drop(semaphore_permit)
+            });
+        }
+        None => drop(semaphore_permit),
+    }
+}
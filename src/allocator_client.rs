@@ -1,23 +1,39 @@
 use std::{
+    collections::VecDeque,
     fmt::Debug,
     marker::PhantomData,
     pin::Pin,
+    sync::Arc,
     task::{Context, Poll},
 };
 
+use futures::{future, stream, Stream};
 use futures_core::Future;
 use serde::{de::DeserializeOwned, Serialize};
 use tower::{buffer::Buffer, BoxError, Service, ServiceExt};
 use tracing::{debug, info_span, warn, Instrument};
 
+use crate::allocator::{
+    Allocation, AllocationOptions, AllocatorError, AllocatorEvent, AllocatorReply,
+    AllocatorRequest, AllocatorResponse, CloseReason, DescriptionSummary, EventBatch, Grant,
+    TransferTicket,
+};
 use crate::error::Result;
-use crate::mux_client::MuxClient;
+use crate::mux_client::{explain, Lease, LeaseControl, MuxClient};
+use crate::tagged::random_id;
+use crate::transport::{self, TransportConfig};
+
+type AllocatorHandle<D> =
+    Buffer<MuxClient<AllocatorRequest<D>, AllocatorResponse<D>>, AllocatorRequest<D>>;
 
 pub struct AllocatorClientService<D, S, Req>
 where
-    D: Clone + PartialEq + Serialize + Send + 'static,
+    D: Clone + PartialEq + Serialize + DeserializeOwned + Send + 'static,
 {
-    allocator: Buffer<MuxClient<D, Option<u16>>, D>,
+    allocator: AllocatorHandle<D>,
+    // Where the allocator was reached, leases are served on the same host.
+    addr: String,
+    transport: TransportConfig,
     label: Option<String>,
     service: PhantomData<S>,
     request: PhantomData<Req>,
@@ -25,7 +41,7 @@ where
 
 impl<D, S, Req> Drop for AllocatorClientService<D, S, Req>
 where
-    D: Clone + PartialEq + Serialize + Send + 'static,
+    D: Clone + PartialEq + Serialize + DeserializeOwned + Send + 'static,
 {
     fn drop(&mut self) {
         debug!("Dropping allocator client: {:?}", self.label)
@@ -34,7 +50,7 @@ where
 
 impl<D, S, Req> Debug for AllocatorClientService<D, S, Req>
 where
-    D: Clone + PartialEq + Serialize + Send + 'static,
+    D: Clone + PartialEq + Serialize + DeserializeOwned + Send + 'static,
 {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("AllocatorClientService")
@@ -48,11 +64,13 @@ where
 
 impl<D, S, Req> Clone for AllocatorClientService<D, S, Req>
 where
-    D: Clone + PartialEq + Serialize + Send + 'static,
+    D: Clone + PartialEq + Serialize + DeserializeOwned + Send + 'static,
 {
     fn clone(&self) -> Self {
         Self {
             allocator: self.allocator.clone(),
+            addr: self.addr.clone(),
+            transport: self.transport.clone(),
             service: self.service,
             request: self.request,
             label: self.label.clone().map(|label| format!("{label}-clone")),
@@ -62,11 +80,20 @@ where
 
 impl<D, S, Req> AllocatorClientService<D, S, Req>
 where
-    D: Clone + PartialEq + Serialize + Send + 'static,
+    D: Clone + PartialEq + Serialize + DeserializeOwned + Send + 'static,
 {
-    pub(crate) async fn new_impl(addr: &str, label: Option<String>) -> Result<Self> {
+    pub(crate) async fn new_impl(
+        addr: &str,
+        label: Option<String>,
+        transport: TransportConfig,
+    ) -> Result<Self> {
         Ok(Self {
-            allocator: Buffer::new(MuxClient::new_impl(addr, label.clone()).await?, 1),
+            allocator: Buffer::new(
+                MuxClient::new_impl(addr, label.clone(), &transport).await?,
+                1,
+            ),
+            addr: addr.to_string(),
+            transport,
             service: Default::default(),
             request: Default::default(),
             label,
@@ -74,41 +101,277 @@ where
     }
 
     pub async fn new(addr: &str) -> Result<Self> {
-        Self::new_impl(addr, None).await
+        Self::new_impl(addr, None, TransportConfig::default()).await
     }
 
     pub async fn new_labelled(addr: &str, label: &str) -> Result<Self> {
-        Self::new_impl(addr, Some(label.to_string())).await
+        Self::new_impl(addr, Some(label.to_string()), TransportConfig::default()).await
+    }
+
+    /// Connect with the given transport configuration, used for leased resources too.
+    /// It must match the allocator's (see [`crate::mux_server::run_with`])
+    /// and the allocator's lease configuration
+    /// (see [`crate::allocator::AllocatorService::with_lease_transport`]).
+    pub async fn new_with(addr: &str, config: TransportConfig) -> Result<Self> {
+        Self::new_impl(addr, None, config).await
+    }
+
+    /// The distinct descriptions the allocator serves, with how many resources
+    /// there are of each and how many of those are free right now.
+    pub async fn descriptions(&self) -> Result<Vec<DescriptionSummary<D>>>
+    where
+        D: Debug,
+    {
+        let mut allocator = self.allocator.clone();
+        match allocator
+            .ready()
+            .await?
+            .call(AllocatorRequest::Discover)
+            .await?
+        {
+            Ok(AllocatorReply::Descriptions(descriptions)) => Ok(descriptions),
+            Ok(reply) => Err(format!("Unexpected reply to discovery: {reply:?}").into()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Follow what happens to the allocator's resources from now on.
+    /// With a filter, only events about resources matching it are seen.
+    ///
+    /// A subscriber which falls far behind is told how many events it missed
+    /// with [`AllocatorError::EventsMissed`], after which the stream goes on.
+    /// Other errors end the stream.
+    pub async fn subscribe(
+        &self,
+        filter: Option<D>,
+    ) -> Result<impl Stream<Item = Result<AllocatorEvent<D>>> + Send>
+    where
+        D: Debug + Sync,
+    {
+        let mut allocator = self.allocator.clone();
+        let start = next_events(&mut allocator, filter.clone(), None).await?;
+
+        let state = Some((allocator, start.last, VecDeque::new()));
+        Ok(stream::unfold(state, move |state| {
+            let filter = filter.clone();
+            async move {
+                let (mut allocator, mut after, mut pending) = state?;
+                loop {
+                    if let Some(event) = pending.pop_front() {
+                        return Some((Ok(event), Some((allocator, after, pending))));
+                    }
+
+                    let batch = match next_events(&mut allocator, filter.clone(), Some(after)).await
+                    {
+                        Ok(batch) => batch,
+                        Err(e) => return Some((Err(e), None)),
+                    };
+                    after = batch.last;
+                    pending.extend(batch.events);
+                    if batch.missed > 0 {
+                        let missed = AllocatorError::EventsMissed(batch.missed).into();
+                        return Some((Err(missed), Some((allocator, after, pending))));
+                    }
+                }
+            }
+        }))
     }
 }
 
-impl<D, S, Req> Service<D> for AllocatorClientService<D, S, Req>
+async fn next_events<D>(
+    allocator: &mut AllocatorHandle<D>,
+    filter: Option<D>,
+    after: Option<u64>,
+) -> Result<EventBatch<D>>
 where
-    D: Debug + PartialEq + Send + Clone + Sync + 'static + Serialize,
-    S: Service<Req>,
-    S::Response: DeserializeOwned + Send + 'static,
+    D: Debug + Clone + PartialEq + Serialize + DeserializeOwned + Send + 'static,
+{
+    let request = AllocatorRequest::Events { filter, after };
+    match allocator.ready().await?.call(request).await? {
+        Ok(AllocatorReply::Events(batch)) => Ok(batch),
+        Ok(reply) => Err(format!("Unexpected reply to subscription: {reply:?}").into()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Lets leased clients talk to the allocator about their lease.
+struct AllocatorLeaseControl<D>(AllocatorHandle<D>)
+where
+    D: Clone + PartialEq + Serialize + DeserializeOwned + Send + 'static;
+
+impl<D> LeaseControl for AllocatorLeaseControl<D>
+where
+    D: Debug + Clone + PartialEq + Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    fn transfer(&self, lease: u64) -> Pin<Box<dyn Future<Output = Result<TransferTicket>> + Send>> {
+        let mut allocator = self.0.clone();
+        Box::pin(async move {
+            match allocator
+                .ready()
+                .await?
+                .call(AllocatorRequest::Transfer(lease))
+                .await?
+            {
+                Ok(AllocatorReply::Ticket(ticket)) => Ok(ticket),
+                Ok(reply) => Err(format!("Unexpected reply to transfer: {reply:?}").into()),
+                Err(e) => Err(e.into()),
+            }
+        })
+    }
+
+    fn release(&self, lease: u64) -> Pin<Box<dyn Future<Output = Result<()>> + Send>> {
+        let mut allocator = self.0.clone();
+        Box::pin(async move {
+            match allocator
+                .ready()
+                .await?
+                .call(AllocatorRequest::Release(lease))
+                .await?
+            {
+                Ok(AllocatorReply::Done) => Ok(()),
+                Ok(reply) => Err(format!("Unexpected reply to release: {reply:?}").into()),
+                Err(e) => Err(e.into()),
+            }
+        })
+    }
+
+    fn why_closed(
+        &self,
+        lease: u64,
+    ) -> Pin<Box<dyn Future<Output = Result<Option<CloseReason>>> + Send>> {
+        let mut allocator = self.0.clone();
+        Box::pin(async move {
+            match allocator
+                .ready()
+                .await?
+                .call(AllocatorRequest::WhyClosed(lease))
+                .await?
+            {
+                Ok(AllocatorReply::Closed(reason)) => Ok(reason),
+                Ok(reply) => {
+                    Err(format!("Unexpected reply to asking why closed: {reply:?}").into())
+                }
+                Err(e) => Err(e.into()),
+            }
+        })
+    }
+}
+
+/// Connect to a resource the allocator at `allocator_addr` leased out.
+async fn connect<D, Req, Resp>(
+    allocator: &AllocatorHandle<D>,
+    allocator_addr: &str,
+    transport: &TransportConfig,
+    label: Option<String>,
+    grant: Grant,
+) -> Result<MuxClient<Req, Resp>>
+where
+    D: Debug + Clone + PartialEq + Serialize + DeserializeOwned + Send + Sync + 'static,
     Req: Serialize + Send + Clone + 'static,
+    Resp: DeserializeOwned + Send + 'static,
 {
-    type Response = Option<MuxClient<Req, S::Response>>;
-    type Error = BoxError;
+    let Grant {
+        port,
+        address,
+        resource,
+        lease,
+    } = grant;
+    // Unless told otherwise, the resource is on the allocator's host,
+    // which is also the name its certificate is checked against.
+    let address = address
+        .or_else(|| transport::on_host_of(allocator_addr, port))
+        .unwrap_or_else(|| format!("0.0.0.0:{port}"));
+    debug!("Got resource {resource} allocated ready on {address}. Setting up a client there.");
 
-    #[allow(clippy::type_complexity)]
-    type Future = Pin<Box<dyn Future<Output = Result<Self::Response>> + Send>>;
+    let label = label.map(|label| format!("{label:?}-{port}"));
+    let lease = Lease {
+        id: lease,
+        control: Arc::new(AllocatorLeaseControl(allocator.clone())),
+    };
+    match MuxClient::new_impl(&address, label, transport).await {
+        Ok(client) => Ok(client.with_resource(resource).with_lease(lease)),
+        // E.g. the resource stopped waiting for us.
+        Err(e) => explain(Some(lease), e).await,
+    }
+}
 
-    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<()>> {
-        debug!("Polling ready");
-        Poll::Ready(Ok(()))
+/// Tells the allocator to stop waiting for an allocation if the caller
+/// stopped waiting for it before it was answered.
+struct CancelOnDrop<D>
+where
+    D: Clone + PartialEq + Serialize + DeserializeOwned + Send + 'static,
+{
+    allocator: Option<AllocatorHandle<D>>,
+    id: u64,
+}
+
+impl<D> CancelOnDrop<D>
+where
+    D: Clone + PartialEq + Serialize + DeserializeOwned + Send + 'static,
+{
+    fn disarm(mut self) {
+        self.allocator = None;
     }
+}
 
-    fn call(&mut self, request: D) -> Self::Future {
-        debug!("Calling");
+impl<D> Drop for CancelOnDrop<D>
+where
+    D: Clone + PartialEq + Serialize + DeserializeOwned + Send + 'static,
+{
+    fn drop(&mut self) {
+        let (Some(mut allocator), Ok(runtime)) =
+            (self.allocator.take(), tokio::runtime::Handle::try_current())
+        else {
+            return;
+        };
+        let id = self.id;
+
+        debug!(%id, "Allocation abandoned, cancelling it");
+        runtime.spawn(async move {
+            if let Ok(ready) = allocator.ready().await {
+                let _ = ready.call(AllocatorRequest::Cancel(id)).await;
+            }
+        });
+    }
+}
+
+impl<D, S, Req> AllocatorClientService<D, S, Req>
+where
+    D: Debug + PartialEq + Send + Clone + Sync + 'static + Serialize + DeserializeOwned,
+    S: Service<Req>,
+    S::Response: DeserializeOwned + Send + 'static,
+    Req: Serialize + Send + Clone + 'static,
+{
+    /// Same as calling the service, but with hints on how to allocate.
+    pub fn allocate_with(
+        &self,
+        request: D,
+        options: AllocationOptions,
+    ) -> <Self as Service<D>>::Future {
+        self.allocate_until(request, options, future::pending())
+    }
+
+    /// Allocate, cancelling the allocation once `until` completes rather than only
+    /// when the returned future is dropped.
+    /// If the resource was granted before the allocator heard of the cancellation,
+    /// the client for it is given all the same.
+    pub(crate) fn allocate_until(
+        &self,
+        request: D,
+        options: AllocationOptions,
+        until: impl Future<Output = ()> + Send + 'static,
+    ) -> <Self as Service<D>>::Future {
         let mut allocator_handle = self.allocator.clone();
+        let cancel_handle = self.allocator.clone();
+        let cancel_handle_for_lease = self.allocator.clone();
         let label = self.label.clone();
+        let addr = self.addr.clone();
+        let transport = self.transport.clone();
 
         Box::pin(
             async move {
                 debug!("Attempting allocation of resource");
-                let port = {
+                let grant = {
                     // allocator_handle.ready().await?.call(request).await?
                     let ready = match allocator_handle.ready().await {
                         Ok(ready) => ready,
@@ -118,12 +381,44 @@ where
                         },
                     };
 
-                    let response = match ready.call(request).await {
-                        Ok(Some(port)) => port,
-                        Ok(None) => {
+                    let id = random_id();
+                    let request = AllocatorRequest::Allocate(Allocation {
+                        id,
+                        description: request,
+                        client: label.clone(),
+                        options,
+                    });
+                    let mut cancel = Some(CancelOnDrop {
+                        allocator: Some(cancel_handle),
+                        id,
+                    });
+                    let response = ready.call(request);
+                    tokio::pin!(response, until);
+                    let response = tokio::select! {
+                        response = &mut response => response,
+                        () = &mut until => {
+                            drop(cancel.take());
+                            response.await
+                        }
+                    };
+                    if let Some(cancel) = cancel {
+                        cancel.disarm();
+                    }
+
+                    let response = match response {
+                        Ok(Ok(AllocatorReply::Granted(grant))) => grant,
+                        Ok(Ok(AllocatorReply::NoMatch)) => {
                             debug!("No matching resource on allocator, can't make a client!");
                             return Ok(None);
                         },
+                        Ok(Ok(reply)) => {
+                            warn!("Unexpected reply to allocation: {reply:?}");
+                            return Err(format!("Unexpected reply to allocation: {reply:?}").into());
+                        },
+                        Ok(Err(e)) => {
+                            warn!("Allocator could not lease out resource: {e:?}");
+                            return Err(e.into());
+                        },
                         Err(e) => {
                             warn!("Did not get allocated resource on port: {e:?}");
                             return Err(e);
@@ -132,10 +427,8 @@ where
 
                     response
                 };
-                debug!("Got resource allocated ready on port {port}. Setting up a client on this port.");
-
-                let label = label.clone().map(|label| format!("{label:?}-{port}"));
-                let client = MuxClient::new_impl(&format!("0.0.0.0:{port}"), label).await?;
+                let client =
+                    connect(&cancel_handle_for_lease, &addr, &transport, label, grant).await?;
 
                 debug!("Client allocated, returning");
                 Ok(Some(client))
@@ -143,4 +436,51 @@ where
             .instrument(info_span!("allocator-client-fut")),
         )
     }
+
+    /// Take over a lease another client handed over, see [`MuxClient::transfer`].
+    pub async fn redeem(&self, ticket: &TransferTicket) -> Result<MuxClient<Req, S::Response>> {
+        let mut allocator = self.allocator.clone();
+        let request = AllocatorRequest::Redeem {
+            ticket: ticket.ticket,
+            client: self.label.clone(),
+        };
+        let grant = match allocator.ready().await?.call(request).await? {
+            Ok(AllocatorReply::Granted(grant)) => grant,
+            Ok(reply) => return Err(format!("Unexpected reply to redeem: {reply:?}").into()),
+            Err(e) => return Err(e.into()),
+        };
+
+        connect(
+            &self.allocator,
+            &self.addr,
+            &self.transport,
+            self.label.clone(),
+            grant,
+        )
+        .await
+    }
+}
+
+impl<D, S, Req> Service<D> for AllocatorClientService<D, S, Req>
+where
+    D: Debug + PartialEq + Send + Clone + Sync + 'static + Serialize + DeserializeOwned,
+    S: Service<Req>,
+    S::Response: DeserializeOwned + Send + 'static,
+    Req: Serialize + Send + Clone + 'static,
+{
+    type Response = Option<MuxClient<Req, S::Response>>;
+    type Error = BoxError;
+
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        debug!("Polling ready");
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: D) -> Self::Future {
+        debug!("Calling");
+        self.allocate_with(request, AllocationOptions::default())
+    }
 }
@@ -0,0 +1,317 @@
+use std::{
+    fmt::Debug,
+    pin::Pin,
+    sync::{Arc, Mutex, MutexGuard},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::{future, stream::FuturesUnordered, StreamExt};
+use futures_core::Future;
+use serde::{de::DeserializeOwned, Serialize};
+use tokio_util::sync::CancellationToken;
+use tower::{BoxError, Service};
+use tracing::{debug, info_span, warn, Instrument};
+
+use crate::allocator::{AllocationOptions, AllocatorError};
+use crate::allocator_client::AllocatorClientService;
+use crate::error::Result;
+use crate::mux_client::MuxClient;
+use crate::transport::TransportConfig;
+
+/// How long to try connecting to an allocator before considering it down.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+/// One of the allocators in a federation.
+/// Not connected while the allocator is down.
+struct Member<D, S, Req>
+where
+    D: Clone + PartialEq + Serialize + DeserializeOwned + Send + 'static,
+{
+    addr: String,
+    label: Option<String>,
+    transport: TransportConfig,
+    allocator: Arc<Mutex<Option<AllocatorClientService<D, S, Req>>>>,
+}
+
+impl<D, S, Req> Clone for Member<D, S, Req>
+where
+    D: Clone + PartialEq + Serialize + DeserializeOwned + Send + 'static,
+{
+    fn clone(&self) -> Self {
+        Self {
+            addr: self.addr.clone(),
+            label: self.label.clone(),
+            transport: self.transport.clone(),
+            allocator: self.allocator.clone(),
+        }
+    }
+}
+
+impl<D, S, Req> Member<D, S, Req>
+where
+    D: Clone + PartialEq + Serialize + DeserializeOwned + Send + 'static,
+{
+    async fn connect(addr: &str, label: Option<String>, transport: TransportConfig) -> Self {
+        let member = Self {
+            addr: addr.to_string(),
+            label,
+            transport,
+            allocator: Default::default(),
+        };
+        if let Err(e) = member.allocator().await {
+            warn!(%addr, ?e, "Could not connect to allocator, will retry later");
+        }
+        member
+    }
+
+    fn is_connected(&self) -> bool {
+        lock(&self.allocator).is_some()
+    }
+
+    /// Get the allocator, connecting to it first if needed.
+    async fn allocator(&self) -> Result<AllocatorClientService<D, S, Req>> {
+        if let Some(allocator) = lock(&self.allocator).as_ref() {
+            return Ok(allocator.clone());
+        }
+
+        // Not holding the lock while connecting, such that allocations
+        // which raced to connect do not queue up behind each other.
+        debug!(addr = %self.addr, "Connecting to allocator");
+        let connecting = AllocatorClientService::new_impl(
+            &self.addr,
+            self.label.clone(),
+            self.transport.clone(),
+        );
+        let connected = match tokio::time::timeout(CONNECT_TIMEOUT, connecting).await {
+            Ok(connected) => connected?,
+            Err(_) => {
+                return Err(format!(
+                    "Timed out connecting to allocator at {} after {CONNECT_TIMEOUT:?}",
+                    self.addr
+                )
+                .into())
+            }
+        };
+
+        let mut allocator = lock(&self.allocator);
+        match allocator.as_ref() {
+            // Someone else got there first.
+            Some(allocator) => Ok(allocator.clone()),
+            None => {
+                *allocator = Some(connected.clone());
+                Ok(connected)
+            }
+        }
+    }
+
+    fn disconnect(&self) {
+        *lock(&self.allocator) = None;
+    }
+}
+
+/// Allocates from whichever of several allocators first can satisfy a request.
+///
+/// The allocation is attempted on all allocators at the same time.
+/// When one of them grants a resource, the waits on the others are cancelled.
+///
+/// An allocator being down does not fail the allocation as long as another one can
+/// satisfy it. Allocators which are down are reconnected to on later allocations.
+///
+/// If none grants a resource, an allocator refusing to (e.g. because its queue is full)
+/// is told rather than the others having no match.
+pub struct FederatedAllocatorClientService<D, S, Req>
+where
+    D: Clone + PartialEq + Serialize + DeserializeOwned + Send + 'static,
+{
+    members: Vec<Member<D, S, Req>>,
+    label: Option<String>,
+}
+
+impl<D, S, Req> Debug for FederatedAllocatorClientService<D, S, Req>
+where
+    D: Clone + PartialEq + Serialize + DeserializeOwned + Send + 'static,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FederatedAllocatorClientService")
+            .field(
+                "members",
+                &self
+                    .members
+                    .iter()
+                    .map(|member| &member.addr)
+                    .collect::<Vec<_>>(),
+            )
+            .field("label", &self.label)
+            .finish()
+    }
+}
+
+impl<D, S, Req> Clone for FederatedAllocatorClientService<D, S, Req>
+where
+    D: Clone + PartialEq + Serialize + DeserializeOwned + Send + 'static,
+{
+    fn clone(&self) -> Self {
+        Self {
+            members: self.members.clone(),
+            label: self.label.clone(),
+        }
+    }
+}
+
+impl<D, S, Req> FederatedAllocatorClientService<D, S, Req>
+where
+    D: Clone + PartialEq + Serialize + DeserializeOwned + Send + 'static,
+{
+    async fn new_impl(
+        addrs: &[&str],
+        label: Option<String>,
+        transport: TransportConfig,
+    ) -> Result<Self> {
+        // Connected all at once, such that allocators which are down don't add up.
+        let members = future::join_all(
+            addrs
+                .iter()
+                .map(|addr| Member::connect(addr, label.clone(), transport.clone())),
+        )
+        .await;
+
+        if !members.iter().any(Member::is_connected) {
+            return Err(format!("Could not connect to any of the allocators: {addrs:?}").into());
+        }
+
+        Ok(Self { members, label })
+    }
+
+    /// Connect to the allocators at the given addresses.
+    /// Fails only if none of them can be reached.
+    pub async fn new(addrs: &[&str]) -> Result<Self> {
+        Self::new_impl(addrs, None, TransportConfig::default()).await
+    }
+
+    pub async fn new_labelled(addrs: &[&str], label: &str) -> Result<Self> {
+        Self::new_impl(addrs, Some(label.to_string()), TransportConfig::default()).await
+    }
+
+    /// Connect with the given transport configuration, used for all the allocators
+    /// and their leased resources, see [`AllocatorClientService::new_with`].
+    pub async fn new_with(addrs: &[&str], config: TransportConfig) -> Result<Self> {
+        Self::new_impl(addrs, None, config).await
+    }
+}
+
+impl<D, S, Req> Service<D> for FederatedAllocatorClientService<D, S, Req>
+where
+    D: Debug + PartialEq + Send + Clone + Sync + 'static + Serialize + DeserializeOwned,
+    S: Service<Req> + Send + 'static,
+    S::Response: DeserializeOwned + Send + 'static,
+    Req: Serialize + Send + Clone + 'static,
+{
+    type Response = Option<MuxClient<Req, S::Response>>;
+    type Error = BoxError;
+
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: D) -> Self::Future {
+        let members = self.members.clone();
+
+        Box::pin(
+            async move {
+                // Cancels the attempts which lost.
+                let lost = CancellationToken::new();
+                let mut attempts = members
+                    .into_iter()
+                    .map(|member| {
+                        let request = request.clone();
+                        let lost = lost.clone().cancelled_owned();
+                        async move {
+                            let result = async {
+                                member
+                                    .allocator()
+                                    .await?
+                                    .allocate_until(request, AllocationOptions::default(), lost)
+                                    .await
+                            }
+                            .await;
+
+                            // Errors from the allocator itself are fine, anything else
+                            // means the connection is broken.
+                            if let Err(e) = &result {
+                                if e.downcast_ref::<AllocatorError>().is_none() {
+                                    member.disconnect();
+                                }
+                            }
+                            (member.addr, result)
+                        }
+                    })
+                    .collect::<FuturesUnordered<_>>();
+
+                let mut any_answered = false;
+                // Refusals from allocators, e.g. because their queue is full.
+                let mut refused = None;
+                let mut last_error = None;
+                while let Some((addr, result)) = attempts.next().await {
+                    match result {
+                        Ok(Some(client)) => {
+                            debug!(%addr, "Resource allocated");
+                            lost.cancel();
+                            tokio::spawn(release_lost(attempts));
+                            return Ok(Some(client));
+                        }
+                        Ok(None) => {
+                            debug!(%addr, "No matching resource on allocator");
+                            any_answered = true;
+                        }
+                        Err(e) if e.downcast_ref::<AllocatorError>().is_some() => {
+                            warn!(%addr, "Allocation refused: {e:?}");
+                            refused = Some(e);
+                        }
+                        Err(e) => {
+                            warn!(%addr, "Allocation failed: {e:?}");
+                            last_error = Some(e);
+                        }
+                    }
+                }
+
+                // An allocator which has matching resources, but could not grant one,
+                // tells more than those which have none.
+                match (refused, last_error) {
+                    (Some(e), _) => Err(e),
+                    (None, Some(e)) if !any_answered => Err(e),
+                    _ => Ok(None),
+                }
+            }
+            .instrument(info_span!("federated-allocator-client-fut")),
+        )
+    }
+}
+
+/// See the cancelled attempts through, releasing resources granted by allocators
+/// which had not heard of the cancellation yet.
+async fn release_lost<F, Req, Resp>(mut attempts: FuturesUnordered<F>)
+where
+    F: Future<Output = (String, Result<Option<MuxClient<Req, Resp>>>)>,
+    Req: Serialize + Send + Clone + 'static,
+    Resp: DeserializeOwned + Send + 'static,
+{
+    while let Some((addr, result)) = attempts.next().await {
+        if let Ok(Some(client)) = result {
+            debug!(%addr, "Resource granted after another allocator's, releasing it");
+            if let Err(e) = client.release().await {
+                warn!(%addr, ?e, "Could not release resource granted too late");
+            }
+        }
+    }
+}
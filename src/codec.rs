@@ -0,0 +1,91 @@
+//! How requests and responses are turned into bytes on the wire.
+//!
+//! Bincode is always available. The other codecs are enabled by cargo features:
+//! `json`, `msgpack` and `postcard`.
+//! Both ends of a connection must use the same codec,
+//! see [`crate::transport::TransportConfig::with_codec`].
+
+use std::io;
+
+use bincode::Options;
+use serde::{de::DeserializeOwned, Serialize};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Codec {
+    /// Compact, and what peers use unless told otherwise.
+    #[default]
+    Bincode,
+    /// Readable by eye, and by tooling written in other languages.
+    #[cfg(feature = "json")]
+    Json,
+    /// Compact, and readable by tooling written in other languages.
+    /// Structs are encoded as maps, such that fields are named.
+    #[cfg(feature = "msgpack")]
+    MessagePack,
+    /// Very compact, meant for constrained peers.
+    #[cfg(feature = "postcard")]
+    Postcard,
+}
+
+/// Every codec built in.
+const ALL: &[Codec] = &[
+    Codec::Bincode,
+    #[cfg(feature = "json")]
+    Codec::Json,
+    #[cfg(feature = "msgpack")]
+    Codec::MessagePack,
+    #[cfg(feature = "postcard")]
+    Codec::Postcard,
+];
+
+impl Codec {
+    /// How peers refer to the codec when setting up a connection,
+    /// the same whichever codecs they have built in.
+    pub(crate) fn id(self) -> u8 {
+        match self {
+            Self::Bincode => 0,
+            #[cfg(feature = "json")]
+            Self::Json => 1,
+            #[cfg(feature = "msgpack")]
+            Self::MessagePack => 2,
+            #[cfg(feature = "postcard")]
+            Self::Postcard => 3,
+        }
+    }
+
+    pub(crate) fn from_id(id: u8) -> Option<Self> {
+        ALL.iter().copied().find(|codec| codec.id() == id)
+    }
+
+    pub(crate) fn encode<T: Serialize>(&self, item: &T) -> io::Result<Vec<u8>> {
+        match self {
+            Self::Bincode => bincode::options().serialize(item).map_err(invalid),
+            #[cfg(feature = "json")]
+            Self::Json => serde_json::to_vec(item).map_err(invalid),
+            #[cfg(feature = "msgpack")]
+            Self::MessagePack => rmp_serde::to_vec_named(item).map_err(invalid),
+            #[cfg(feature = "postcard")]
+            Self::Postcard => postcard::to_stdvec(item).map_err(invalid),
+        }
+    }
+
+    pub(crate) fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> io::Result<T> {
+        match self {
+            // Lengths claimed within the frame may not make bincode read past it.
+            Self::Bincode => bincode::options()
+                .with_limit(bytes.len() as u64)
+                .deserialize(bytes)
+                .map_err(invalid),
+            #[cfg(feature = "json")]
+            Self::Json => serde_json::from_slice(bytes).map_err(invalid),
+            #[cfg(feature = "msgpack")]
+            Self::MessagePack => rmp_serde::from_slice(bytes).map_err(invalid),
+            #[cfg(feature = "postcard")]
+            Self::Postcard => postcard::from_bytes(bytes).map_err(invalid),
+        }
+    }
+}
+
+fn invalid(e: impl std::error::Error + Send + Sync + 'static) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
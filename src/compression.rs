@@ -0,0 +1,93 @@
+//! Compression of frames on the wire, for services moving large payloads.
+//!
+//! The algorithms are enabled by cargo features: `zstd` and `lz4`.
+//! Whether a connection is compressed is settled when it is set up: the client offers the
+//! algorithms it is configured with, and the server picks the first one it is configured with too.
+//! Peers with none in common, or which do not know about compression at all,
+//! exchange frames uncompressed.
+//! See [`crate::transport::TransportConfig::with_compression`].
+
+use std::io;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// Better ratios, for large and repetitive payloads such as logs.
+    #[cfg(feature = "zstd")]
+    Zstd,
+    /// Faster, for when the network is not the bottleneck.
+    #[cfg(feature = "lz4")]
+    Lz4,
+}
+
+/// Every algorithm built in.
+const ALL: &[Compression] = &[
+    #[cfg(feature = "zstd")]
+    Compression::Zstd,
+    #[cfg(feature = "lz4")]
+    Compression::Lz4,
+];
+
+// Without any algorithms built in, there is nothing to compress with.
+#[cfg_attr(not(any(feature = "zstd", feature = "lz4")), allow(unused_variables))]
+impl Compression {
+    /// How peers refer to the algorithm when setting up a connection,
+    /// the same whichever algorithms they have built in.
+    pub(crate) fn id(self) -> u8 {
+        match self {
+            #[cfg(feature = "zstd")]
+            Self::Zstd => 1,
+            #[cfg(feature = "lz4")]
+            Self::Lz4 => 2,
+        }
+    }
+
+    pub(crate) fn from_id(id: u8) -> Option<Self> {
+        ALL.iter()
+            .copied()
+            .find(|compression| compression.id() == id)
+    }
+
+    pub(crate) fn compress(self, frame: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            #[cfg(feature = "zstd")]
+            Self::Zstd => zstd::bulk::compress(frame, zstd::DEFAULT_COMPRESSION_LEVEL),
+            #[cfg(feature = "lz4")]
+            Self::Lz4 => Ok(lz4_flex::compress_prepend_size(frame)),
+        }
+    }
+
+    /// Decompress a frame, unless it would be larger than `max` bytes.
+    pub(crate) fn decompress(self, frame: &[u8], max: usize) -> io::Result<Vec<u8>> {
+        match self {
+            #[cfg(feature = "zstd")]
+            Self::Zstd => {
+                let size = zstd::zstd_safe::get_frame_content_size(frame)
+                    .ok()
+                    .flatten()
+                    .ok_or_else(|| invalid("compressed frame does not tell its size".into()))?;
+                zstd::bulk::decompress(frame, bounded(size as usize, max)?)
+            }
+            #[cfg(feature = "lz4")]
+            Self::Lz4 => {
+                let (size, frame) = lz4_flex::block::uncompressed_size(frame)
+                    .map_err(|e| invalid(e.to_string()))?;
+                lz4_flex::decompress(frame, bounded(size, max)?).map_err(|e| invalid(e.to_string()))
+            }
+        }
+    }
+}
+
+#[cfg(any(feature = "zstd", feature = "lz4"))]
+fn bounded(size: usize, max: usize) -> io::Result<usize> {
+    if size > max {
+        return Err(invalid(format!(
+            "frame decompresses to {size} bytes, more than the maximum of {max}"
+        )));
+    }
+    Ok(size)
+}
+
+#[cfg(any(feature = "zstd", feature = "lz4"))]
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
@@ -0,0 +1,246 @@
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::Path,
+    sync::mpsc,
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use crate::error::Result;
+
+/// Something that happened to an allocation.
+///
+/// `request` is the allocator's running number for the request,
+/// `resource` is the identity of the resource (see [`crate::resource_filter::Describable::id`]).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    RequestReceived {
+        request: usize,
+        client: Option<String>,
+        description: String,
+    },
+    Granted {
+        request: usize,
+        client: Option<String>,
+        resource: String,
+        port: u16,
+    },
+    Connected {
+        request: usize,
+        resource: String,
+    },
+    /// The lease was handed over to another client, see [`crate::allocator::TransferTicket`].
+    Transferred {
+        request: usize,
+        client: Option<String>,
+        resource: String,
+        port: u16,
+    },
+    Released {
+        request: usize,
+        resource: String,
+    },
+    /// The client never connected to the leased resource.
+    Expired {
+        request: usize,
+        resource: String,
+    },
+    /// The allocator closed the lease, e.g. because it was idle.
+    Closed {
+        request: usize,
+        resource: String,
+        reason: String,
+    },
+    /// Serving the client failed, e.g. because the connection broke.
+    SessionFailed {
+        request: usize,
+        resource: String,
+        reason: String,
+    },
+    Failed {
+        request: usize,
+        client: Option<String>,
+        reason: String,
+    },
+}
+
+/// A single line in the journal.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    /// Milliseconds since the unix epoch.
+    pub time: u64,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+/// Append-only journal of allocation events, stored as JSON lines.
+///
+/// Entries are written to the file by a thread of its own,
+/// such that recording them never blocks the async tasks doing so.
+#[derive(Debug, Clone)]
+pub struct Journal {
+    lines: mpsc::Sender<String>,
+}
+
+impl Journal {
+    /// Open the journal at the given path, creating it if needed.
+    /// New entries are appended.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let (lines, written) = mpsc::channel();
+        thread::Builder::new()
+            .name("journal".to_string())
+            .spawn(move || write_lines(file, written))?;
+
+        Ok(Self { lines })
+    }
+
+    /// Append an event, timestamped now.
+    /// Problems writing are logged, but otherwise ignored such that allocation is not
+    /// held up by the journal.
+    pub fn record(&self, event: Event) {
+        let entry = Entry {
+            time: now_ms(),
+            event,
+        };
+
+        let mut line = match serde_json::to_string(&entry) {
+            Ok(line) => line,
+            Err(e) => {
+                error!(?e, "Could not serialize journal entry");
+                return;
+            }
+        };
+        line.push('\n');
+
+        if self.lines.send(line).is_err() {
+            error!("Journal writer is gone, dropping entry");
+        }
+    }
+}
+
+/// Writes lines until every handle to the journal is dropped.
+fn write_lines(mut file: File, lines: mpsc::Receiver<String>) {
+    for line in lines {
+        if let Err(e) = file.write_all(line.as_bytes()) {
+            error!(?e, "Could not write journal entry");
+        }
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// Read back all entries of the journal at the given path.
+pub fn replay(path: impl AsRef<Path>) -> Result<Vec<Entry>> {
+    let reader = BufReader::new(File::open(path)?);
+
+    let mut entries = vec![];
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        entries.push(serde_json::from_str(&line)?);
+    }
+
+    Ok(entries)
+}
+
+/// How much something was used.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Usage {
+    /// Number of leases granted.
+    pub leases: usize,
+    /// Total time from being granted until released or expired.
+    pub held: Duration,
+}
+
+impl Usage {
+    fn add(&mut self, held: Duration) {
+        self.leases += 1;
+        self.held += held;
+    }
+}
+
+/// Usage per resource and per client, built from journal entries.
+///
+/// A lease handed over to another client counts as one for the resource,
+/// and as one for each client which held it, for as long as they did.
+///
+/// Leases which were granted but never ended (e.g. the allocator was stopped)
+/// are not counted.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Summary {
+    pub per_resource: HashMap<String, Usage>,
+    pub per_client: HashMap<Option<String>, Usage>,
+}
+
+impl Summary {
+    pub fn from_entries<'a>(entries: impl IntoIterator<Item = &'a Entry>) -> Self {
+        let mut summary = Self::default();
+        // Granted leases by request number: (time granted, time the holder got it, holder).
+        let mut granted: HashMap<usize, (u64, u64, Option<String>)> = HashMap::new();
+
+        for entry in entries {
+            match &entry.event {
+                Event::Granted {
+                    request, client, ..
+                } => {
+                    granted.insert(*request, (entry.time, entry.time, client.clone()));
+                }
+                // The resource stays leased out, but to someone else from here on.
+                Event::Transferred {
+                    request, client, ..
+                } => {
+                    if let Some((_, since, holder)) = granted.get_mut(request) {
+                        let held = Duration::from_millis(entry.time.saturating_sub(*since));
+                        let previous = std::mem::replace(holder, client.clone());
+                        summary.per_client.entry(previous).or_default().add(held);
+                        *since = entry.time;
+                    }
+                }
+                Event::Released { request, resource }
+                | Event::Expired { request, resource }
+                | Event::Closed {
+                    request, resource, ..
+                }
+                | Event::SessionFailed {
+                    request, resource, ..
+                } => {
+                    if let Some((granted, since, client)) = granted.remove(request) {
+                        let held = |since| Duration::from_millis(entry.time.saturating_sub(since));
+                        summary
+                            .per_resource
+                            .entry(resource.clone())
+                            .or_default()
+                            .add(held(granted));
+                        summary
+                            .per_client
+                            .entry(client)
+                            .or_default()
+                            .add(held(since));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        summary
+    }
+}
+
+/// Summarize the journal at the given path.
+pub fn summarize(path: impl AsRef<Path>) -> Result<Summary> {
+    Ok(Summary::from_entries(&replay(path)?))
+}
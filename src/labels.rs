@@ -0,0 +1,334 @@
+//! Descriptions made of key/value labels, queried with selectors.
+//!
+//! Resources describe themselves with exact labels, e.g. `site=osl, rack=3, type=nrf52`.
+//! Requests use selectors on those labels, which may be:
+//!
+//! * exact: `type=nrf52`
+//! * wildcard: `fw=1.*`, where `*` matches anything
+//! * set-membership: `rack in (3, 4)`
+//! * not: `type!=nrf52`, `rack notin (3, 4)`
+//! * existence: `gpu`, or `!gpu` for absence
+//!
+//! A resource matches a request if it satisfies all of the request's selectors,
+//! including several on the same label such as `rack!=3, rack!=4`.
+//! Implement [`Labelled`] for a resource to use labels as its description,
+//! parsing its labels with [`Labels::parse_literal`] such that values are taken as they are.
+
+use std::{collections::BTreeSet, fmt::Display, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::resource_filter::Describable;
+
+/// A condition on the value of a single label.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Selector {
+    /// The label has exactly this value.
+    Exact(String),
+    /// The label's value matches this pattern, where `*` matches any run of characters.
+    Wildcard(String),
+    /// The label has one of these values.
+    In(BTreeSet<String>),
+    /// The inner selector does not hold. Also holds if the label is missing.
+    Not(Box<Selector>),
+}
+
+impl Selector {
+    pub fn exact(value: impl Into<String>) -> Self {
+        Self::Exact(value.into())
+    }
+
+    pub fn wildcard(pattern: impl Into<String>) -> Self {
+        Self::Wildcard(pattern.into())
+    }
+
+    pub fn any_of<V: Into<String>>(values: impl IntoIterator<Item = V>) -> Self {
+        Self::In(values.into_iter().map(Into::into).collect())
+    }
+
+    #[allow(clippy::should_implement_trait)]
+    pub fn not(selector: Selector) -> Self {
+        Self::Not(Box::new(selector))
+    }
+
+    /// The label is present, with any value.
+    pub fn exists() -> Self {
+        Self::wildcard("*")
+    }
+
+    /// The label is not present.
+    pub fn absent() -> Self {
+        Self::not(Self::exists())
+    }
+
+    /// Whether the selector holds for a label with the given value, or `None` if missing.
+    pub fn matches(&self, value: Option<&str>) -> bool {
+        match (self, value) {
+            (Self::Not(inner), value) => !inner.matches(value),
+            (_, None) => false,
+            (Self::Exact(expected), Some(value)) => expected == value,
+            (Self::Wildcard(pattern), Some(value)) => wildcard_matches(pattern, value),
+            (Self::In(values), Some(value)) => values.contains(value),
+        }
+    }
+}
+
+/// Whether `value` matches `pattern`, where `*` in the pattern matches any run of characters.
+fn wildcard_matches(pattern: &str, value: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = value.strip_prefix(first) else {
+        return false;
+    };
+
+    let mut parts = parts.collect::<Vec<_>>();
+    // No `*` at all, the pattern must be the whole value.
+    let Some(last) = parts.pop() else {
+        return rest.is_empty();
+    };
+
+    for part in parts {
+        match rest.find(part) {
+            Some(position) => rest = &rest[position + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+/// Labels on a resource, or selectors in a request.
+///
+/// Built with [`Labels::with`] and [`Labels::with_selector`], or parsed from
+/// a comma separated list such as `"site=osl, rack in (3, 4), fw=1.*, !broken"`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Labels {
+    // Ordered by key, such that equal labels compare equal however they were built.
+    selectors: Vec<(String, Selector)>,
+}
+
+impl Labels {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a label with an exact value.
+    pub fn with(self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.with_selector(key, Selector::exact(value))
+    }
+
+    /// Add a selector on a label.
+    /// Selectors add up, several on the same label must all hold.
+    pub fn with_selector(mut self, key: impl Into<String>, selector: Selector) -> Self {
+        let key = key.into();
+        let position = self
+            .selectors
+            .partition_point(|(existing, _)| *existing <= key);
+        self.selectors.insert(position, (key, selector));
+        self
+    }
+
+    /// The exact value of a label, if it has one.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.selectors
+            .iter()
+            .filter(|(existing, _)| existing == key)
+            .find_map(|(_, selector)| match selector {
+                Selector::Exact(value) => Some(value.as_str()),
+                _ => None,
+            })
+    }
+
+    /// Parse the labels of a resource, a comma separated list of `key=value`.
+    /// Unlike when parsing selectors, values are taken as they are, `*` included.
+    pub fn parse_literal(s: &str) -> Result<Self, ParseLabelsError> {
+        let mut labels = Labels::new();
+        for term in split_terms(s) {
+            let invalid = || ParseLabelsError(format!("invalid label `{term}`"));
+            let (key, value) = term.split_once('=').ok_or_else(invalid)?;
+            let (key, value) = (parse_key(key).ok_or_else(invalid)?, value.trim());
+            if value.is_empty() {
+                return Err(invalid());
+            }
+            labels = labels.with(key, value);
+        }
+        Ok(labels)
+    }
+
+    /// Whether the given labels satisfy all selectors in `self`.
+    /// Only exact labels count as present in `labels`.
+    pub fn selects(&self, labels: &Labels) -> bool {
+        self.selectors
+            .iter()
+            .all(|(key, selector)| selector.matches(labels.get(key)))
+    }
+
+    /// Scores resources described by `description` for a request of selectors,
+    /// see [`Describable::score`]. All resources satisfying the selectors score the same.
+    pub fn score(description: &Labels, request: &Labels) -> Option<u32> {
+        request.selects(description).then_some(0)
+    }
+}
+
+/// A resource described by labels.
+///
+/// Implementing this gives a [`Describable<Labels>`] implementation,
+/// such that requests are matched by [`Labels::selects`].
+/// Only exact labels count, so build them with [`Labels::with`]
+/// or parse them with [`Labels::parse_literal`].
+pub trait Labelled {
+    fn labels(&self) -> Labels;
+
+    /// See [`Describable::id`].
+    fn id(&self) -> Option<String> {
+        None
+    }
+}
+
+impl<T> Describable<Labels> for T
+where
+    T: Labelled,
+{
+    fn describe(&self) -> Labels {
+        self.labels()
+    }
+
+    fn id(&self) -> Option<String> {
+        Labelled::id(self)
+    }
+
+    fn score(description: &Labels, request: &Labels) -> Option<u32> {
+        Labels::score(description, request)
+    }
+}
+
+impl Display for Labels {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (position, (key, selector)) in self.selectors.iter().enumerate() {
+            if position > 0 {
+                write!(f, ", ")?;
+            }
+            let set =
+                |values: &BTreeSet<String>| values.iter().cloned().collect::<Vec<_>>().join(", ");
+            match selector {
+                Selector::Wildcard(value) if value == "*" => write!(f, "{key}")?,
+                Selector::Exact(value) | Selector::Wildcard(value) => write!(f, "{key}={value}")?,
+                Selector::In(values) => write!(f, "{key} in ({})", set(values))?,
+                Selector::Not(inner) => match inner.as_ref() {
+                    Selector::Wildcard(value) if value == "*" => write!(f, "!{key}")?,
+                    Selector::Exact(value) | Selector::Wildcard(value) => {
+                        write!(f, "{key}!={value}")?
+                    }
+                    Selector::In(values) => write!(f, "{key} notin ({})", set(values))?,
+                    // Double negation has no syntax of its own.
+                    Selector::Not(_) => write!(f, "{key}=<{selector:?}>")?,
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A problem parsing [`Labels`] from a string.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseLabelsError(String);
+
+impl Display for ParseLabelsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Could not parse labels: {}", self.0)
+    }
+}
+
+impl std::error::Error for ParseLabelsError {}
+
+impl FromStr for Labels {
+    type Err = ParseLabelsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut labels = Labels::new();
+        for term in split_terms(s) {
+            let (key, selector) = parse_term(term)?;
+            labels = labels.with_selector(key, selector);
+        }
+        Ok(labels)
+    }
+}
+
+/// Split on commas which are not within parentheses, skipping empty terms.
+fn split_terms(s: &str) -> Vec<&str> {
+    let mut terms = vec![];
+    let mut depth = 0usize;
+    let mut start = 0;
+    for (position, c) in s.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth = depth.saturating_sub(1),
+            ',' if depth == 0 => {
+                terms.push(&s[start..position]);
+                start = position + 1;
+            }
+            _ => {}
+        }
+    }
+    terms.push(&s[start..]);
+
+    terms
+        .into_iter()
+        .map(str::trim)
+        .filter(|term| !term.is_empty())
+        .collect()
+}
+
+/// A label's key, which must be a single word.
+fn parse_key(key: &str) -> Option<String> {
+    let key = key.trim();
+    if key.is_empty() || key.contains(|c: char| c.is_whitespace() || "=!()".contains(c)) {
+        None
+    } else {
+        Some(key.to_string())
+    }
+}
+
+fn parse_term(term: &str) -> Result<(String, Selector), ParseLabelsError> {
+    let invalid = || ParseLabelsError(format!("invalid selector `{term}`"));
+    let key = |key: &str| parse_key(key).ok_or_else(invalid);
+    let value = |value: &str| {
+        let value = value.trim();
+        if value.is_empty() {
+            Err(invalid())
+        } else if value.contains('*') {
+            Ok(Selector::wildcard(value))
+        } else {
+            Ok(Selector::exact(value))
+        }
+    };
+    let set = |values: &str| {
+        let values = values
+            .trim()
+            .strip_prefix('(')
+            .and_then(|values| values.strip_suffix(')'))
+            .ok_or_else(invalid)?;
+        Ok(Selector::any_of(
+            values
+                .split(',')
+                .map(str::trim)
+                .filter(|value| !value.is_empty()),
+        ))
+    };
+
+    if let Some((k, v)) = term.split_once("!=") {
+        return Ok((key(k)?, Selector::not(value(v)?)));
+    }
+    if let Some((k, v)) = term.split_once('=') {
+        return Ok((key(k)?, value(v.strip_prefix('=').unwrap_or(v))?));
+    }
+    if let Some((k, v)) = term.split_once(" notin ") {
+        return Ok((key(k)?, Selector::not(set(v)?)));
+    }
+    if let Some((k, v)) = term.split_once(" in ") {
+        return Ok((key(k)?, set(v)?));
+    }
+    if let Some(k) = term.strip_prefix('!') {
+        return Ok((key(k)?, Selector::absent()));
+    }
+    Ok((key(term)?, Selector::exists()))
+}
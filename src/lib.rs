@@ -3,9 +3,19 @@
 
 pub mod allocator;
 pub mod allocator_client;
+pub mod allocator_federation;
+pub mod codec;
+pub mod compression;
 pub mod error;
+pub mod journal;
+pub mod labels;
+pub mod metrics;
 pub mod mux_client;
 pub mod mux_server;
 pub mod resource_filter;
+pub mod selection;
 pub mod slab_store;
 pub mod tagged;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod transport;
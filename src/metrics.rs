@@ -0,0 +1,439 @@
+//! Prometheus style metrics for the allocator and the multiplexed transport.
+//!
+//! Metrics are always collected in a process wide registry.
+//! Use [`render`] to get them in the Prometheus text format,
+//! or [`serve`] to expose them on a local HTTP endpoint.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard, OnceLock,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+};
+use tracing::{debug, error};
+
+use crate::{error::Result, transport};
+
+pub(crate) const ALLOCATION_REQUESTS: &str = "leaning_tower_allocation_requests_total";
+pub(crate) const ALLOCATION_REJECTED: &str = "leaning_tower_allocation_rejected_total";
+pub(crate) const ALLOCATION_WAIT: &str = "leaning_tower_allocation_wait_seconds";
+pub(crate) const ALLOCATION_QUEUE_DEPTH: &str = "leaning_tower_allocation_queue_depth";
+pub(crate) const LEASE_HOLD: &str = "leaning_tower_lease_hold_seconds";
+pub(crate) const RESOURCES_TOTAL: &str = "leaning_tower_resources";
+pub(crate) const RESOURCES_LEASED: &str = "leaning_tower_resources_leased";
+const RESOURCES_UTILIZATION: &str = "leaning_tower_resource_utilization";
+pub(crate) const MUX_REQUESTS: &str = "leaning_tower_mux_requests_total";
+pub(crate) const MUX_REQUEST_DURATION: &str = "leaning_tower_mux_request_duration_seconds";
+pub(crate) const MUX_BYTES_RECEIVED: &str = "leaning_tower_mux_received_bytes_total";
+pub(crate) const MUX_BYTES_SENT: &str = "leaning_tower_mux_sent_bytes_total";
+
+const HELP: &[(&str, &str)] = &[
+    (
+        ALLOCATION_REQUESTS,
+        "Allocation requests received, by description.",
+    ),
+    (
+        ALLOCATION_REJECTED,
+        "Allocation requests turned away because the wait queue was full, by description.",
+    ),
+    (
+        ALLOCATION_WAIT,
+        "Time from an allocation request until a resource was granted.",
+    ),
+    (
+        ALLOCATION_QUEUE_DEPTH,
+        "Allocation requests currently waiting for a resource.",
+    ),
+    (LEASE_HOLD, "Time a leased resource was held."),
+    (
+        RESOURCES_TOTAL,
+        "Resources managed by allocators, by description.",
+    ),
+    (
+        RESOURCES_LEASED,
+        "Resources currently leased out, by description.",
+    ),
+    (
+        RESOURCES_UTILIZATION,
+        "Fraction of resources currently leased out, by description.",
+    ),
+    (MUX_REQUESTS, "Requests handled on multiplexed connections."),
+    (
+        MUX_REQUEST_DURATION,
+        "Latency of requests on multiplexed connections.",
+    ),
+    (
+        MUX_BYTES_RECEIVED,
+        "Bytes received on multiplexed connections.",
+    ),
+    (MUX_BYTES_SENT, "Bytes sent on multiplexed connections."),
+];
+
+/// Upper bounds of histogram buckets, in seconds.
+const BUCKETS: &[f64] = &[
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 300.0, 900.0,
+    3600.0,
+];
+
+type Labels = Vec<(&'static str, String)>;
+type Key = (&'static str, Labels);
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Counter(Arc<AtomicU64>);
+
+impl Counter {
+    pub(crate) fn add(&self, value: u64) {
+        self.0.fetch_add(value, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Gauge(Arc<AtomicI64>);
+
+impl Gauge {
+    pub(crate) fn add(&self, value: i64) {
+        self.0.fetch_add(value, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Increment the gauge until the returned guard is dropped.
+    pub(crate) fn track(&self) -> GaugeGuard {
+        self.add(1);
+        GaugeGuard(self.clone())
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct GaugeGuard(Gauge);
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        self.0.add(-1)
+    }
+}
+
+#[derive(Debug, Default)]
+struct HistogramData {
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Histogram(Arc<Mutex<HistogramData>>);
+
+impl Histogram {
+    pub(crate) fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let mut data = lock(&self.0);
+        if data.buckets.is_empty() {
+            data.buckets = vec![0; BUCKETS.len()];
+        }
+        for (bucket, bound) in data.buckets.iter_mut().zip(BUCKETS) {
+            if seconds <= *bound {
+                *bucket += 1;
+            }
+        }
+        data.sum += seconds;
+        data.count += 1;
+    }
+}
+
+#[derive(Debug, Default)]
+struct Registry {
+    counters: BTreeMap<Key, Counter>,
+    gauges: BTreeMap<Key, Gauge>,
+    histograms: BTreeMap<Key, Histogram>,
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+fn registry() -> MutexGuard<'static, Registry> {
+    static REGISTRY: OnceLock<Mutex<Registry>> = OnceLock::new();
+    lock(REGISTRY.get_or_init(Default::default))
+}
+
+fn key(name: &'static str, labels: &[(&'static str, &str)]) -> Key {
+    (
+        name,
+        labels
+            .iter()
+            .map(|(label, value)| (*label, value.to_string()))
+            .collect(),
+    )
+}
+
+pub(crate) fn counter(name: &'static str, labels: &[(&'static str, &str)]) -> Counter {
+    registry()
+        .counters
+        .entry(key(name, labels))
+        .or_default()
+        .clone()
+}
+
+pub(crate) fn gauge(name: &'static str, labels: &[(&'static str, &str)]) -> Gauge {
+    registry()
+        .gauges
+        .entry(key(name, labels))
+        .or_default()
+        .clone()
+}
+
+pub(crate) fn histogram(name: &'static str, labels: &[(&'static str, &str)]) -> Histogram {
+    registry()
+        .histograms
+        .entry(key(name, labels))
+        .or_default()
+        .clone()
+}
+
+/// Request count and latency for one side of a multiplexed connection.
+#[derive(Debug, Clone)]
+pub(crate) struct ConnectionMetrics {
+    requests: Counter,
+    duration: Histogram,
+    received: Counter,
+    sent: Counter,
+}
+
+impl ConnectionMetrics {
+    /// `side` is either "client" or "server", `addr` is the address connected to or from.
+    /// Connections are counted by the kind of transport only,
+    /// such that the number of series stays bounded however many come and go.
+    pub(crate) fn new(side: &str, addr: &str) -> Self {
+        let labels = [("side", side), ("transport", transport::kind(addr))];
+        Self {
+            requests: counter(MUX_REQUESTS, &labels),
+            duration: histogram(MUX_REQUEST_DURATION, &labels),
+            received: counter(MUX_BYTES_RECEIVED, &labels),
+            sent: counter(MUX_BYTES_SENT, &labels),
+        }
+    }
+
+    pub(crate) fn request_done(&self, duration: Duration) {
+        self.requests.add(1);
+        self.duration.observe(duration);
+    }
+
+    /// Wrap a stream such that bytes going through it are counted.
+    pub(crate) fn count<S>(&self, inner: S) -> Counted<S> {
+        Counted {
+            inner,
+            received: self.received.clone(),
+            sent: self.sent.clone(),
+        }
+    }
+}
+
+/// A stream which counts bytes read and written.
+#[derive(Debug)]
+pub(crate) struct Counted<S> {
+    inner: S,
+    received: Counter,
+    sent: Counter,
+}
+
+impl<S> AsyncRead for Counted<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        self.received.add((buf.filled().len() - before) as u64);
+        result
+    }
+}
+
+impl<S> AsyncWrite for Counted<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = result {
+            self.sent.add(written as u64);
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+fn write_labels(out: &mut String, labels: &[(&'static str, String)], extra: Option<(&str, &str)>) {
+    let mut all = labels
+        .iter()
+        .map(|(label, value)| (*label, value.as_str()))
+        .chain(extra)
+        .peekable();
+    if all.peek().is_none() {
+        return;
+    }
+
+    out.push('{');
+    for (index, (label, value)) in all.enumerate() {
+        if index > 0 {
+            out.push(',');
+        }
+        let value = value
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n");
+        let _ = write!(out, "{label}=\"{value}\"");
+    }
+    out.push('}');
+}
+
+fn write_header(out: &mut String, last: &mut Option<&'static str>, name: &'static str, kind: &str) {
+    if *last == Some(name) {
+        return;
+    }
+    *last = Some(name);
+
+    if let Some((_, help)) = HELP.iter().find(|(metric, _)| *metric == name) {
+        let _ = writeln!(out, "# HELP {name} {help}");
+    }
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+/// Render all metrics in the Prometheus text exposition format.
+pub fn render() -> String {
+    let registry = registry();
+    let mut out = String::new();
+    let mut last = None;
+
+    for ((name, labels), counter) in &registry.counters {
+        write_header(&mut out, &mut last, name, "counter");
+        out.push_str(name);
+        write_labels(&mut out, labels, None);
+        let _ = writeln!(out, " {}", counter.0.load(Ordering::Relaxed));
+    }
+
+    for ((name, labels), gauge) in &registry.gauges {
+        write_header(&mut out, &mut last, name, "gauge");
+        out.push_str(name);
+        write_labels(&mut out, labels, None);
+        let _ = writeln!(out, " {}", gauge.get());
+    }
+
+    // Utilization is derived from the leased and total resource gauges.
+    for ((name, labels), total) in &registry.gauges {
+        if *name != RESOURCES_TOTAL || total.get() <= 0 {
+            continue;
+        }
+        let leased = registry
+            .gauges
+            .get(&(RESOURCES_LEASED, labels.clone()))
+            .map(Gauge::get)
+            .unwrap_or_default();
+
+        write_header(&mut out, &mut last, RESOURCES_UTILIZATION, "gauge");
+        out.push_str(RESOURCES_UTILIZATION);
+        write_labels(&mut out, labels, None);
+        let _ = writeln!(out, " {}", leased as f64 / total.get() as f64);
+    }
+
+    for ((name, labels), histogram) in &registry.histograms {
+        write_header(&mut out, &mut last, name, "histogram");
+        let data = lock(&histogram.0);
+
+        for (index, bound) in BUCKETS.iter().enumerate() {
+            let _ = write!(out, "{name}_bucket");
+            write_labels(&mut out, labels, Some(("le", &bound.to_string())));
+            let count = data.buckets.get(index).copied().unwrap_or_default();
+            let _ = writeln!(out, " {count}");
+        }
+        let _ = write!(out, "{name}_bucket");
+        write_labels(&mut out, labels, Some(("le", "+Inf")));
+        let _ = writeln!(out, " {}", data.count);
+
+        let _ = write!(out, "{name}_sum");
+        write_labels(&mut out, labels, None);
+        let _ = writeln!(out, " {}", data.sum);
+
+        let _ = write!(out, "{name}_count");
+        write_labels(&mut out, labels, None);
+        let _ = writeln!(out, " {}", data.count);
+    }
+
+    out
+}
+
+async fn respond(mut stream: TcpStream) -> io::Result<()> {
+    // We only care about the request line, which fits easily.
+    let mut buf = [0; 1024];
+    let read = stream.read(&mut buf).await?;
+    let request = String::from_utf8_lossy(&buf[..read]);
+    let path = request.split_whitespace().nth(1).unwrap_or_default();
+
+    let (status, body) = match path {
+        "/" | "/metrics" => ("200 OK", render()),
+        _ => ("404 Not Found", String::new()),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Serve metrics over HTTP on the given bind address, at `/metrics`.
+pub async fn serve(bind: &str) -> Result<JoinHandle<()>> {
+    let rx = TcpListener::bind(bind).await?;
+
+    let handle = tokio::spawn(async move {
+        loop {
+            let (stream, _) = match rx.accept().await {
+                Ok(rx) => rx,
+                Err(e) => {
+                    error!(?e, "Problem accepting on metrics listener");
+                    return;
+                }
+            };
+
+            tokio::spawn(async move {
+                if let Err(e) = respond(stream).await {
+                    debug!(?e, "Problem responding with metrics");
+                }
+            });
+        }
+    });
+
+    Ok(handle)
+}
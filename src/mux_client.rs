@@ -1,17 +1,24 @@
 use std::{
+    io,
     pin::Pin,
+    sync::{Arc, Mutex},
     task::{Context, Poll},
+    time::Instant,
 };
 
-use async_bincode::{AsyncBincodeStream, AsyncDestination};
 use futures::Future;
 use serde::{de::DeserializeOwned, Serialize};
-use tokio::net::TcpStream;
 use tokio_tower::multiplex::{self, MultiplexTransport};
 use tower::{BoxError, Service};
-use tracing::{debug, error};
+use tracing::{debug, error, field, info_span, warn, Instrument, Span};
 
-use crate::{error::Result, slab_store, tagged};
+use crate::{
+    allocator::{AllocatorError, CloseReason, TransferTicket},
+    error::Result,
+    metrics::ConnectionMetrics,
+    slab_store, tagged,
+    transport::{self, Framed, HandshakeError, TransportConfig},
+};
 
 /// Multiplexing client which automatically tags requests and de-tags responses.
 /// Must target a multiplexing server.
@@ -23,18 +30,63 @@ where
     #[allow(clippy::type_complexity)]
     client: multiplex::Client<
         MultiplexTransport<
-            AsyncBincodeStream<
-                TcpStream,
-                tagged::Response<Resp>,
-                tagged::Request<Req>,
-                AsyncDestination,
-            >,
+            Framed<tagged::Response<Resp>, tagged::Request<Req>>,
             slab_store::SlabStore,
         >,
         tower::BoxError,
         tagged::Request<Req>,
     >,
     label: Option<String>,
+    resource: Option<String>,
+    lease: Option<Lease>,
+    // A leased connection broke while polling readiness, reported by the next call.
+    broken: Option<BoxError>,
+    failure: Failure,
+    metrics: ConnectionMetrics,
+}
+
+/// Why the connection broke, as told by the transport.
+/// Calls which fail because of it are told this, rather than that the client went away.
+#[derive(Clone, Default)]
+struct Failure(Arc<Mutex<Option<(io::ErrorKind, String)>>>);
+
+impl Failure {
+    fn lock(&self) -> std::sync::MutexGuard<'_, Option<(io::ErrorKind, String)>> {
+        match self.0.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    fn record(&self, e: &io::Error) {
+        self.lock().get_or_insert_with(|| (e.kind(), e.to_string()));
+    }
+
+    fn or(&self, e: BoxError) -> BoxError {
+        match &*self.lock() {
+            Some((kind, message)) => io::Error::new(*kind, message.clone()).into(),
+            None => e,
+        }
+    }
+}
+
+/// Talks to the allocator about a lease on behalf of the client using it.
+pub(crate) trait LeaseControl: Send + Sync {
+    fn transfer(&self, lease: u64) -> Pin<Box<dyn Future<Output = Result<TransferTicket>> + Send>>;
+
+    fn release(&self, lease: u64) -> Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+
+    fn why_closed(
+        &self,
+        lease: u64,
+    ) -> Pin<Box<dyn Future<Output = Result<Option<CloseReason>>> + Send>>;
+}
+
+/// A lease from an allocator.
+#[derive(Clone)]
+pub(crate) struct Lease {
+    pub(crate) id: u64,
+    pub(crate) control: Arc<dyn LeaseControl>,
 }
 
 impl<Req, Resp> std::fmt::Debug for MuxClient<Req, Resp>
@@ -45,6 +97,7 @@ where
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("MuxClient")
             .field("label", &self.label)
+            .field("resource", &self.resource)
             .finish()
     }
 }
@@ -54,24 +107,116 @@ where
     Req: Serialize + Send + 'static + Clone,
     Resp: DeserializeOwned + Send + 'static,
 {
-    pub(crate) async fn new_impl(addr: &str, label: Option<String>) -> Result<Self> {
-        let tx = TcpStream::connect(addr).await?;
-        let tx = AsyncBincodeStream::from(tx).for_async();
+    pub(crate) async fn new_impl(
+        addr: &str,
+        label: Option<String>,
+        config: &TransportConfig,
+    ) -> Result<Self> {
+        let metrics = ConnectionMetrics::new("client", addr);
+        let tx = match Self::open(addr, config, &metrics).await {
+            Err(e) if e.kind() == io::ErrorKind::Unsupported => {
+                let Some(config) = config.without_handshake() else {
+                    return Err(HandshakeError::NoHandshake.into());
+                };
+                warn!(%addr, "Server does not do handshakes, connecting without");
+                Self::open(addr, &config, &metrics).await
+            }
+            tx => tx,
+        }
+        .map_err(|e| match handshake_error(&e) {
+            Some(handshake) => handshake.into(),
+            None => BoxError::from(e),
+        })?;
 
+        let failure = Failure::default();
         let client = multiplex::Client::with_error_handler(
             multiplex::MultiplexTransport::new(tx, slab_store::SlabStore::default()),
-            |e| error!("Client error: {:?}", e),
+            {
+                let failure = failure.clone();
+                move |e: BoxError| {
+                    error!("Client error: {:?}", e);
+                    if let Some(e) = e.source().and_then(|e| e.downcast_ref::<io::Error>()) {
+                        failure.record(e);
+                    }
+                }
+            },
         );
 
-        Ok(Self { client, label })
+        Ok(Self {
+            client,
+            label,
+            resource: None,
+            lease: None,
+            broken: None,
+            failure,
+            metrics,
+        })
+    }
+
+    /// Connect, and set the connection up as configured.
+    async fn open(
+        addr: &str,
+        config: &TransportConfig,
+        metrics: &ConnectionMetrics,
+    ) -> io::Result<Framed<tagged::Response<Resp>, tagged::Request<Req>>> {
+        let tx = transport::connect(addr).await?;
+        let tx = config.connect(Box::new(metrics.count(tx)), addr).await?;
+        config.frame_connected(tx).await
+    }
+
+    pub(crate) fn with_resource(mut self, resource: String) -> Self {
+        self.resource = Some(resource);
+        self
+    }
+
+    pub(crate) fn with_lease(mut self, lease: Lease) -> Self {
+        self.lease = Some(lease);
+        self
+    }
+
+    /// Hand the lease this client uses over to another client, without the resource
+    /// becoming free in between.
+    ///
+    /// The returned ticket is redeemed with
+    /// [`crate::allocator_client::AllocatorClientService::redeem`], possibly in another process.
+    /// This client is closed, and the allocator keeps the resource until the ticket is redeemed
+    /// or it times out, see [`crate::allocator::AllocatorService::with_transfer_timeout`].
+    pub async fn transfer(mut self) -> Result<TransferTicket> {
+        let Some(lease) = self.lease.take() else {
+            return Err("Not leased out by an allocator".into());
+        };
+        lease.control.transfer(lease.id).await
+    }
+
+    /// Give the lease this client uses back to the allocator.
+    /// Returns once the allocator confirmed the resource is free again.
+    ///
+    /// Dropping the client releases the lease too, but without waiting for it.
+    pub async fn release(mut self) -> Result<()> {
+        let Some(lease) = self.lease.take() else {
+            return Err("Not leased out by an allocator".into());
+        };
+        lease.control.release(lease.id).await
+    }
+
+    /// The identity of the resource this client uses,
+    /// if it was leased out by an allocator.
+    pub fn resource(&self) -> Option<&str> {
+        self.resource.as_deref()
     }
 
     pub async fn new(addr: &str) -> Result<Self> {
-        Self::new_impl(addr, None).await
+        Self::new_impl(addr, None, &TransportConfig::default()).await
     }
 
     pub async fn new_labelled(addr: &str, label: &str) -> Result<Self> {
-        Self::new_impl(addr, Some(label.to_string())).await
+        Self::new_impl(addr, Some(label.to_string()), &TransportConfig::default()).await
+    }
+
+    /// Connect with the given transport configuration,
+    /// which must match the server's (see [`crate::mux_server::run_with`]).
+    pub async fn new_with(addr: &str, config: &TransportConfig) -> Result<Self> {
+        Self::new_impl(addr, None, config).await
     }
 }
 
@@ -87,12 +232,73 @@ where
     type Future = Pin<Box<dyn Future<Output = Result<Self::Response>> + Send>>;
 
     fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
-        self.client.poll_ready(cx).map_err(Into::into)
+        match self.client.poll_ready(cx) {
+            // The call can ask the allocator why the connection broke, polling can't.
+            Poll::Ready(Err(e)) if self.lease.is_some() => {
+                self.broken = Some(self.failure.or(e));
+                Poll::Ready(Ok(()))
+            }
+            ready => ready.map_err(|e| self.failure.or(e)),
+        }
     }
 
     fn call(&mut self, request: Req) -> Self::Future {
-        let future = self.client.call(tagged::Request::new(request));
-        Box::pin(async move { future.await.map(|tagged_response| tagged_response.inner()) })
+        if let Some(e) = self.broken.take() {
+            return Box::pin(explain(self.lease.clone(), e));
+        }
+
+        let span = info_span!("mux-client-call", label = ?self.label, trace_id = field::Empty);
+
+        // The trace is propagated whether or not this call is traced here,
+        // servers may well be interested in it.
+        // Without a span of its own, the call is made on behalf of the span it's made from.
+        let sender = if span.is_disabled() {
+            Span::current()
+        } else {
+            span.clone()
+        };
+        let trace = tagged::TraceContext::child_of(&sender);
+        span.record(
+            "trace_id",
+            field::display(format_args!("{:016x}", trace.trace_id)),
+        );
+        let sending = trace.sent_from(&sender);
+        let request = tagged::Request::new(request).with_trace(trace);
+
+        let future = span.in_scope(|| self.client.call(request));
+        let metrics = self.metrics.clone();
+        let lease = self.lease.clone();
+        let failure = self.failure.clone();
+        let start = Instant::now();
+
+        Box::pin(
+            async move {
+                let response = future.await;
+                drop(sending);
+                metrics.request_done(start.elapsed());
+                match response {
+                    Ok(tagged_response) => Ok(tagged_response.inner()),
+                    Err(e) => explain(lease, failure.or(e)).await,
+                }
+            }
+            .instrument(span),
+        )
+    }
+}
+
+/// Why the handshake failed, if that's why connecting did.
+fn handshake_error(e: &io::Error) -> Option<HandshakeError> {
+    e.get_ref()?.downcast_ref::<HandshakeError>().cloned()
+}
+
+/// If the allocator closed the lease, tell why instead of the error it caused.
+pub(crate) async fn explain<T>(lease: Option<Lease>, e: BoxError) -> Result<T> {
+    let Some(lease) = lease else {
+        return Err(e);
+    };
+    match lease.control.why_closed(lease.id).await {
+        Ok(Some(reason)) => Err(AllocatorError::LeaseClosed(reason).into()),
+        _ => Err(e),
     }
 }
 
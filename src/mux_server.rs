@@ -1,27 +1,104 @@
 use std::{
+    io,
     pin::Pin,
+    sync::{Arc, Mutex},
     task::{Context, Poll},
     time::Duration,
 };
 
-use async_bincode::AsyncBincodeStream;
 use futures::Future;
 use serde::{de::DeserializeOwned, Serialize};
-use tokio::{net::TcpListener, task::JoinHandle};
+use tokio::{sync::oneshot, task::JoinHandle, time::Instant};
 use tokio_tower::multiplex;
 use tower::{buffer::Buffer, Service};
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, info_span, Instrument, Span};
 
-use crate::{error::Result, tagged};
+use crate::{
+    error::Result,
+    metrics::ConnectionMetrics,
+    tagged,
+    transport::{Connection, Framed, Listener, PeerIdentity, TransportConfig},
+};
 
 // TODO: Could be a layer? Probably more idiomatic.
 pub struct Detagger<S> {
     inner: S,
+    metrics: Option<ConnectionMetrics>,
+    activity: Option<Activity>,
+    peer: Option<PeerIdentity>,
 }
 
 impl<S> Detagger<S> {
     pub fn new(inner: S) -> Self {
-        Self { inner }
+        Self {
+            inner,
+            metrics: None,
+            activity: None,
+            peer: None,
+        }
+    }
+
+    pub(crate) fn with_metrics(inner: S, metrics: ConnectionMetrics) -> Self {
+        Self {
+            inner,
+            metrics: Some(metrics),
+            activity: None,
+            peer: None,
+        }
+    }
+
+    pub(crate) fn with_activity(mut self, activity: Activity) -> Self {
+        self.activity = Some(activity);
+        self
+    }
+
+    /// Requests are served as the given client, see [`PeerIdentity::current`].
+    pub(crate) fn with_peer(mut self, peer: Option<PeerIdentity>) -> Self {
+        self.peer = peer;
+        self
+    }
+}
+
+/// When a connection last saw traffic, and how many requests are in flight on it.
+/// The clock starts once a client connects.
+#[derive(Debug, Clone)]
+pub(crate) struct Activity(Arc<Mutex<(Option<Instant>, usize)>>);
+
+impl Activity {
+    pub(crate) fn new() -> Self {
+        Self(Arc::new(Mutex::new((None, 0))))
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, (Option<Instant>, usize)> {
+        match self.0.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    fn connected(&self) {
+        self.lock().0 = Some(Instant::now());
+    }
+
+    fn request_started(&self) {
+        let mut activity = self.lock();
+        activity.0 = Some(Instant::now());
+        activity.1 += 1;
+    }
+
+    fn request_done(&self) {
+        let mut activity = self.lock();
+        activity.0 = Some(Instant::now());
+        activity.1 = activity.1.saturating_sub(1);
+    }
+
+    /// How long there has been no traffic. Never idle while requests are in flight,
+    /// nor before a client connected.
+    pub(crate) fn idle_for(&self) -> Duration {
+        match *self.lock() {
+            (Some(last), 0) => last.elapsed(),
+            _ => Duration::ZERO,
+        }
     }
 }
 
@@ -43,21 +120,88 @@ where
 
     fn call(&mut self, request: tagged::Request<Req>) -> Self::Future {
         let detagged = request.clone_inner();
-        let future = self.inner.call(detagged);
+
+        // Work done on behalf of a traced request is done within a span
+        // carrying the trace of the client.
+        let trace = request.trace();
+        // When the client is in this process, its span is the parent.
+        let span = match trace {
+            Some(trace) => match trace.sender() {
+                Some(sender) => info_span!(
+                    parent: &sender,
+                    "remote-request",
+                    trace_id = %format_args!("{:016x}", trace.trace_id),
+                    parent_span_id = %format_args!("{:016x}", trace.span_id),
+                ),
+                None => info_span!(
+                    "remote-request",
+                    trace_id = %format_args!("{:016x}", trace.trace_id),
+                    parent_span_id = %format_args!("{:016x}", trace.span_id),
+                ),
+            },
+            None => Span::none(),
+        };
+
+        let future = span.in_scope(|| self.inner.call(detagged));
+        let metrics = self.metrics.clone();
+        let activity = self.activity.clone();
+        let peer = self.peer.clone();
+        if let Some(activity) = &activity {
+            activity.request_started();
+        }
+        let start = Instant::now();
 
         Box::pin(async move {
-            future
-                .await
-                .map(|response| tagged::Response::new(request, response))
+            let serve = async move {
+                match trace {
+                    Some(trace) => {
+                        // Requests sent while serving this one continue the trace.
+                        let trace = tagged::TraceContext {
+                            span_id: span.id().map_or(trace.span_id, |id| id.into_u64()),
+                            ..trace
+                        };
+                        trace.scope(future).instrument(span).await
+                    }
+                    None => future.await,
+                }
+            };
+            let response = match peer {
+                Some(peer) => peer.scope(serve).await,
+                None => serve.await,
+            };
+            if let Some(metrics) = metrics {
+                metrics.request_done(start.elapsed());
+            }
+            if let Some(activity) = activity {
+                activity.request_done();
+            }
+            response.map(|response| tagged::Response::new(request, response))
         })
     }
 }
 
+/// How a session served by [`once`] came to an end.
+#[derive(Debug)]
+pub enum SessionEnd {
+    /// A client connected, and was served until it left.
+    Done,
+    /// No client connected in time.
+    NotConnected,
+    /// A client connected, but serving it failed.
+    Failed(String),
+}
+
+/// How long [`once`] waits for a client to connect and set up the connection,
+/// and how long [`run`] gives each client to set up its connection.
+pub const DEFAULT_ACCEPT_TIMEOUT: Duration = Duration::from_secs(5);
+
 /// Run a multiplexed server for a single connection.
-/// The service will be available on the bind address provided.
+/// The service will be available on the bind address provided,
+/// see [`run`] for the kinds of addresses supported.
 ///
-/// The task will be alive as long as the connection to the bind address is kept alive.
-pub async fn once<S, Req>(bind: &str, service: S) -> Result<(JoinHandle<()>, u16)>
+/// The task will be alive as long as the connection to the bind address is kept alive,
+/// or until [`DEFAULT_ACCEPT_TIMEOUT`] if nobody connects and sets up the connection.
+pub async fn once<S, Req>(bind: &str, service: S) -> Result<(JoinHandle<SessionEnd>, u16)>
 where
     S: Service<Req> + Send + 'static,
     S::Response: Serialize + Send,
@@ -65,43 +209,118 @@ where
     S::Error: Send + Sync + Into<tower::BoxError> + std::fmt::Debug,
     Req: Clone + Send + DeserializeOwned + 'static,
 {
-    let rx = TcpListener::bind(bind).await?;
-    let port = rx.local_addr()?.port();
+    let (connected, _) = oneshot::channel();
+    let (handle, port, _) = once_notify(
+        bind,
+        service,
+        &TransportConfig::default(),
+        DEFAULT_ACCEPT_TIMEOUT,
+        connected,
+        Activity::new(),
+    )
+    .await?;
+    Ok((handle, port))
+}
+
+/// Same as [`once`], but the connection is set up as configured, waits `accept_timeout` for a client,
+/// `connected` is notified as soon as a client has set up the connection,
+/// and traffic on the connection is tracked in `activity`.
+/// Also returns where to connect, if not by port.
+pub(crate) async fn once_notify<S, Req>(
+    bind: &str,
+    service: S,
+    config: &TransportConfig,
+    accept_timeout: Duration,
+    connected: oneshot::Sender<()>,
+    activity: Activity,
+) -> Result<(JoinHandle<SessionEnd>, u16, Option<String>)>
+where
+    S: Service<Req> + Send + 'static,
+    S::Response: Serialize + Send,
+    S::Future: Send + 'static,
+    S::Error: Send + Sync + Into<tower::BoxError> + std::fmt::Debug,
+    Req: Clone + Send + DeserializeOwned + 'static,
+{
+    let mut rx = Listener::bind(bind).await?;
+    let port = rx.port()?;
+    let address = rx.address();
+    let config = config.clone();
 
     let handle = tokio::spawn(async move {
         // This ensure that if the client left, we won't hold onto the
-        // semaphore for more than this amount of seconds.
-        //
-        // TODO: Configurable timeout.
-        // Or let the caller handle timeouts?
-        let timeout_fut = tokio::time::timeout(Duration::from_secs(5), rx.accept());
-
-        let rx = match timeout_fut.await {
-            Ok(Ok((rx, _))) => rx,
+        // semaphore for more than this amount of time.
+        let deadline = Instant::now() + accept_timeout;
+        let timeout_fut = tokio::time::timeout_at(deadline, rx.accept());
+
+        let (rx, peer) = match timeout_fut.await {
+            Ok(Ok(rx)) => rx,
             Ok(Err(e)) => {
                 error!("Problem setting up server: {:?}", e);
-                return;
+                return SessionEnd::NotConnected;
             }
             Err(e) => {
                 error!("Could not accept in time: {:?}", e);
-                return;
+                return SessionEnd::NotConnected;
             }
         };
         info!(%port, "Client connected, setting up server");
+        let metrics = ConnectionMetrics::new("server", &peer);
+        // Nor does a client which connects but stays silent.
+        let set_up =
+            tokio::time::timeout_at(deadline, set_up(&config, Box::new(metrics.count(rx))));
+        let (rx, identity) = match set_up.await {
+            Ok(Ok(rx)) => rx,
+            Ok(Err(e)) => {
+                error!(?e, "Problem setting up connection");
+                return SessionEnd::Failed(format!("{e:?}"));
+            }
+            Err(e) => {
+                error!("Could not set up connection in time: {:?}", e);
+                return SessionEnd::NotConnected;
+            }
+        };
+        activity.connected();
+        // Nobody listening is fine.
+        let _ = connected.send(());
 
-        let rx = AsyncBincodeStream::from(rx).for_async();
-        let server = multiplex::Server::new(rx, Detagger::new(service));
+        let server = multiplex::Server::new(
+            rx,
+            Detagger::with_metrics(service, metrics)
+                .with_activity(activity)
+                .with_peer(identity),
+        );
         match server.await {
-            Ok(_) => debug!("Done serving connection"),
-            Err(e) => error!(?e, "Problem in multiplexed server"),
+            Ok(_) => {
+                debug!("Done serving connection");
+                SessionEnd::Done
+            }
+            Err(e) => {
+                error!(?e, "Problem in multiplexed server");
+                SessionEnd::Failed(format!("{e:?}"))
+            }
         }
     });
 
-    Ok((handle, port))
+    Ok((handle, port, address))
 }
 
-/// Run a TCP listener on the given bind address.
+/// Set up an accepted connection as configured, giving who the client is if it told.
+async fn set_up<In, Out>(
+    config: &TransportConfig,
+    connection: Connection,
+) -> io::Result<(Framed<In, Out>, Option<PeerIdentity>)> {
+    let (connection, identity) = config.accept(connection).await?;
+    Ok((config.frame_accepted(connection).await?, identity))
+}
+
+/// Run a listener on the given bind address.
 /// Connections will be served the given service on a multiplexed transport.
+///
+/// The address is either a TCP address, `unix:PATH` for a Unix domain socket,
+/// or `memory:NAME` for serving clients within the same process without touching the network
+/// (e.g. in tests).
+///
+/// Clients which do not set up their connection within [`DEFAULT_ACCEPT_TIMEOUT`] are hung up on.
 pub async fn run<S, Req>(bind: &str, service: S) -> Result<JoinHandle<()>>
 where
     S: Service<Req> + Send + 'static,
@@ -110,34 +329,69 @@ where
     S::Error: Send + Sync + Into<tower::BoxError> + std::fmt::Debug,
     Req: Clone + Send + DeserializeOwned + 'static,
 {
-    let rx = TcpListener::bind(bind).await?;
+    run_with(bind, service, &TransportConfig::default()).await
+}
+
+/// Same as [`run`], but connections are set up as configured.
+/// Clients must be configured the same way, see [`crate::mux_client::MuxClient::new_with`].
+pub async fn run_with<S, Req>(
+    bind: &str,
+    service: S,
+    config: &TransportConfig,
+) -> Result<JoinHandle<()>>
+where
+    S: Service<Req> + Send + 'static,
+    S::Response: Serialize + Send,
+    S::Future: Send + 'static,
+    S::Error: Send + Sync + Into<tower::BoxError> + std::fmt::Debug,
+    Req: Clone + Send + DeserializeOwned + 'static,
+{
+    let config = config.clone();
+    let mut rx = Listener::bind(bind).await?;
     let service = Buffer::new(service, 32);
 
     let handle = tokio::spawn(async move {
         loop {
             let service_for_iteration = service.clone();
 
-            // TODO: Timeout fut
-            // This ensure that if the client left, we won't hold onto the
-            // semaphore for more than this amount of seconds.
-            // let timeout_fut =
-            //     tokio::time::timeout(Duration::from_secs(5), server);
-
-            let (rx, _) = match rx.accept().await {
+            let (rx, peer) = match rx.accept().await {
                 Ok(rx) => rx,
                 Err(e) => {
-                    error!(?e, "Problem accepting on TCP listener");
+                    error!(?e, "Problem accepting on listener");
                     return;
                 }
             };
-            let rx = AsyncBincodeStream::from(rx).for_async();
+            let metrics = ConnectionMetrics::new("server", &peer);
+            let config = config.clone();
 
-            // TODO: We want to spawn a task for this right?
-            let server = multiplex::Server::new(rx, Detagger::new(service_for_iteration));
-            match server.await {
-                Ok(_) => debug!("Done serving connection"),
-                Err(e) => error!(?e, "Problem in multiplexed server"),
-            }
+            // Each connection gets its own task, such that several clients
+            // may be served at the same time.
+            tokio::spawn(async move {
+                // Clients which connect but stay silent are hung up on.
+                let set_up = tokio::time::timeout(
+                    DEFAULT_ACCEPT_TIMEOUT,
+                    set_up(&config, Box::new(metrics.count(rx))),
+                );
+                let (rx, identity) = match set_up.await {
+                    Ok(Ok(rx)) => rx,
+                    Ok(Err(e)) => {
+                        error!(?e, %peer, "Problem setting up connection");
+                        return;
+                    }
+                    Err(e) => {
+                        error!(?e, %peer, "Could not set up connection in time");
+                        return;
+                    }
+                };
+                let server = multiplex::Server::new(
+                    rx,
+                    Detagger::with_metrics(service_for_iteration, metrics).with_peer(identity),
+                );
+                match server.await {
+                    Ok(_) => debug!("Done serving connection"),
+                    Err(e) => error!(?e, "Problem in multiplexed server"),
+                }
+            });
         }
     });
 
@@ -8,4 +8,27 @@ where
     D: PartialEq,
 {
     fn describe(&self) -> D;
+
+    /// A stable identity of this specific resource, e.g. a serial number.
+    /// Clients may use it to prefer the same resource they had before.
+    ///
+    /// If `None`, the resource's position in the allocator's list of resources is used,
+    /// which is only stable as long as that list is the same.
+    fn id(&self) -> Option<String> {
+        None
+    }
+
+    /// How well a resource described by `description` satisfies `request`, higher is better.
+    /// `None` means it does not satisfy the request at all.
+    ///
+    /// The allocator leases out the best-scoring free resource,
+    /// see [`crate::allocator::MatchPolicy`] for when it waits for a better one.
+    ///
+    /// By default only equal descriptions match, all with the same score.
+    fn score(description: &D, request: &D) -> Option<u32>
+    where
+        Self: Sized,
+    {
+        (description == request).then_some(0)
+    }
 }
@@ -0,0 +1,113 @@
+//! Strategies for choosing among several free resources matching a request.
+
+use std::{
+    fmt::Debug,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+use crate::tagged::random_id;
+
+/// What a selection strategy knows about a free resource.
+#[derive(Debug, Clone)]
+pub struct Candidate<'a> {
+    /// The identity of the resource, see [`crate::resource_filter::Describable::id`].
+    pub id: &'a str,
+    /// The resource's position in the allocator's list of resources.
+    pub index: usize,
+    /// When the resource was last released, or `None` if it has never been leased out.
+    pub last_released: Option<Instant>,
+    /// How long the resource has been leased out in total.
+    pub total_held: Duration,
+    /// How many times the resource has been leased out.
+    pub leases: usize,
+}
+
+/// Chooses which resource to lease out when several matching ones are free.
+pub trait SelectionStrategy: Debug + Send + Sync {
+    /// Choose among the free resources, of which there is at least one.
+    /// Returns the position of the chosen one in `free`.
+    fn select(&self, free: &[Candidate<'_>]) -> usize;
+}
+
+/// Picks the first free resource in the order given to the allocator.
+/// This is the default.
+#[derive(Debug, Default)]
+pub struct FirstFree;
+
+impl SelectionStrategy for FirstFree {
+    fn select(&self, _free: &[Candidate<'_>]) -> usize {
+        0
+    }
+}
+
+/// Picks the resource which has been idle the longest.
+/// Resources never leased out go first.
+#[derive(Debug, Default)]
+pub struct LeastRecentlyUsed;
+
+impl SelectionStrategy for LeastRecentlyUsed {
+    fn select(&self, free: &[Candidate<'_>]) -> usize {
+        position_of_min(free, |candidate| candidate.last_released)
+    }
+}
+
+/// Cycles through the resources in the order given to the allocator.
+#[derive(Debug, Default)]
+pub struct RoundRobin {
+    next: AtomicUsize,
+}
+
+impl SelectionStrategy for RoundRobin {
+    fn select(&self, free: &[Candidate<'_>]) -> usize {
+        let next = self.next.load(Ordering::Relaxed);
+
+        // The first free resource at or after the cursor, else wrap around.
+        let position = free
+            .iter()
+            .enumerate()
+            .filter(|(_, candidate)| candidate.index >= next)
+            .min_by_key(|(_, candidate)| candidate.index)
+            .or_else(|| {
+                free.iter()
+                    .enumerate()
+                    .min_by_key(|(_, candidate)| candidate.index)
+            })
+            .map(|(position, _)| position)
+            .unwrap_or_default();
+
+        if let Some(candidate) = free.get(position) {
+            self.next.store(candidate.index + 1, Ordering::Relaxed);
+        }
+        position
+    }
+}
+
+/// Picks any free resource at random.
+#[derive(Debug, Default)]
+pub struct Random;
+
+impl SelectionStrategy for Random {
+    fn select(&self, free: &[Candidate<'_>]) -> usize {
+        (random_id() % free.len().max(1) as u64) as usize
+    }
+}
+
+/// Picks the resource which has been leased out for the least time in total.
+#[derive(Debug, Default)]
+pub struct LeastTotalUsage;
+
+impl SelectionStrategy for LeastTotalUsage {
+    fn select(&self, free: &[Candidate<'_>]) -> usize {
+        position_of_min(free, |candidate| candidate.total_held)
+    }
+}
+
+/// Position of the candidate with the smallest key, the earliest one on ties.
+fn position_of_min<K: Ord>(free: &[Candidate<'_>], key: impl Fn(&Candidate<'_>) -> K) -> usize {
+    free.iter()
+        .enumerate()
+        .min_by_key(|(position, candidate)| (key(candidate), *position))
+        .map(|(position, _)| position)
+        .unwrap_or_default()
+}
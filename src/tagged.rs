@@ -1,9 +1,131 @@
+use std::{
+    collections::{btree_map::Entry, hash_map::RandomState, BTreeMap},
+    hash::{BuildHasher, Hasher},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, MutexGuard,
+    },
+};
+
+use futures::Future;
 use serde::{Deserialize, Serialize};
+use tracing::Span;
+
+tokio::task_local! {
+    static CURRENT_TRACE: TraceContext;
+}
+
+/// Spans which sent requests still in flight, by the trace context the requests carry,
+/// such that servers in the same process can serve them within child spans.
+/// Several requests may be in flight from the same span, so they are counted.
+static SENDERS: Mutex<BTreeMap<(u64, u64), (Span, usize)>> = Mutex::new(BTreeMap::new());
+
+/// Ties a request to the trace it is part of, such that work done on the server side
+/// shows up in the same trace as the client request which caused it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TraceContext {
+    /// Shared by all requests stemming from the same root request.
+    pub trace_id: u64,
+    /// The span which sent the request.
+    pub span_id: u64,
+}
+
+impl TraceContext {
+    /// The trace context requests sent from here continue:
+    /// that of the remote request currently being served, or of the trace
+    /// being run in with [`TraceContext::scope`], if any.
+    pub fn current() -> Option<Self> {
+        CURRENT_TRACE.try_with(|trace| *trace).ok()
+    }
+
+    /// A new trace, started from the span currently entered.
+    /// Requests sent outside of any trace each start their own,
+    /// run them within the same one with [`TraceContext::scope`] to tie them together.
+    pub fn start() -> Self {
+        let span_id = Span::current().id().map(|id| id.into_u64());
+        Self {
+            trace_id: random_id(),
+            span_id: span_id.unwrap_or_else(random_id),
+        }
+    }
+
+    /// Context for a request sent from within the given span.
+    /// Continues the current trace if there is one, else starts a new trace.
+    pub(crate) fn child_of(span: &Span) -> Self {
+        let trace_id = Self::current()
+            .map(|trace| trace.trace_id)
+            .unwrap_or_else(random_id);
+        let span_id = span.id().map(|id| id.into_u64()).unwrap_or_else(random_id);
+
+        Self { trace_id, span_id }
+    }
+
+    /// Run the future with this as the current trace context,
+    /// such that requests it sends continue the trace.
+    pub async fn scope<F: Future>(self, future: F) -> F::Output {
+        CURRENT_TRACE.scope(self, future).await
+    }
+
+    /// Remember that a request with this context is sent from `span`,
+    /// until the returned guard is dropped.
+    pub(crate) fn sent_from(self, span: &Span) -> Sending {
+        if span.is_disabled() {
+            return Sending(None);
+        }
+        lock(&SENDERS)
+            .entry((self.trace_id, self.span_id))
+            .or_insert_with(|| (span.clone(), 0))
+            .1 += 1;
+        Sending(Some(self))
+    }
+
+    /// The span a request with this context was sent from,
+    /// if it was sent from this process and is still in flight.
+    pub(crate) fn sender(&self) -> Option<Span> {
+        lock(&SENDERS)
+            .get(&(self.trace_id, self.span_id))
+            .map(|(span, _)| span.clone())
+    }
+}
+
+/// Keeps the span a request was sent from known while the request is in flight.
+#[derive(Debug)]
+pub(crate) struct Sending(Option<TraceContext>);
+
+impl Drop for Sending {
+    fn drop(&mut self) {
+        let Some(trace) = self.0 else {
+            return;
+        };
+        if let Entry::Occupied(mut sender) = lock(&SENDERS).entry((trace.trace_id, trace.span_id)) {
+            sender.get_mut().1 -= 1;
+            if sender.get().1 == 0 {
+                sender.remove();
+            }
+        }
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+pub(crate) fn random_id() -> u64 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    hasher.finish()
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Request<T> {
     pub(crate) inner: T,
     tag: usize,
+    trace: Option<TraceContext>,
 }
 
 impl<T> Request<T>
@@ -14,13 +136,25 @@ where
         Self {
             inner: request,
             tag: 0,
+            trace: None,
         }
     }
 
+    /// Let the request carry the given trace context to the server.
+    pub fn with_trace(mut self, trace: TraceContext) -> Self {
+        self.trace = Some(trace);
+        self
+    }
+
     pub fn set_tag(&mut self, tag: usize) {
         self.tag = tag;
     }
 
+    /// Get the trace context the request carries, if any.
+    pub fn trace(&self) -> Option<TraceContext> {
+        self.trace
+    }
+
     /// Extract the inner request.
     pub fn inner(self) -> T {
         self.inner
@@ -0,0 +1,208 @@
+//! TLS for multiplexed connections, enabled by the `tls` cargo feature.
+//!
+//! Certificates and keys are read from PEM files.
+//! Servers present a certificate, and may require clients to present one signed by a given CA
+//! (mutual TLS). Who such a client is shows up as [`PeerIdentity::current`] while its requests
+//! are served.
+//!
+//! Configure with [`crate::transport::TransportConfig::with_tls_server`] and
+//! [`crate::transport::TransportConfig::with_tls_client`].
+
+use std::{io, path::Path, sync::Arc};
+
+use tokio_rustls::{
+    rustls::{
+        crypto::{ring, CryptoProvider},
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName},
+        server::WebPkiClientVerifier,
+        ClientConfig, RootCertStore, ServerConfig,
+    },
+    TlsAcceptor, TlsConnector,
+};
+
+use crate::{
+    error::Result,
+    transport::{self, Connection, PeerIdentity},
+};
+
+/// Server side TLS: the certificate to present, and which clients to trust.
+#[derive(Clone)]
+pub struct TlsServer {
+    certificates: Vec<CertificateDer<'static>>,
+    key: Arc<PrivateKeyDer<'static>>,
+    acceptor: TlsAcceptor,
+}
+
+impl std::fmt::Debug for TlsServer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TlsServer").finish_non_exhaustive()
+    }
+}
+
+impl TlsServer {
+    /// Present the certificate chain in `certificates`, signed with `key`.
+    /// Clients are not asked for a certificate.
+    pub fn from_pem(certificates: impl AsRef<Path>, key: impl AsRef<Path>) -> Result<Self> {
+        let certificates = read_certificates(certificates.as_ref())?;
+        let key = Arc::new(read_key(key.as_ref())?);
+        let config = ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(certificates.clone(), key.clone_key())?;
+        Ok(Self {
+            certificates,
+            key,
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+        })
+    }
+
+    /// Require clients to present a certificate signed by one of the CAs in `ca`.
+    pub fn with_client_ca(self, ca: impl AsRef<Path>) -> Result<Self> {
+        let verifier =
+            WebPkiClientVerifier::builder_with_provider(Arc::new(roots(ca.as_ref())?), provider())
+                .build()?;
+        let config = ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(self.certificates.clone(), self.key.clone_key())?;
+        Ok(Self {
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+            ..self
+        })
+    }
+
+    /// Do the handshake on an accepted connection, giving who the client is if it told.
+    pub(crate) async fn accept(
+        &self,
+        connection: Connection,
+    ) -> io::Result<(Connection, Option<PeerIdentity>)> {
+        let stream = self.acceptor.accept(connection).await?;
+        let peer = stream
+            .get_ref()
+            .1
+            .peer_certificates()
+            .and_then(|certificates| certificates.first())
+            .map(PeerIdentity::from_certificate);
+        Ok((Box::new(stream), peer))
+    }
+}
+
+/// Client side TLS: which servers to trust, and possibly who to be.
+#[derive(Clone)]
+pub struct TlsClient {
+    roots: Arc<RootCertStore>,
+    server_name: Option<ServerName<'static>>,
+    connector: TlsConnector,
+}
+
+impl std::fmt::Debug for TlsClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TlsClient")
+            .field("server_name", &self.server_name)
+            .finish_non_exhaustive()
+    }
+}
+
+impl TlsClient {
+    /// Trust servers presenting a certificate signed by one of the CAs in `ca`.
+    pub fn from_pem(ca: impl AsRef<Path>) -> Result<Self> {
+        let roots = Arc::new(roots(ca.as_ref())?);
+        let config = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots.clone())
+            .with_no_client_auth();
+        Ok(Self {
+            roots,
+            server_name: None,
+            connector: TlsConnector::from(Arc::new(config)),
+        })
+    }
+
+    /// Present the certificate chain in `certificates`, signed with `key`,
+    /// for servers requiring mutual TLS.
+    pub fn with_identity(
+        self,
+        certificates: impl AsRef<Path>,
+        key: impl AsRef<Path>,
+    ) -> Result<Self> {
+        let config = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(self.roots.clone())
+            .with_client_auth_cert(
+                read_certificates(certificates.as_ref())?,
+                read_key(key.as_ref())?,
+            )?;
+        Ok(Self {
+            connector: TlsConnector::from(Arc::new(config)),
+            ..self
+        })
+    }
+
+    /// The name the server certificate must be valid for.
+    /// Defaults to the host connected to, or `localhost` for Unix sockets and in-memory connections.
+    pub fn with_server_name(mut self, name: &str) -> Result<Self> {
+        self.server_name = Some(ServerName::try_from(name.to_string())?);
+        Ok(self)
+    }
+
+    /// Do the handshake on a connection to `addr`.
+    pub(crate) async fn connect(
+        &self,
+        connection: Connection,
+        addr: &str,
+    ) -> io::Result<Connection> {
+        let name = match &self.server_name {
+            Some(name) => name.clone(),
+            None => ServerName::try_from(transport::host(addr).unwrap_or("localhost").to_string())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?,
+        };
+        Ok(Box::new(self.connector.connect(name, connection).await?))
+    }
+}
+
+impl PeerIdentity {
+    fn from_certificate(certificate: &CertificateDer<'_>) -> Self {
+        let common_name = x509_parser::parse_x509_certificate(certificate)
+            .ok()
+            .and_then(|(_, parsed)| {
+                parsed
+                    .subject()
+                    .iter_common_name()
+                    .next()
+                    .and_then(|name| name.as_str().ok())
+                    .map(str::to_string)
+            });
+        Self {
+            common_name,
+            certificate: certificate.to_vec(),
+        }
+    }
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+fn roots(ca: &Path) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for certificate in read_certificates(ca)? {
+        roots.add(certificate)?;
+    }
+    Ok(roots)
+}
+
+fn read_certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certificates = CertificateDer::pem_file_iter(path)
+        .map_err(|e| format!("Could not read certificates from {}: {e}", path.display()))?
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(|e| format!("Could not read certificates from {}: {e}", path.display()))?;
+    if certificates.is_empty() {
+        return Err(format!("No certificates in {}", path.display()).into());
+    }
+    Ok(certificates)
+}
+
+fn read_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path)
+        .map_err(|e| format!("Could not read private key from {}: {e}", path.display()).into())
+}
@@ -0,0 +1,1067 @@
+//! The connections multiplexed transports run over.
+//!
+//! Addresses are TCP addresses such as `0.0.0.0:5566`,
+//! `unix:PATH` for Unix domain sockets,
+//! or `memory:NAME` for channels within the process.
+//!
+//! Unix domain sockets are reached by path, such that filesystem permissions decide who may
+//! connect. Binding a directory creates a socket with a fresh name in it, and sockets are
+//! removed again when their listener stops.
+//!
+//! In-memory connections never touch the network, and work with paused tokio time too.
+//! Binding `memory:0` picks a free numbered name, like binding port zero does for TCP.
+//!
+//! How connections are set up is configured with [`TransportConfig`].
+//! With the `tls` cargo feature, connections may be secured with TLS, see [`crate::tls`].
+
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, VecDeque},
+    fmt::Display,
+    future::Future,
+    io,
+    marker::PhantomData,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use bytes::{Bytes, BytesMut};
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, DuplexStream, ReadHalf, WriteHalf},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    time::{Instant, MissedTickBehavior},
+};
+use tokio_util::{
+    codec::{self, FramedRead, FramedWrite, LengthDelimitedCodec, LengthDelimitedCodecError},
+    sync::PollSender,
+};
+
+use crate::{codec::Codec, compression::Compression, error::Result, tagged::random_id};
+
+const MEMORY: &str = "memory:";
+const UNIX: &str = "unix:";
+
+/// How much may be in flight in each direction of an in-memory connection.
+const MEMORY_BUFFER: usize = 64 * 1024;
+
+tokio::task_local! {
+    static CURRENT_PEER: PeerIdentity;
+}
+
+/// In-memory listeners by name, and how to reach them.
+static MEMORY_LISTENERS: Mutex<BTreeMap<String, mpsc::UnboundedSender<DuplexStream>>> =
+    Mutex::new(BTreeMap::new());
+
+pub(crate) trait Io: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+impl<T> Io for T where T: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+/// A connection on any of the supported transports.
+pub(crate) type Connection = Box<dyn Io>;
+
+/// Who is on the other end of a connection, as told by the certificate it presented
+/// when connecting with mutual TLS.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerIdentity {
+    /// The common name of the certificate's subject, if it has one.
+    pub common_name: Option<String>,
+    /// The certificate, DER encoded.
+    pub certificate: Vec<u8>,
+}
+
+impl PeerIdentity {
+    /// The identity of the client whose request is being served,
+    /// if it presented a certificate.
+    pub fn current() -> Option<Self> {
+        CURRENT_PEER.try_with(Clone::clone).ok()
+    }
+
+    pub(crate) async fn scope<F: Future>(self, future: F) -> F::Output {
+        CURRENT_PEER.scope(self, future).await
+    }
+}
+
+/// Listens for connections on any of the supported transports.
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+    Memory(MemoryListener),
+}
+
+impl Listener {
+    pub(crate) async fn bind(addr: &str) -> Result<Self> {
+        if let Some(name) = addr.strip_prefix(MEMORY) {
+            return Ok(Self::Memory(MemoryListener::bind(name)?));
+        }
+        if let Some(path) = addr.strip_prefix(UNIX) {
+            #[cfg(unix)]
+            return Ok(Self::Unix(UnixListener::bind(path)?));
+            #[cfg(not(unix))]
+            return Err(format!("Unix domain sockets are not supported here: {path}").into());
+        }
+        Ok(Self::Tcp(TcpListener::bind(addr).await?))
+    }
+
+    /// The port listened on.
+    /// In-memory listeners with a numbered name use the number, Unix sockets have none.
+    pub(crate) fn port(&self) -> Result<u16> {
+        match self {
+            Self::Tcp(listener) => Ok(listener.local_addr()?.port()),
+            #[cfg(unix)]
+            Self::Unix(_) => Ok(0),
+            Self::Memory(listener) => Ok(listener.name.parse().unwrap_or_default()),
+        }
+    }
+
+    /// Where to connect, for listeners which are not reached by port.
+    pub(crate) fn address(&self) -> Option<String> {
+        match self {
+            Self::Tcp(_) => None,
+            #[cfg(unix)]
+            Self::Unix(listener) => Some(format!("{UNIX}{}", listener.path.display())),
+            Self::Memory(listener) => Some(format!("{MEMORY}{}", listener.name)),
+        }
+    }
+
+    /// Wait for a connection, giving it along with who connected.
+    pub(crate) async fn accept(&mut self) -> io::Result<(Connection, String)> {
+        match self {
+            Self::Tcp(listener) => {
+                let (stream, peer) = listener.accept().await?;
+                Ok((Box::new(stream), peer.to_string()))
+            }
+            #[cfg(unix)]
+            Self::Unix(listener) => {
+                let (stream, _) = listener.listener.accept().await?;
+                Ok((
+                    Box::new(stream),
+                    format!("{UNIX}{}", listener.path.display()),
+                ))
+            }
+            Self::Memory(listener) => match listener.incoming.recv().await {
+                Some(stream) => Ok((Box::new(stream), format!("{MEMORY}{}", listener.name))),
+                None => Err(io::ErrorKind::BrokenPipe.into()),
+            },
+        }
+    }
+}
+
+/// Connect to the given address.
+pub(crate) async fn connect(addr: &str) -> io::Result<Connection> {
+    if let Some(name) = addr.strip_prefix(MEMORY) {
+        return Ok(Box::new(memory_connect(name)?));
+    }
+    if let Some(path) = addr.strip_prefix(UNIX) {
+        #[cfg(unix)]
+        return Ok(Box::new(tokio::net::UnixStream::connect(path).await?));
+        #[cfg(not(unix))]
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Unix domain sockets are not supported here: {path}"),
+        ));
+    }
+    Ok(Box::new(TcpStream::connect(addr).await?))
+}
+
+/// Which kind of transport an address is for: "tcp", "unix" or "memory".
+pub(crate) fn kind(addr: &str) -> &'static str {
+    if addr.starts_with(MEMORY) {
+        "memory"
+    } else if addr.starts_with(UNIX) {
+        "unix"
+    } else {
+        "tcp"
+    }
+}
+
+/// The address of `port` on the same host as the TCP address `addr`.
+pub(crate) fn on_host_of(addr: &str, port: u16) -> Option<String> {
+    if addr.starts_with(MEMORY) || addr.starts_with(UNIX) {
+        return None;
+    }
+    let (host, _port) = addr.rsplit_once(':')?;
+    Some(format!("{host}:{port}"))
+}
+
+/// The host part of a TCP address.
+#[cfg(feature = "tls")]
+pub(crate) fn host(addr: &str) -> Option<&str> {
+    if addr.starts_with(MEMORY) || addr.starts_with(UNIX) {
+        return None;
+    }
+    let (host, _port) = addr.rsplit_once(':')?;
+    Some(host.trim_start_matches('[').trim_end_matches(']'))
+}
+
+/// Listens on a Unix domain socket, removing it when dropped.
+#[cfg(unix)]
+pub(crate) struct UnixListener {
+    listener: tokio::net::UnixListener,
+    path: std::path::PathBuf,
+}
+
+#[cfg(unix)]
+impl UnixListener {
+    fn bind(path: &str) -> Result<Self> {
+        let mut path = std::path::PathBuf::from(path);
+        if path.is_dir() {
+            path.push(format!("{:016x}.sock", random_id()));
+        }
+        let listener = tokio::net::UnixListener::bind(&path)?;
+        Ok(Self { listener, path })
+    }
+}
+
+#[cfg(unix)]
+impl Drop for UnixListener {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+pub(crate) struct MemoryListener {
+    name: String,
+    incoming: mpsc::UnboundedReceiver<DuplexStream>,
+}
+
+impl MemoryListener {
+    fn bind(name: &str) -> Result<Self> {
+        let mut listeners = lock_listeners();
+
+        let name = if name == "0" {
+            (1..=u16::MAX)
+                .map(|number| number.to_string())
+                .find(|number| !listeners.contains_key(number))
+                .ok_or_else(|| io::Error::from(io::ErrorKind::AddrNotAvailable))?
+        } else if listeners.contains_key(name) {
+            return Err(io::Error::from(io::ErrorKind::AddrInUse).into());
+        } else {
+            name.to_string()
+        };
+
+        let (tx, incoming) = mpsc::unbounded_channel();
+        listeners.insert(name.clone(), tx);
+        Ok(Self { name, incoming })
+    }
+}
+
+impl Drop for MemoryListener {
+    fn drop(&mut self) {
+        self.incoming.close();
+        lock_listeners().retain(|_, listener| !listener.is_closed());
+    }
+}
+
+fn memory_connect(name: &str) -> io::Result<DuplexStream> {
+    let (client, server) = tokio::io::duplex(MEMORY_BUFFER);
+    match lock_listeners().get(name) {
+        Some(listener) if listener.send(server).is_ok() => Ok(client),
+        _ => Err(io::ErrorKind::ConnectionRefused.into()),
+    }
+}
+
+fn lock_listeners(
+) -> std::sync::MutexGuard<'static, BTreeMap<String, mpsc::UnboundedSender<DuplexStream>>> {
+    match MEMORY_LISTENERS.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+/// How connections are set up. Both ends of a connection must agree.
+#[derive(Debug, Clone)]
+pub struct TransportConfig {
+    codec: Codec,
+    // Offered or accepted, preferred first.
+    compression: Vec<Compression>,
+    compression_threshold: usize,
+    max_frame_size: usize,
+    fingerprint: Option<String>,
+    keepalive: Option<Keepalive>,
+    // Whether clients start out with a handshake. Only not for servers from before handshakes.
+    handshake: bool,
+    #[cfg(feature = "tls")]
+    tls_server: Option<crate::tls::TlsServer>,
+    #[cfg(feature = "tls")]
+    tls_client: Option<crate::tls::TlsClient>,
+}
+
+/// Frames smaller than this are not worth compressing, by default.
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 1024;
+
+/// Frames larger than this are refused, by default.
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 8 * 1024 * 1024;
+
+impl Default for TransportConfig {
+    fn default() -> Self {
+        Self {
+            codec: Codec::default(),
+            compression: vec![],
+            compression_threshold: DEFAULT_COMPRESSION_THRESHOLD,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            fingerprint: None,
+            keepalive: None,
+            handshake: true,
+            #[cfg(feature = "tls")]
+            tls_server: None,
+            #[cfg(feature = "tls")]
+            tls_client: None,
+        }
+    }
+}
+
+impl TransportConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How requests and responses are encoded. Defaults to [`Codec::Bincode`].
+    /// Peers encoding differently refuse to talk, see [`HandshakeError::Codec`].
+    pub fn with_codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Compress frames with the given algorithm, if the peer agrees.
+    /// May be given several times, earlier algorithms are preferred.
+    /// Servers use whichever algorithm the client prefers of the ones they are given.
+    /// Peers which do not agree on any get frames uncompressed, see [`crate::compression`].
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        if !self.compression.contains(&compression) {
+            self.compression.push(compression);
+        }
+        self
+    }
+
+    /// Only compress frames of at least this many bytes.
+    /// Defaults to [`DEFAULT_COMPRESSION_THRESHOLD`].
+    pub fn with_compression_threshold(mut self, bytes: usize) -> Self {
+        self.compression_threshold = bytes;
+        self
+    }
+
+    /// Refuse to send or receive frames of more than this many bytes, compressed or not.
+    /// A peer sending one is hung up on, and sending one fails.
+    /// Defaults to [`DEFAULT_MAX_FRAME_SIZE`].
+    pub fn with_max_frame_size(mut self, bytes: usize) -> Self {
+        self.max_frame_size = bytes;
+        self
+    }
+
+    /// Only talk to peers given the same fingerprint, e.g. a version of the request and
+    /// response types, or a hash of their schema.
+    /// Peers which disagree refuse each other when connecting, see [`HandshakeError`].
+    /// By default there is none, and only peers without one are talked to.
+    pub fn with_fingerprint(mut self, fingerprint: impl Into<String>) -> Self {
+        self.fingerprint = Some(fingerprint.into());
+        self
+    }
+
+    /// Ping the peer whenever the connection was quiet for `interval`,
+    /// and hang up on it once `misses` pings in a row went unanswered.
+    /// Catches peers which went away without closing the connection, such as a laptop going
+    /// to sleep. A lease held over such a connection is freed.
+    ///
+    /// Peers answer pings whether or not they send any themselves,
+    /// unless they are from before handshakes. Off by default.
+    pub fn with_keepalive(mut self, interval: Duration, misses: u32) -> Self {
+        self.keepalive = Some(Keepalive { interval, misses });
+        self
+    }
+
+    /// Accepted connections are secured with TLS.
+    #[cfg(feature = "tls")]
+    pub fn with_tls_server(mut self, tls: crate::tls::TlsServer) -> Self {
+        self.tls_server = Some(tls);
+        self
+    }
+
+    /// Outgoing connections are secured with TLS.
+    #[cfg(feature = "tls")]
+    pub fn with_tls_client(mut self, tls: crate::tls::TlsClient) -> Self {
+        self.tls_client = Some(tls);
+        self
+    }
+
+    /// Set up an accepted connection, giving who the client is if it told.
+    pub(crate) async fn accept(
+        &self,
+        connection: Connection,
+    ) -> io::Result<(Connection, Option<PeerIdentity>)> {
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &self.tls_server {
+            return tls.accept(connection).await;
+        }
+        Ok((connection, None))
+    }
+
+    /// Set up a connection made to `addr`.
+    #[cfg_attr(not(feature = "tls"), allow(unused_variables))]
+    pub(crate) async fn connect(
+        &self,
+        connection: Connection,
+        addr: &str,
+    ) -> io::Result<Connection> {
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &self.tls_client {
+            return tls.connect(connection, addr).await;
+        }
+        Ok(connection)
+    }
+
+    /// Frame an accepted connection, sending `Out` and receiving `In`.
+    /// The client says hello first, and is told how the connection is used from here on.
+    pub(crate) async fn frame_accepted<In, Out>(
+        &self,
+        connection: Connection,
+    ) -> io::Result<Framed<In, Out>> {
+        let mut framed = self.frame(connection);
+        let first = framed.frames.next().await.transpose();
+        let Some(first) = first.map_err(|e| framed.too_large(e))? else {
+            return Ok(framed);
+        };
+        let Some((version, hello)) = parse_setup(&first) else {
+            // A client from before handshakes, its first frame is a request.
+            if self.fingerprint.is_some() {
+                return Err(HandshakeError::NoHandshake.into());
+            }
+            framed.unread = Some(first);
+            return Ok(framed);
+        };
+
+        if version != PROTOCOL_VERSION {
+            // Answered all the same, such that the client can tell why it's hung up on.
+            framed.send_setup(&HelloReply::default()).await?;
+            return Err(HandshakeError::Version {
+                ours: PROTOCOL_VERSION,
+                theirs: version,
+            }
+            .into());
+        }
+        let hello: Hello = Codec::Bincode.decode(hello)?;
+        let compression = hello
+            .compression
+            .into_iter()
+            .filter_map(Compression::from_id)
+            .find(|compression| self.compression.contains(compression));
+        framed
+            .send_setup(&HelloReply {
+                codec: self.codec.id(),
+                compression: compression.map(Compression::id),
+                fingerprint: self.fingerprint.clone(),
+                pings: self.keepalive.is_some(),
+            })
+            .await?;
+        self.check_codec(hello.codec)?;
+        if hello.fingerprint != self.fingerprint {
+            return Err(HandshakeError::Fingerprint {
+                ours: self.fingerprint.clone(),
+                theirs: hello.fingerprint,
+            }
+            .into());
+        }
+
+        framed.compression = compression;
+        if hello.pings || self.keepalive.is_some() {
+            framed = framed.kept_alive(self.keepalive);
+        }
+        Ok(framed)
+    }
+
+    /// Frame a connection made to a server, sending `Out` and receiving `In`.
+    /// Says hello, and learns from the answer how the connection is used from here on.
+    ///
+    /// Servers from before handshakes hang up, which gives [`io::ErrorKind::Unsupported`].
+    /// Connecting again without a handshake works with those, see [`Self::without_handshake`].
+    pub(crate) async fn frame_connected<In, Out>(
+        &self,
+        connection: Connection,
+    ) -> io::Result<Framed<In, Out>> {
+        let mut framed = self.frame(connection);
+        if !self.handshake {
+            return Ok(framed);
+        }
+
+        framed
+            .send_setup(&Hello {
+                codec: self.codec.id(),
+                compression: self.compression.iter().map(|c| c.id()).collect(),
+                fingerprint: self.fingerprint.clone(),
+                pings: self.keepalive.is_some(),
+            })
+            .await?;
+        let reply = match framed.frames.next().await {
+            Some(Ok(reply)) => reply,
+            Some(Err(e)) if !hung_up(&e) => return Err(framed.too_large(e)),
+            _ => return Err(io::ErrorKind::Unsupported.into()),
+        };
+        let (version, reply) = parse_setup(&reply)
+            .ok_or_else(|| invalid("the server did not answer the handshake"))?;
+        if version != PROTOCOL_VERSION {
+            return Err(HandshakeError::Version {
+                ours: PROTOCOL_VERSION,
+                theirs: version,
+            }
+            .into());
+        }
+        let reply: HelloReply = Codec::Bincode.decode(reply)?;
+        self.check_codec(reply.codec)?;
+        if reply.fingerprint != self.fingerprint {
+            return Err(HandshakeError::Fingerprint {
+                ours: self.fingerprint.clone(),
+                theirs: reply.fingerprint,
+            }
+            .into());
+        }
+
+        framed.compression = match reply.compression {
+            None => None,
+            Some(id) => Some(
+                Compression::from_id(id)
+                    .filter(|compression| self.compression.contains(compression))
+                    .ok_or_else(|| invalid(format!("the server chose unknown compression {id}")))?,
+            ),
+        };
+        if reply.pings || self.keepalive.is_some() {
+            framed = framed.kept_alive(self.keepalive);
+        }
+        Ok(framed)
+    }
+
+    /// Refuse a peer encoding with another codec, whose frames could not be read.
+    fn check_codec(&self, theirs: u8) -> io::Result<()> {
+        if theirs == self.codec.id() {
+            return Ok(());
+        }
+        Err(HandshakeError::Codec {
+            ours: self.codec,
+            theirs: Codec::from_id(theirs),
+        }
+        .into())
+    }
+
+    /// The same, but skipping the handshake, for servers from before handshakes.
+    /// Not if there is a fingerprint, since those servers can't check it.
+    pub(crate) fn without_handshake(&self) -> Option<Self> {
+        self.fingerprint.is_none().then(|| Self {
+            handshake: false,
+            ..self.clone()
+        })
+    }
+
+    fn frame<In, Out>(&self, connection: Connection) -> Framed<In, Out> {
+        Framed {
+            frames: Frames::Direct(codec::Framed::new(
+                connection,
+                LengthDelimitedCodec::builder()
+                    .max_frame_length(self.max_frame_size)
+                    .new_codec(),
+            )),
+            codec: self.codec,
+            compression: None,
+            compression_threshold: self.compression_threshold,
+            max_frame_size: self.max_frame_size,
+            unread: None,
+            types: PhantomData,
+        }
+    }
+}
+
+/// The version of the protocol spoken by this build, told to peers when connecting.
+/// Peers speaking different versions refuse to talk, see [`HandshakeError::Version`].
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Why two peers would not talk to each other.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HandshakeError {
+    /// The peers speak different versions of the protocol, see [`PROTOCOL_VERSION`].
+    Version { ours: u32, theirs: u32 },
+    /// The peers were given different fingerprints, see [`TransportConfig::with_fingerprint`].
+    Fingerprint {
+        ours: Option<String>,
+        theirs: Option<String>,
+    },
+    /// The peer is from before handshakes, so the fingerprint could not be checked.
+    NoHandshake,
+    /// The peers encode with different codecs, see [`TransportConfig::with_codec`].
+    /// Theirs is unknown if this end is built without it.
+    Codec { ours: Codec, theirs: Option<Codec> },
+}
+
+impl Display for HandshakeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Version { ours, theirs } => write!(
+                f,
+                "peer speaks protocol version {theirs}, but this end speaks version {ours}"
+            ),
+            Self::Fingerprint { ours, theirs } => write!(
+                f,
+                "peer has fingerprint {}, but this end has {}",
+                describe(theirs),
+                describe(ours)
+            ),
+            Self::NoHandshake => write!(
+                f,
+                "peer does not do handshakes, so its fingerprint could not be checked"
+            ),
+            Self::Codec {
+                ours,
+                theirs: Some(theirs),
+            } => write!(
+                f,
+                "peer encodes with {theirs:?}, but this end with {ours:?}"
+            ),
+            Self::Codec { ours, theirs: None } => write!(
+                f,
+                "peer encodes with a codec this end is built without, this end with {ours:?}"
+            ),
+        }
+    }
+}
+
+fn describe(fingerprint: &Option<String>) -> String {
+    match fingerprint {
+        Some(fingerprint) => format!("{fingerprint:?}"),
+        None => "none".to_string(),
+    }
+}
+
+impl std::error::Error for HandshakeError {}
+
+impl From<HandshakeError> for io::Error {
+    fn from(e: HandshakeError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+/// Starts the frames exchanged in the handshake, followed by the protocol version.
+/// Tells them apart from requests, which clients from before handshakes start out with.
+const HELLO: &[u8] = b"\0leaning-tower-hello\0";
+
+/// The protocol version and the rest of a handshake frame, if it is one.
+fn parse_setup(frame: &[u8]) -> Option<(u32, &[u8])> {
+    let (version, rest) = frame.strip_prefix(HELLO)?.split_first_chunk()?;
+    Some((u32::from_be_bytes(*version), rest))
+}
+
+/// What a client asks for when setting up a connection.
+#[derive(Debug, Serialize, Deserialize)]
+struct Hello {
+    /// The codec it encodes with, see [`Codec::id`].
+    codec: u8,
+    /// The compression algorithms it offers, preferred first, see [`Compression::id`].
+    compression: Vec<u8>,
+    fingerprint: Option<String>,
+    /// Whether it pings, such that the server keeps answering even while it is idle.
+    pings: bool,
+}
+
+/// What the server settled on.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct HelloReply {
+    codec: u8,
+    compression: Option<u8>,
+    fingerprint: Option<String>,
+    pings: bool,
+}
+
+fn hung_up(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::UnexpectedEof | io::ErrorKind::ConnectionReset | io::ErrorKind::BrokenPipe
+    )
+}
+
+fn invalid(e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// Length prefixed frames, each holding one encoded item.
+///
+/// On compressed connections each frame starts with a byte telling whether the rest is compressed.
+pub(crate) struct Framed<In, Out> {
+    frames: Frames,
+    codec: Codec,
+    compression: Option<Compression>,
+    compression_threshold: usize,
+    max_frame_size: usize,
+    // Read while setting up the connection, but not part of it.
+    unread: Option<BytesMut>,
+    types: PhantomData<fn(Out) -> In>,
+}
+
+const UNCOMPRESSED: u8 = 0;
+const COMPRESSED: u8 = 1;
+
+impl<In, Out> Framed<In, Out> {
+    async fn send_setup(&mut self, message: &impl Serialize) -> io::Result<()> {
+        let message = Codec::Bincode.encode(message)?;
+        let version = PROTOCOL_VERSION.to_be_bytes();
+        self.frames
+            .send(Bytes::from([HELLO, &version, &message].concat()))
+            .await
+    }
+
+    /// Hand the connection to a task which answers pings, and sends them if given a keepalive.
+    fn kept_alive(mut self, keepalive: Option<Keepalive>) -> Self {
+        if let Frames::Direct(frames) = self.frames {
+            self.frames = Frames::KeptAlive(KeptAlive::spawn(frames, keepalive));
+        }
+        self
+    }
+
+    fn decompress<'a>(&self, frame: &'a [u8]) -> io::Result<Cow<'a, [u8]>> {
+        let Some(compression) = self.compression else {
+            return Ok(Cow::Borrowed(frame));
+        };
+        match frame.split_first() {
+            Some((&UNCOMPRESSED, rest)) => Ok(Cow::Borrowed(rest)),
+            Some((&COMPRESSED, rest)) => Ok(Cow::Owned(
+                compression.decompress(rest, self.max_frame_size)?,
+            )),
+            _ => Err(invalid("frame is neither compressed nor uncompressed")),
+        }
+    }
+
+    /// Tell which limit was hit, if it was a frame which was too large.
+    fn too_large(&self, e: io::Error) -> io::Error {
+        if e.get_ref()
+            .is_some_and(|inner| inner.is::<LengthDelimitedCodecError>())
+        {
+            return invalid(format!(
+                "peer sent a frame larger than the maximum of {} bytes",
+                self.max_frame_size
+            ));
+        }
+        e
+    }
+
+    fn compress(&self, frame: Vec<u8>) -> io::Result<Vec<u8>> {
+        let Some(compression) = self.compression else {
+            return Ok(frame);
+        };
+        if frame.len() < self.compression_threshold {
+            return Ok([&[UNCOMPRESSED], frame.as_slice()].concat());
+        }
+        Ok([&[COMPRESSED], compression.compress(&frame)?.as_slice()].concat())
+    }
+}
+
+impl<In, Out> Stream for Framed<In, Out>
+where
+    In: DeserializeOwned,
+{
+    type Item = io::Result<In>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let frame = match self.unread.take() {
+            Some(frame) => Some(Ok(frame)),
+            None => ready!(self.frames.poll_next_unpin(cx)),
+        };
+        Poll::Ready(frame.map(|frame| {
+            let frame = frame.map_err(|e| self.too_large(e))?;
+            self.codec.decode(&self.decompress(&frame)?)
+        }))
+    }
+}
+
+impl<In, Out> Sink<Out> for Framed<In, Out>
+where
+    Out: Serialize,
+{
+    type Error = io::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        SinkExt::<Bytes>::poll_ready_unpin(&mut self.frames, cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Out) -> io::Result<()> {
+        let frame = self.compress(self.codec.encode(&item)?)?;
+        if frame.len() > self.max_frame_size {
+            return Err(invalid(format!(
+                "frame of {} bytes is larger than the maximum of {}",
+                frame.len(),
+                self.max_frame_size
+            )));
+        }
+        self.frames.start_send_unpin(Bytes::from(frame))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        SinkExt::<Bytes>::poll_flush_unpin(&mut self.frames, cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        SinkExt::<Bytes>::poll_close_unpin(&mut self.frames, cx)
+    }
+}
+
+/// See [`TransportConfig::with_keepalive`].
+#[derive(Debug, Clone, Copy)]
+struct Keepalive {
+    interval: Duration,
+    misses: u32,
+}
+
+/// Sent to check the peer is still there, and answered with [`PONG`] right away.
+/// Tells them apart from other frames, like [`HELLO`] does.
+const PING: &[u8] = b"\0leaning-tower-ping\0";
+const PONG: &[u8] = b"\0leaning-tower-pong\0";
+
+/// How many frames may wait to be read or written on a kept alive connection.
+const KEPT_ALIVE_BUFFER: usize = 16;
+
+/// How many more frames are read ahead on a kept alive connection while the multiplexer
+/// is not ready for them, such that pings behind them are still answered.
+/// A peer further ahead than that is pushed back on, and its pings wait their turn.
+const READ_AHEAD: usize = 1024;
+
+/// How many bytes of frames are read ahead at most, as a multiple of the maximum frame size,
+/// see [`TransportConfig::with_max_frame_size`]. Large frames hit this before [`READ_AHEAD`].
+const READ_AHEAD_FRAME_SIZES: usize = 2;
+
+/// Frames on a connection, read and written either right there or through a task.
+enum Frames {
+    Direct(codec::Framed<Connection, LengthDelimitedCodec>),
+    KeptAlive(KeptAlive),
+}
+
+impl Stream for Frames {
+    type Item = io::Result<BytesMut>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match &mut *self {
+            Self::Direct(frames) => frames.poll_next_unpin(cx),
+            Self::KeptAlive(frames) => frames.incoming.poll_recv(cx),
+        }
+    }
+}
+
+impl Sink<Bytes> for Frames {
+    type Error = io::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut *self {
+            Self::Direct(frames) => SinkExt::<Bytes>::poll_ready_unpin(frames, cx),
+            Self::KeptAlive(frames) => {
+                let ready = ready!(frames.outgoing.poll_reserve(cx));
+                Poll::Ready(ready.map_err(|_| frames.gone()))
+            }
+        }
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, frame: Bytes) -> io::Result<()> {
+        match &mut *self {
+            Self::Direct(frames) => frames.start_send_unpin(frame),
+            Self::KeptAlive(frames) => {
+                let sent = frames.outgoing.send_item(frame);
+                sent.map_err(|_| frames.gone())
+            }
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut *self {
+            Self::Direct(frames) => SinkExt::<Bytes>::poll_flush_unpin(frames, cx),
+            // The task writes frames out as soon as it gets them.
+            Self::KeptAlive(_) => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut *self {
+            Self::Direct(frames) => SinkExt::<Bytes>::poll_close_unpin(frames, cx),
+            Self::KeptAlive(frames) => {
+                frames.outgoing.close();
+                Poll::Ready(Ok(()))
+            }
+        }
+    }
+}
+
+/// A connection read and written by a task, which keeps at it while the multiplexer is idle.
+/// Idle clients would otherwise not answer pings.
+struct KeptAlive {
+    incoming: mpsc::Receiver<io::Result<BytesMut>>,
+    outgoing: PollSender<Bytes>,
+}
+
+impl KeptAlive {
+    fn spawn(
+        frames: codec::Framed<Connection, LengthDelimitedCodec>,
+        keepalive: Option<Keepalive>,
+    ) -> Self {
+        let (incoming_tx, incoming) = mpsc::channel(KEPT_ALIVE_BUFFER);
+        let (outgoing, outgoing_rx) = mpsc::channel(KEPT_ALIVE_BUFFER);
+        tokio::spawn(keep_alive(frames, incoming_tx, outgoing_rx, keepalive));
+        Self {
+            incoming,
+            outgoing: PollSender::new(outgoing),
+        }
+    }
+
+    /// Why the task stopped, if it told.
+    fn gone(&mut self) -> io::Error {
+        match self.incoming.try_recv() {
+            Ok(Err(e)) => e,
+            _ => io::Error::new(io::ErrorKind::BrokenPipe, "connection closed"),
+        }
+    }
+}
+
+async fn keep_alive(
+    frames: codec::Framed<Connection, LengthDelimitedCodec>,
+    incoming: mpsc::Sender<io::Result<BytesMut>>,
+    outgoing: mpsc::Receiver<Bytes>,
+    keepalive: Option<Keepalive>,
+) {
+    let parts = frames.into_parts();
+    let read_ahead = parts
+        .codec
+        .max_frame_length()
+        .saturating_mul(READ_AHEAD_FRAME_SIZES);
+    let (reader, writer) = tokio::io::split(parts.io);
+    let mut reader = FramedRead::new(reader, parts.codec.clone());
+    *reader.read_buffer_mut() = parts.read_buf;
+    let writer = FramedWrite::new(writer, parts.codec);
+
+    let (control, control_rx) = mpsc::channel(2);
+    let heard = AtomicBool::new(false);
+    let mut reading = Box::pin(read_frames(
+        reader,
+        read_ahead,
+        incoming.clone(),
+        control.clone(),
+        &heard,
+    ));
+    let mut writing = Box::pin(write_frames(writer, outgoing, control_rx));
+    let mut pinging = Box::pin(ping(keepalive, control, &heard));
+
+    let (end, written) = tokio::select! {
+        read = &mut reading => (read, false),
+        written = &mut writing => (written, true),
+        e = &mut pinging => (Err(e), false),
+    };
+    drop((reading, pinging));
+    match end {
+        // The peer is done sending, but may still wait for answers.
+        Ok(()) if !written => {
+            drop(incoming);
+            let _ = writing.await;
+        }
+        Ok(()) => {}
+        Err(e) => {
+            drop(writing);
+            let _ = incoming.send(Err(e)).await;
+        }
+    }
+}
+
+async fn read_frames(
+    mut frames: FramedRead<ReadHalf<Connection>, LengthDelimitedCodec>,
+    read_ahead: usize,
+    incoming: mpsc::Sender<io::Result<BytesMut>>,
+    control: mpsc::Sender<&'static [u8]>,
+    heard: &AtomicBool,
+) -> io::Result<()> {
+    // Frames waiting for the multiplexer, which is not necessarily ready for them,
+    // and how many bytes they take.
+    let mut ahead: VecDeque<BytesMut> = VecDeque::new();
+    let mut ahead_bytes = 0;
+    let end = loop {
+        tokio::select! {
+            permit = incoming.reserve(), if !ahead.is_empty() => {
+                let (Ok(permit), Some(frame)) = (permit, ahead.pop_front()) else {
+                    // Nobody wants them anymore.
+                    return Ok(());
+                };
+                ahead_bytes -= frame.len();
+                permit.send(Ok(frame));
+            }
+            frame = frames.next(), if ahead.len() < READ_AHEAD && ahead_bytes < read_ahead => {
+                let frame = match frame {
+                    Some(Ok(frame)) => frame,
+                    Some(Err(e)) => break Err(e),
+                    None => break Ok(()),
+                };
+                heard.store(true, Ordering::Relaxed);
+                match &frame[..] {
+                    // Should answers pile up, one is as good as many.
+                    PING => {
+                        let _ = control.try_send(PONG);
+                    }
+                    PONG => {}
+                    _ => {
+                        ahead_bytes += frame.len();
+                        ahead.push_back(frame);
+                    }
+                }
+            }
+        }
+    };
+
+    // What was read before the end is passed on all the same.
+    for frame in ahead {
+        if incoming.send(Ok(frame)).await.is_err() {
+            break;
+        }
+    }
+    end
+}
+
+async fn write_frames(
+    mut frames: FramedWrite<WriteHalf<Connection>, LengthDelimitedCodec>,
+    mut outgoing: mpsc::Receiver<Bytes>,
+    mut control: mpsc::Receiver<&'static [u8]>,
+) -> io::Result<()> {
+    loop {
+        let frame = tokio::select! {
+            frame = outgoing.recv() => match frame {
+                Some(frame) => frame,
+                None => return SinkExt::<Bytes>::close(&mut frames).await,
+            },
+            Some(frame) = control.recv() => Bytes::from_static(frame),
+        };
+        frames.send(frame).await?;
+    }
+}
+
+/// Pings the peer whenever nothing was heard from it for an interval,
+/// until it missed too many pings in a row.
+async fn ping(
+    keepalive: Option<Keepalive>,
+    control: mpsc::Sender<&'static [u8]>,
+    heard: &AtomicBool,
+) -> io::Error {
+    let Some(Keepalive { interval, misses }) = keepalive else {
+        return std::future::pending().await;
+    };
+    let mut ticks = tokio::time::interval_at(Instant::now() + interval, interval);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut unanswered = 0;
+    loop {
+        ticks.tick().await;
+        if heard.swap(false, Ordering::Relaxed) {
+            unanswered = 0;
+            continue;
+        }
+        if unanswered >= misses {
+            return io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("peer did not answer {misses} pings in a row, giving up on it"),
+            );
+        }
+        unanswered += 1;
+        let _ = control.try_send(PING);
+    }
+}
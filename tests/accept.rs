@@ -0,0 +1,223 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::Future;
+use leaning_tower::{
+    allocator::{
+        Allocation, AllocationOptions, AllocatorError, AllocatorReply, AllocatorRequest,
+        AllocatorResponse, AllocatorService, CloseReason, Grant,
+    },
+    allocator_client::AllocatorClientService,
+    mux_client::MuxClient,
+    mux_server,
+    resource_filter::Describable,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tower::{BoxError, Service, ServiceExt};
+
+const SERVER_ADDR: &str = "0.0.0.0:5588";
+
+struct Printer;
+
+impl Service<()> for Printer {
+    type Response = ();
+    type Error = BoxError;
+    #[allow(clippy::type_complexity)]
+    type Future =
+        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + 'static + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Ok(()).into()
+    }
+
+    fn call(&mut self, _req: ()) -> Self::Future {
+        Box::pin(async move { Ok(()) })
+    }
+}
+
+impl Describable<usize> for Printer {
+    fn describe(&self) -> usize {
+        0
+    }
+}
+
+type Raw = MuxClient<AllocatorRequest<usize>, AllocatorResponse<usize>>;
+
+/// Allocate without connecting to the resource, like a client that got stuck.
+async fn allocate_raw(allocator: &mut Raw, accept_timeout: Option<Duration>) -> Grant {
+    let request = AllocatorRequest::Allocate(Allocation {
+        id: 1,
+        description: 0usize,
+        client: None,
+        options: AllocationOptions {
+            accept_timeout,
+            ..Default::default()
+        },
+    });
+    match allocator
+        .ready()
+        .await
+        .unwrap()
+        .call(request)
+        .await
+        .unwrap()
+    {
+        Ok(AllocatorReply::Granted(grant)) => grant,
+        reply => panic!("Expected a grant, got {reply:?}"),
+    }
+}
+
+/// Passes connections on to `to`, delaying what comes back on them by `delay`.
+/// Returns where to connect.
+async fn slow_proxy(to: &'static str, delay: Duration) -> String {
+    let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let at = proxy.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        loop {
+            let (client, _) = proxy.accept().await.unwrap();
+            let server = TcpStream::connect(to).await.unwrap();
+            let (mut client_read, mut client_write) = client.into_split();
+            let (mut server_read, mut server_write) = server.into_split();
+            tokio::spawn(async move {
+                let _ = tokio::io::copy(&mut client_read, &mut server_write).await;
+            });
+            tokio::spawn(async move {
+                let mut buf = vec![0; 64 * 1024];
+                loop {
+                    let read = match server_read.read(&mut buf).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(read) => read,
+                    };
+                    tokio::time::sleep(delay).await;
+                    if client_write.write_all(&buf[..read]).await.is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+    });
+    at
+}
+
+async fn why_closed(allocator: &mut Raw, lease: u64) -> Option<CloseReason> {
+    let request = AllocatorRequest::WhyClosed(lease);
+    match allocator
+        .ready()
+        .await
+        .unwrap()
+        .call(request)
+        .await
+        .unwrap()
+    {
+        Ok(AllocatorReply::Closed(reason)) => reason,
+        reply => panic!("Expected a close reason, got {reply:?}"),
+    }
+}
+
+#[tokio::test]
+async fn test_accept_timeout() {
+    let accept_timeout = Duration::from_millis(100);
+    let service = AllocatorService::new(vec![Printer]).with_accept_timeout(accept_timeout);
+    let _handle = mux_server::run(SERVER_ADDR, service).await.unwrap();
+
+    let mut raw = Raw::new(SERVER_ADDR).await.unwrap();
+    let allocator: AllocatorClientService<_, Printer, ()> =
+        AllocatorClientService::new(SERVER_ADDR).await.unwrap();
+
+    // Missing the window frees the resource, and the allocator tells why.
+    let grant = allocate_raw(&mut raw, None).await;
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert_eq!(allocator.descriptions().await.unwrap()[0].free, 1);
+    assert_eq!(
+        why_closed(&mut raw, grant.lease).await,
+        Some(CloseReason::NotConnected(accept_timeout))
+    );
+
+    // A request may ask for a different window.
+    let grant = allocate_raw(&mut raw, Some(Duration::from_millis(50))).await;
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(
+        why_closed(&mut raw, grant.lease).await,
+        Some(CloseReason::NotConnected(Duration::from_millis(50)))
+    );
+
+    // Clients which connect right away are unaffected.
+    let options = AllocationOptions {
+        accept_timeout: Some(Duration::from_secs(5)),
+        ..Default::default()
+    };
+    let mut lease = allocator.allocate_with(0, options).await.unwrap().unwrap();
+    lease.ready().await.unwrap().call(()).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_connecting_too_late() {
+    const ALLOCATOR_ADDR: &str = "127.0.0.1:5577";
+
+    let accept_timeout = Duration::from_millis(50);
+    let service = AllocatorService::new(vec![Printer]).with_accept_timeout(accept_timeout);
+    let _handle = mux_server::run(ALLOCATOR_ADDR, service).await.unwrap();
+
+    // The grant arrives after the window to connect has passed.
+    let proxy = slow_proxy(ALLOCATOR_ADDR, Duration::from_millis(300)).await;
+    let mut allocator: AllocatorClientService<_, Printer, ()> =
+        AllocatorClientService::new(&proxy).await.unwrap();
+
+    let e = allocator
+        .ready()
+        .await
+        .unwrap()
+        .call(0usize)
+        .await
+        .unwrap_err();
+    match e.downcast_ref::<AllocatorError>() {
+        Some(AllocatorError::LeaseClosed(reason)) => {
+            assert_eq!(*reason, CloseReason::NotConnected(accept_timeout))
+        }
+        _ => panic!("Expected the lease to be closed, got {e:?}"),
+    }
+}
+
+#[tokio::test]
+async fn test_silent_client() {
+    const ALLOCATOR_ADDR: &str = "127.0.0.1:5601";
+
+    let accept_timeout = Duration::from_millis(100);
+    let service = AllocatorService::new(vec![Printer]).with_accept_timeout(accept_timeout);
+    let _handle = mux_server::run(ALLOCATOR_ADDR, service).await.unwrap();
+
+    let mut raw = Raw::new(ALLOCATOR_ADDR).await.unwrap();
+    let allocator: AllocatorClientService<usize, Printer, ()> =
+        AllocatorClientService::new(ALLOCATOR_ADDR).await.unwrap();
+
+    // Connecting without ever saying hello does not hold on to the resource.
+    let grant = allocate_raw(&mut raw, None).await;
+    let _silent = TcpStream::connect(("127.0.0.1", grant.port)).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert_eq!(allocator.descriptions().await.unwrap()[0].free, 1);
+    assert_eq!(
+        why_closed(&mut raw, grant.lease).await,
+        Some(CloseReason::NotConnected(accept_timeout))
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_silent_client_hung_up_on() {
+    const SERVER_ADDR: &str = "127.0.0.1:5602";
+
+    let _handle = mux_server::run(SERVER_ADDR, Printer).await.unwrap();
+
+    let mut silent = TcpStream::connect(SERVER_ADDR).await.unwrap();
+    let start = tokio::time::Instant::now();
+    let read = tokio::time::timeout(Duration::from_secs(60), silent.read(&mut [0; 64]))
+        .await
+        .unwrap();
+    assert!(matches!(read, Ok(0) | Err(_)));
+    assert!(start.elapsed() >= mux_server::DEFAULT_ACCEPT_TIMEOUT);
+}
@@ -0,0 +1,167 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::Future;
+use leaning_tower::{
+    allocator::{Affinity, AllocationOptions, AllocatorService},
+    allocator_client::AllocatorClientService,
+    mux_server,
+    resource_filter::Describable,
+};
+use tower::{BoxError, Service, ServiceExt};
+
+const SERVER_ADDR: &str = "0.0.0.0:5575";
+
+/// A device with a serial number.
+struct Device(&'static str);
+
+impl Service<()> for Device {
+    type Response = ();
+    type Error = BoxError;
+    #[allow(clippy::type_complexity)]
+    type Future =
+        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + 'static + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Ok(()).into()
+    }
+
+    fn call(&mut self, _req: ()) -> Self::Future {
+        Box::pin(async move { Ok(()) })
+    }
+}
+
+impl Describable<usize> for Device {
+    fn describe(&self) -> usize {
+        0
+    }
+
+    fn id(&self) -> Option<String> {
+        Some(self.0.to_string())
+    }
+}
+
+fn prefer(resource: &str, fallback_after: Duration) -> AllocationOptions {
+    AllocationOptions {
+        affinity: Some(Affinity {
+            resource: resource.to_string(),
+            fallback_after,
+        }),
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn test_affinity() {
+    let service = AllocatorService::new(vec![Device("dev-a"), Device("dev-b"), Device("dev-c")])
+        .with_affinity_memory(Duration::from_secs(1));
+    let _handle = mux_server::run(SERVER_ADDR, service).await.unwrap();
+
+    let mut allocator: AllocatorClientService<_, Device, _> =
+        AllocatorClientService::new_labelled(SERVER_ADDR, "flasher")
+            .await
+            .unwrap();
+
+    // Explicitly ask for a specific device.
+    let lease = allocator
+        .allocate_with(0usize, prefer("dev-c", Duration::from_secs(1)))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(lease.resource(), Some("dev-c"));
+
+    // While it's busy, we settle for another one after a while.
+    let other = allocator
+        .allocate_with(0usize, prefer("dev-c", Duration::from_millis(100)))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_ne!(other.resource(), Some("dev-c"));
+    drop(other);
+    drop(lease);
+
+    // The allocator remembers what we had, and gives it to us again.
+    // The last one we had was not "dev-c" though.
+    let lease = allocator
+        .ready()
+        .await
+        .unwrap()
+        .call(0usize)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(lease.resource(), Some("dev-a"));
+    drop(lease);
+
+    let lease = allocator
+        .allocate_with(0usize, prefer("dev-b", Duration::from_secs(1)))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(lease.resource(), Some("dev-b"));
+    drop(lease);
+
+    for _ in 0..3 {
+        let lease = allocator
+            .ready()
+            .await
+            .unwrap()
+            .call(0usize)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(lease.resource(), Some("dev-b"));
+    }
+}
+
+#[tokio::test]
+async fn test_affinity_memory_is_bounded() {
+    const ALLOCATOR_ADDR: &str = "memory:affinity-bounded";
+
+    let service = AllocatorService::new(vec![Device("dev-a"), Device("dev-b"), Device("dev-c")])
+        .with_lease_bind("memory:0")
+        .with_affinity_memory(Duration::from_secs(1))
+        .with_clients_remembered(1);
+    let _handle = mux_server::run(ALLOCATOR_ADDR, service).await.unwrap();
+
+    let mut first: AllocatorClientService<_, Device, _> =
+        AllocatorClientService::new_labelled(ALLOCATOR_ADDR, "first")
+            .await
+            .unwrap();
+    let mut second: AllocatorClientService<_, Device, _> =
+        AllocatorClientService::new_labelled(ALLOCATOR_ADDR, "second")
+            .await
+            .unwrap();
+
+    let lease = first
+        .allocate_with(0usize, prefer("dev-c", Duration::from_secs(1)))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(lease.resource(), Some("dev-c"));
+    drop(lease);
+
+    // Remembering the second client makes the allocator forget the first.
+    let lease = second
+        .ready()
+        .await
+        .unwrap()
+        .call(0usize)
+        .await
+        .unwrap()
+        .unwrap();
+    drop(lease);
+
+    let lease = first
+        .ready()
+        .await
+        .unwrap()
+        .call(0usize)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_ne!(lease.resource(), Some("dev-c"));
+}
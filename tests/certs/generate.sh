@@ -0,0 +1,34 @@
+#!/bin/sh
+# Regenerates the certificates used by tests/tls.rs.
+# A CA, a server certificate for localhost, a client certificate signed by the CA,
+# and a self-signed client certificate the server does not trust.
+set -e
+cd "$(dirname "$0")"
+
+days=36500
+key() { openssl genpkey -algorithm EC -pkeyopt ec_paramgen_curve:P-256 -out "$1" 2>/dev/null; }
+
+key ca.key
+openssl req -x509 -new -key ca.key -days $days -subj "/CN=leaning-tower test CA" \
+    -addext "basicConstraints=critical,CA:TRUE" -addext "keyUsage=critical,keyCertSign" \
+    -out ca.pem
+
+issue() {
+    name=$1 cn=$2 ext=$3
+    key "$name.key"
+    openssl req -new -key "$name.key" -subj "/CN=$cn" -out "$name.csr"
+    printf '%s\n' "basicConstraints=critical,CA:FALSE" "$ext" > "$name.ext"
+    openssl x509 -req -in "$name.csr" -CA ca.pem -CAkey ca.key -CAcreateserial \
+        -days $days -extfile "$name.ext" -out "$name.pem" 2>/dev/null
+    rm "$name.csr" "$name.ext"
+}
+
+issue server localhost "subjectAltName=DNS:localhost,IP:127.0.0.1
+extendedKeyUsage=serverAuth"
+issue client build-bot "extendedKeyUsage=clientAuth"
+
+key intruder.key
+openssl req -x509 -new -key intruder.key -days $days -subj "/CN=intruder" \
+    -addext "extendedKeyUsage=clientAuth" -out intruder.pem
+
+rm -f ca.srl
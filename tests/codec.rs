@@ -0,0 +1,108 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures::Future;
+use leaning_tower::{
+    allocator::AllocatorService, allocator_client::AllocatorClientService, codec::Codec,
+    mux_server, resource_filter::Describable, transport::TransportConfig,
+};
+use tower::{BoxError, Service, ServiceExt};
+
+struct Uppercase;
+
+impl Service<String> for Uppercase {
+    type Response = String;
+    type Error = BoxError;
+    #[allow(clippy::type_complexity)]
+    type Future =
+        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + 'static + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Ok(()).into()
+    }
+
+    fn call(&mut self, req: String) -> Self::Future {
+        Box::pin(async move { Ok(req.to_ascii_uppercase()) })
+    }
+}
+
+impl Describable<usize> for Uppercase {
+    fn describe(&self) -> usize {
+        0
+    }
+}
+
+/// Allocate and use a resource, with everything encoded by the given codec.
+async fn round_trip(addr: &str, codec: Codec) {
+    let config = TransportConfig::new().with_codec(codec);
+    let service = AllocatorService::new(vec![Uppercase])
+        .with_lease_bind("memory:0")
+        .with_lease_transport(config.clone());
+    let _handle = mux_server::run_with(addr, service, &config).await.unwrap();
+
+    let mut allocator: AllocatorClientService<_, Uppercase, String> =
+        AllocatorClientService::new_with(addr, config)
+            .await
+            .unwrap();
+    let mut lease = allocator
+        .ready()
+        .await
+        .unwrap()
+        .call(0usize)
+        .await
+        .unwrap()
+        .unwrap();
+    let response = lease
+        .ready()
+        .await
+        .unwrap()
+        .call("hello".to_string())
+        .await
+        .unwrap();
+    assert_eq!(response, "HELLO");
+}
+
+#[tokio::test]
+async fn test_bincode() {
+    round_trip("memory:codec-bincode", Codec::Bincode).await;
+}
+
+#[cfg(feature = "json")]
+#[tokio::test]
+async fn test_json() {
+    round_trip("memory:codec-json", Codec::Json).await;
+}
+
+#[cfg(feature = "msgpack")]
+#[tokio::test]
+async fn test_msgpack() {
+    round_trip("memory:codec-msgpack", Codec::MessagePack).await;
+}
+
+#[cfg(feature = "postcard")]
+#[tokio::test]
+async fn test_postcard() {
+    round_trip("memory:codec-postcard", Codec::Postcard).await;
+}
+
+#[cfg(feature = "json")]
+#[tokio::test]
+async fn test_mismatch() {
+    use leaning_tower::{mux_client::MuxClient, transport::HandshakeError};
+
+    const SERVER_ADDR: &str = "memory:codec-mismatch";
+    let _handle = mux_server::run(SERVER_ADDR, Uppercase).await.unwrap();
+
+    let config = TransportConfig::new().with_codec(Codec::Json);
+    let result: Result<MuxClient<String, String>, _> =
+        MuxClient::new_with(SERVER_ADDR, &config).await;
+    assert_eq!(
+        *result.unwrap_err().downcast::<HandshakeError>().unwrap(),
+        HandshakeError::Codec {
+            ours: Codec::Json,
+            theirs: Some(Codec::Bincode),
+        }
+    );
+}
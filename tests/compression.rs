@@ -0,0 +1,170 @@
+#![cfg(any(feature = "zstd", feature = "lz4"))]
+
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures::Future;
+use leaning_tower::{
+    compression::Compression, metrics, mux_client::MuxClient, mux_server,
+    transport::TransportConfig,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::Mutex,
+};
+use tower::{BoxError, Service, ServiceExt};
+
+struct Echo;
+
+impl Service<String> for Echo {
+    type Response = String;
+    type Error = BoxError;
+    #[allow(clippy::type_complexity)]
+    type Future =
+        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + 'static + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Ok(()).into()
+    }
+
+    fn call(&mut self, req: String) -> Self::Future {
+        Box::pin(async move { Ok(req) })
+    }
+}
+
+/// A log-like payload, which compresses well.
+fn payload() -> String {
+    (0..10_000)
+        .map(|line| format!("{line}: all is well\n"))
+        .collect()
+}
+
+async fn echo(addr: &str, config: &TransportConfig) -> String {
+    let mut client: MuxClient<String, String> = MuxClient::new_with(addr, config).await.unwrap();
+    client.ready().await.unwrap().call(payload()).await.unwrap()
+}
+
+/// How many bytes servers received on in-memory connections so far.
+fn received() -> usize {
+    metrics::render()
+        .lines()
+        .find_map(|line| {
+            line.strip_prefix(
+                "leaning_tower_mux_received_bytes_total{side=\"server\",transport=\"memory\"} ",
+            )
+        })
+        .unwrap_or("0")
+        .parse()
+        .unwrap()
+}
+
+/// Tests connecting in memory take turns,
+/// since what servers receive on such connections is counted all together.
+static MEASURING: Mutex<()> = Mutex::const_new(());
+
+/// Offers every algorithm built in.
+fn offering_all() -> TransportConfig {
+    let config = TransportConfig::new();
+    #[cfg(feature = "zstd")]
+    let config = config.with_compression(Compression::Zstd);
+    #[cfg(feature = "lz4")]
+    let config = config.with_compression(Compression::Lz4);
+    config
+}
+
+async fn assert_compressed(addr: &str, compression: Compression) {
+    let config = TransportConfig::new().with_compression(compression);
+    let _handle = mux_server::run_with(addr, Echo, &config).await.unwrap();
+
+    let _measuring = MEASURING.lock().await;
+    let before = received();
+    assert_eq!(echo(addr, &config).await, payload());
+    assert!(received() - before < payload().len() / 4);
+}
+
+#[cfg(feature = "zstd")]
+#[tokio::test]
+async fn test_zstd() {
+    assert_compressed("memory:compression-zstd", Compression::Zstd).await;
+}
+
+#[cfg(feature = "lz4")]
+#[tokio::test]
+async fn test_lz4() {
+    assert_compressed("memory:compression-lz4", Compression::Lz4).await;
+}
+
+#[cfg(all(feature = "zstd", feature = "lz4"))]
+#[tokio::test]
+async fn test_client_preference() {
+    const SERVER_ADDR: &str = "memory:compression-preference";
+
+    let server = TransportConfig::new()
+        .with_compression(Compression::Lz4)
+        .with_compression(Compression::Zstd);
+    let _handle = mux_server::run_with(SERVER_ADDR, Echo, &server)
+        .await
+        .unwrap();
+
+    let _measuring = MEASURING.lock().await;
+    let client = TransportConfig::new()
+        .with_compression(Compression::Zstd)
+        .with_compression(Compression::Lz4);
+    assert_eq!(echo(SERVER_ADDR, &client).await, payload());
+}
+
+#[tokio::test]
+async fn test_nothing_in_common() {
+    const SERVER_ADDR: &str = "memory:compression-none";
+
+    let _handle = mux_server::run_with(SERVER_ADDR, Echo, &TransportConfig::new())
+        .await
+        .unwrap();
+
+    let _measuring = MEASURING.lock().await;
+    let before = received();
+    let client = offering_all();
+    assert_eq!(echo(SERVER_ADDR, &client).await, payload());
+    assert!(received() - before > payload().len());
+
+    // Servers happily compress for clients asking, and serve those which do not.
+    assert_eq!(echo(SERVER_ADDR, &TransportConfig::new()).await, payload());
+}
+
+#[tokio::test]
+async fn test_old_server() {
+    const SERVER_ADDR: &str = "127.0.0.1:5591";
+    const PROXY_ADDR: &str = "127.0.0.1:5592";
+
+    let _handle = mux_server::run_with(SERVER_ADDR, Echo, &TransportConfig::new())
+        .await
+        .unwrap();
+
+    // Pretends to be a server from before connections were set up,
+    // which hangs up on clients asking how to.
+    let proxy = TcpListener::bind(PROXY_ADDR).await.unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut client, _) = proxy.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut first = vec![0; 1024];
+                let read = client.read(&mut first).await.unwrap();
+                let asking = first[..read]
+                    .windows(b"leaning-tower-hello".len())
+                    .any(|window| window == b"leaning-tower-hello");
+                if asking {
+                    return;
+                }
+                let mut server = TcpStream::connect(SERVER_ADDR).await.unwrap();
+                server.write_all(&first[..read]).await.unwrap();
+                let _ = tokio::io::copy_bidirectional(&mut client, &mut server).await;
+            });
+        }
+    });
+
+    let client = offering_all();
+    assert_eq!(echo(PROXY_ADDR, &client).await, payload());
+}
@@ -70,7 +70,7 @@ async fn run(addr: &str, notify: Arc<Notify>) -> Result<(), BoxError> {
     info!(?addr, "Letting the service allocator run forever");
 
     notify.notify_one();
-    let _ = handle.await?;
+    handle.await?;
     info!("Done serving many resource");
 
     Ok(())
@@ -0,0 +1,101 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::Future;
+use leaning_tower::{
+    allocator::{AllocatorService, DescriptionSummary},
+    allocator_client::AllocatorClientService,
+    mux_server,
+    resource_filter::Describable,
+};
+use serde::{Deserialize, Serialize};
+use tower::{BoxError, Service, ServiceExt};
+
+const SERVER_ADDR: &str = "0.0.0.0:5580";
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+enum Variant {
+    Color,
+    BlackAndWhite,
+}
+
+struct Printer(Variant);
+
+impl Service<()> for Printer {
+    type Response = ();
+    type Error = BoxError;
+    #[allow(clippy::type_complexity)]
+    type Future =
+        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + 'static + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Ok(()).into()
+    }
+
+    fn call(&mut self, _req: ()) -> Self::Future {
+        Box::pin(async move { Ok(()) })
+    }
+}
+
+impl Describable<Variant> for Printer {
+    fn describe(&self) -> Variant {
+        self.0
+    }
+}
+
+fn summary(description: Variant, total: usize, free: usize) -> DescriptionSummary<Variant> {
+    DescriptionSummary {
+        description,
+        total,
+        free,
+    }
+}
+
+#[tokio::test]
+async fn test_discovery() {
+    let service = AllocatorService::new(vec![
+        Printer(Variant::Color),
+        Printer(Variant::BlackAndWhite),
+        Printer(Variant::Color),
+    ]);
+    let _handle = mux_server::run(SERVER_ADDR, service).await.unwrap();
+
+    let mut allocator: AllocatorClientService<_, Printer, _> =
+        AllocatorClientService::new(SERVER_ADDR).await.unwrap();
+
+    assert_eq!(
+        allocator.descriptions().await.unwrap(),
+        [
+            summary(Variant::Color, 2, 2),
+            summary(Variant::BlackAndWhite, 1, 1)
+        ]
+    );
+
+    let lease = allocator
+        .ready()
+        .await
+        .unwrap()
+        .call(Variant::Color)
+        .await
+        .unwrap()
+        .unwrap();
+
+    assert_eq!(
+        allocator.descriptions().await.unwrap(),
+        [
+            summary(Variant::Color, 2, 1),
+            summary(Variant::BlackAndWhite, 1, 1)
+        ]
+    );
+
+    drop(lease);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    assert_eq!(
+        allocator.descriptions().await.unwrap()[0],
+        summary(Variant::Color, 2, 2)
+    );
+}
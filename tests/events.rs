@@ -0,0 +1,154 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::{Future, Stream, StreamExt};
+use leaning_tower::{
+    allocator::{AllocatorEvent, AllocatorService},
+    allocator_client::AllocatorClientService,
+    mux_server,
+    resource_filter::Describable,
+};
+use tower::{BoxError, Service, ServiceExt};
+
+const SERVER_ADDR: &str = "0.0.0.0:5589";
+
+struct Printer(usize);
+
+impl Service<()> for Printer {
+    type Response = ();
+    type Error = BoxError;
+    #[allow(clippy::type_complexity)]
+    type Future =
+        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + 'static + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Ok(()).into()
+    }
+
+    fn call(&mut self, _req: ()) -> Self::Future {
+        Box::pin(async move { Ok(()) })
+    }
+}
+
+impl Describable<usize> for Printer {
+    fn describe(&self) -> usize {
+        self.0
+    }
+
+    fn id(&self) -> Option<String> {
+        Some(format!("printer-{}", self.0))
+    }
+}
+
+async fn next_event(
+    events: &mut (impl Stream<Item = Result<AllocatorEvent<usize>, BoxError>> + Unpin),
+) -> AllocatorEvent<usize> {
+    tokio::time::timeout(Duration::from_secs(1), events.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap()
+}
+
+fn granted(description: usize) -> AllocatorEvent<usize> {
+    AllocatorEvent::Granted {
+        description,
+        resource: format!("printer-{description}"),
+        client: Some("worker".to_string()),
+    }
+}
+
+fn released(description: usize) -> AllocatorEvent<usize> {
+    AllocatorEvent::Released {
+        description,
+        resource: format!("printer-{description}"),
+    }
+}
+
+#[tokio::test]
+async fn test_subscribe() {
+    let service = AllocatorService::new(vec![Printer(0), Printer(1)]);
+    let _handle = mux_server::run(SERVER_ADDR, service).await.unwrap();
+
+    let mut allocator: AllocatorClientService<_, Printer, ()> =
+        AllocatorClientService::new_labelled(SERVER_ADDR, "worker")
+            .await
+            .unwrap();
+    let observer: AllocatorClientService<_, Printer, ()> =
+        AllocatorClientService::new(SERVER_ADDR).await.unwrap();
+
+    let mut everything = Box::pin(observer.subscribe(None).await.unwrap());
+    let mut ones = Box::pin(observer.subscribe(Some(1usize)).await.unwrap());
+
+    for description in [0usize, 1] {
+        let mut lease = allocator
+            .ready()
+            .await
+            .unwrap()
+            .call(description)
+            .await
+            .unwrap()
+            .unwrap();
+        lease.ready().await.unwrap().call(()).await.unwrap();
+        lease.release().await.unwrap();
+    }
+
+    for description in [0, 1] {
+        assert_eq!(next_event(&mut everything).await, granted(description));
+        assert_eq!(next_event(&mut everything).await, released(description));
+    }
+
+    assert_eq!(next_event(&mut ones).await, granted(1));
+    assert_eq!(next_event(&mut ones).await, released(1));
+
+    // Nothing more happened.
+    let nothing = tokio::time::timeout(Duration::from_millis(100), everything.next()).await;
+    assert!(nothing.is_err());
+}
+
+#[tokio::test]
+async fn test_redeem_is_granted() {
+    const SERVER_ADDR: &str = "memory:events-redeem";
+
+    let service = AllocatorService::new(vec![Printer(0)]).with_lease_bind("memory:0");
+    let _handle = mux_server::run(SERVER_ADDR, service).await.unwrap();
+
+    let mut allocator: AllocatorClientService<_, Printer, ()> =
+        AllocatorClientService::new_labelled(SERVER_ADDR, "worker")
+            .await
+            .unwrap();
+    let successor: AllocatorClientService<usize, Printer, ()> =
+        AllocatorClientService::new_labelled(SERVER_ADDR, "successor")
+            .await
+            .unwrap();
+    let observer: AllocatorClientService<_, Printer, ()> =
+        AllocatorClientService::new(SERVER_ADDR).await.unwrap();
+    let mut events = Box::pin(observer.subscribe(None).await.unwrap());
+
+    let lease = allocator
+        .ready()
+        .await
+        .unwrap()
+        .call(0usize)
+        .await
+        .unwrap()
+        .unwrap();
+    let ticket = lease.transfer().await.unwrap();
+    let mut lease = successor.redeem(&ticket).await.unwrap();
+    lease.ready().await.unwrap().call(()).await.unwrap();
+    lease.release().await.unwrap();
+
+    assert_eq!(next_event(&mut events).await, granted(0));
+    assert_eq!(
+        next_event(&mut events).await,
+        AllocatorEvent::Granted {
+            description: 0,
+            resource: "printer-0".to_string(),
+            client: Some("successor".to_string()),
+        }
+    );
+    assert_eq!(next_event(&mut events).await, released(0));
+}
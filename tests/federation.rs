@@ -0,0 +1,247 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::Future;
+use leaning_tower::{
+    allocator::{AllocatorError, AllocatorService},
+    allocator_client::AllocatorClientService,
+    allocator_federation::FederatedAllocatorClientService,
+    mux_server,
+    resource_filter::Describable,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tower::{BoxError, Service, ServiceExt};
+
+const ALLOCATOR_A: &str = "0.0.0.0:5572";
+const ALLOCATOR_B: &str = "0.0.0.0:5573";
+const ALLOCATOR_DOWN: &str = "0.0.0.0:5574";
+
+/// Responds with the name of the allocator it belongs to.
+struct NamedService(&'static str);
+
+impl Service<()> for NamedService {
+    type Response = String;
+    type Error = BoxError;
+    #[allow(clippy::type_complexity)]
+    type Future =
+        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + 'static + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Ok(()).into()
+    }
+
+    fn call(&mut self, _req: ()) -> Self::Future {
+        let name = self.0.to_string();
+        Box::pin(async move { Ok(name) })
+    }
+}
+
+impl Describable<usize> for NamedService {
+    fn describe(&self) -> usize {
+        0
+    }
+}
+
+#[tokio::test]
+async fn test_federation_skips_busy_and_down_allocators() {
+    let _a = mux_server::run(ALLOCATOR_A, AllocatorService::new(vec![NamedService("a")]))
+        .await
+        .unwrap();
+    let _b = mux_server::run(ALLOCATOR_B, AllocatorService::new(vec![NamedService("b")]))
+        .await
+        .unwrap();
+
+    // Keep the only resource on allocator A busy.
+    let mut allocator_a: AllocatorClientService<_, NamedService, _> =
+        AllocatorClientService::new(ALLOCATOR_A).await.unwrap();
+    let held = allocator_a
+        .ready()
+        .await
+        .unwrap()
+        .call(0usize)
+        .await
+        .unwrap()
+        .unwrap();
+
+    let mut federation: FederatedAllocatorClientService<_, NamedService, _> =
+        FederatedAllocatorClientService::new(&[ALLOCATOR_DOWN, ALLOCATOR_A, ALLOCATOR_B])
+            .await
+            .unwrap();
+    let mut svc = federation
+        .ready()
+        .await
+        .unwrap()
+        .call(0usize)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(svc.ready().await.unwrap().call(()).await.unwrap(), "b");
+
+    // The wait on allocator A was cancelled, so once released the resource
+    // is available right away instead of being leased out to nobody.
+    // The cancellation is sent in the background, give it a moment to arrive.
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    drop(held);
+    tokio::time::timeout(Duration::from_secs(2), async {
+        allocator_a
+            .ready()
+            .await
+            .unwrap()
+            .call(0usize)
+            .await
+            .unwrap()
+            .unwrap()
+    })
+    .await
+    .unwrap();
+}
+
+/// Passes connections on to `to`, delaying what comes back on them.
+async fn slow_proxy(at: &str, to: &'static str) {
+    let proxy = TcpListener::bind(at).await.unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (client, _) = proxy.accept().await.unwrap();
+            let server = TcpStream::connect(to).await.unwrap();
+            let (mut client_read, mut client_write) = client.into_split();
+            let (mut server_read, mut server_write) = server.into_split();
+            tokio::spawn(async move {
+                let _ = tokio::io::copy(&mut client_read, &mut server_write).await;
+            });
+            tokio::spawn(async move {
+                let mut buf = vec![0; 64 * 1024];
+                loop {
+                    let read = match server_read.read(&mut buf).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(read) => read,
+                    };
+                    tokio::time::sleep(Duration::from_millis(200)).await;
+                    if client_write.write_all(&buf[..read]).await.is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+    });
+}
+
+#[tokio::test]
+async fn test_federation_releases_losing_grants() {
+    const ALLOCATOR_C: &str = "memory:federation-c";
+    const ALLOCATOR_D: &str = "127.0.0.1:5598";
+    const PROXY_D: &str = "127.0.0.1:5599";
+
+    // Without being released, a losing grant would be held until the client
+    // is given up on for not connecting.
+    let allocator = |name| {
+        AllocatorService::new(vec![NamedService(name)])
+            .with_lease_bind("memory:0")
+            .with_accept_timeout(Duration::from_secs(30))
+    };
+    let _c = mux_server::run(ALLOCATOR_C, allocator("c")).await.unwrap();
+    let _d = mux_server::run(ALLOCATOR_D, allocator("d")).await.unwrap();
+    slow_proxy(PROXY_D, ALLOCATOR_D).await;
+
+    // Both grant right away, but the grant from D is slow to arrive,
+    // so D has granted by the time it hears it lost.
+    let mut federation: FederatedAllocatorClientService<_, NamedService, _> =
+        FederatedAllocatorClientService::new(&[ALLOCATOR_C, PROXY_D])
+            .await
+            .unwrap();
+    let mut svc = federation
+        .ready()
+        .await
+        .unwrap()
+        .call(0usize)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(svc.ready().await.unwrap().call(()).await.unwrap(), "c");
+
+    let allocator_d: AllocatorClientService<usize, NamedService, ()> =
+        AllocatorClientService::new(ALLOCATOR_D).await.unwrap();
+    tokio::time::timeout(Duration::from_secs(2), async {
+        while allocator_d.descriptions().await.unwrap()[0].free == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_federation_connects_concurrently() {
+    const ALLOCATOR_E: &str = "memory:federation-e";
+
+    let _e = mux_server::run(ALLOCATOR_E, AllocatorService::new(vec![NamedService("e")]))
+        .await
+        .unwrap();
+    // Take connections, but never answer on them.
+    let silent = [
+        TcpListener::bind("127.0.0.1:0").await.unwrap(),
+        TcpListener::bind("127.0.0.1:0").await.unwrap(),
+    ];
+    let silent = silent
+        .iter()
+        .map(|listener| listener.local_addr().unwrap().to_string())
+        .collect::<Vec<_>>();
+
+    // Each silent allocator is given up on after a while, but not one after the other.
+    let start = tokio::time::Instant::now();
+    let _federation: FederatedAllocatorClientService<usize, NamedService, ()> =
+        FederatedAllocatorClientService::new(&[ALLOCATOR_E, &silent[0], &silent[1]])
+            .await
+            .unwrap();
+    assert!(
+        start.elapsed() < Duration::from_secs(8),
+        "{:?}",
+        start.elapsed()
+    );
+}
+
+#[tokio::test]
+async fn test_federation_tells_busy_from_no_match() {
+    const ALLOCATOR_F: &str = "memory:federation-f";
+    const ALLOCATOR_G: &str = "memory:federation-g";
+
+    // F has a matching resource, but it's busy and nobody may wait for it. G has none.
+    let busy = AllocatorService::new(vec![NamedService("f")])
+        .with_lease_bind("memory:0")
+        .with_max_waiters(0);
+    let _f = mux_server::run(ALLOCATOR_F, busy).await.unwrap();
+    let none = AllocatorService::<NamedService, (), usize>::new(vec![]);
+    let _g = mux_server::run(ALLOCATOR_G, none).await.unwrap();
+
+    let mut allocator_f: AllocatorClientService<usize, NamedService, ()> =
+        AllocatorClientService::new(ALLOCATOR_F).await.unwrap();
+    let _held = allocator_f
+        .ready()
+        .await
+        .unwrap()
+        .call(0usize)
+        .await
+        .unwrap()
+        .unwrap();
+
+    let mut federation: FederatedAllocatorClientService<_, NamedService, ()> =
+        FederatedAllocatorClientService::new(&[ALLOCATOR_F, ALLOCATOR_G])
+            .await
+            .unwrap();
+    let e = federation
+        .ready()
+        .await
+        .unwrap()
+        .call(0usize)
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(e.downcast_ref(), Some(AllocatorError::QueueFull)),
+        "{e:?}"
+    );
+}
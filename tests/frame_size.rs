@@ -0,0 +1,119 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures::Future;
+use leaning_tower::{mux_client::MuxClient, mux_server, transport::TransportConfig};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+use tower::{BoxError, Service, ServiceExt};
+
+/// Answers with a string as long as asked for, and ignores the padding.
+struct Repeat;
+
+impl Service<(usize, String)> for Repeat {
+    type Response = String;
+    type Error = BoxError;
+    #[allow(clippy::type_complexity)]
+    type Future =
+        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + 'static + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Ok(()).into()
+    }
+
+    fn call(&mut self, (length, _padding): (usize, String)) -> Self::Future {
+        Box::pin(async move { Ok("x".repeat(length)) })
+    }
+}
+
+type Client = MuxClient<(usize, String), String>;
+
+async fn call(client: &mut Client, length: usize, padding: usize) -> Result<String, BoxError> {
+    client
+        .ready()
+        .await?
+        .call((length, "p".repeat(padding)))
+        .await
+}
+
+#[tokio::test]
+async fn test_max_frame_size() {
+    const SERVER_ADDR: &str = "memory:frame-size";
+    const LARGE_SERVER_ADDR: &str = "memory:frame-size-large";
+
+    let server = TransportConfig::new().with_max_frame_size(1024);
+    let _handle = mux_server::run_with(SERVER_ADDR, Repeat, &server)
+        .await
+        .unwrap();
+
+    // Small frames are fine both ways.
+    let mut client = Client::new(SERVER_ADDR).await.unwrap();
+    assert_eq!(call(&mut client, 10, 10).await.unwrap(), "x".repeat(10));
+
+    // The server hangs up on clients sending too much.
+    assert!(call(&mut client, 10, 10_000).await.is_err());
+
+    // Clients refuse to send too much, or to receive it.
+    let mut client = Client::new_with(SERVER_ADDR, &server).await.unwrap();
+    let e = call(&mut client, 10, 10_000).await.unwrap_err();
+    assert!(format!("{e:?}").contains("larger than the maximum of 1024"));
+
+    let _handle = mux_server::run(LARGE_SERVER_ADDR, Repeat).await.unwrap();
+    let mut client = Client::new_with(LARGE_SERVER_ADDR, &server).await.unwrap();
+    let e = call(&mut client, 10_000, 10).await.unwrap_err();
+    assert!(format!("{e:?}").contains("larger than the maximum of 1024"));
+
+    // Others are still served.
+    let mut client = Client::new(SERVER_ADDR).await.unwrap();
+    assert_eq!(call(&mut client, 10, 10).await.unwrap(), "x".repeat(10));
+}
+
+#[tokio::test]
+async fn test_malformed_frames() {
+    const SERVER_ADDR: &str = "127.0.0.1:5593";
+
+    let _handle = mux_server::run(SERVER_ADDR, Repeat).await.unwrap();
+
+    let frames: [&[u8]; 2] = [
+        // A length prefix claiming a frame of almost 4 GiB.
+        &[0xff, 0xff, 0xff, 0xf0],
+        // A frame claiming to hold a string of almost 4 GiB.
+        &[0, 0, 0, 7, 1, 1, 0xfc, 0xf0, 0xff, 0xff, 0xff],
+    ];
+    for frame in frames {
+        let mut stream = TcpStream::connect(SERVER_ADDR).await.unwrap();
+        stream.write_all(frame).await.unwrap();
+
+        // Hung up on without an answer.
+        let mut answer = vec![];
+        let _ = stream.read_to_end(&mut answer).await;
+        assert!(answer.is_empty());
+    }
+
+    let mut client = Client::new(SERVER_ADDR).await.unwrap();
+    assert_eq!(call(&mut client, 10, 10).await.unwrap(), "x".repeat(10));
+}
+
+#[cfg(feature = "zstd")]
+#[tokio::test]
+async fn test_decompressed_size() {
+    use leaning_tower::compression::Compression;
+
+    const SERVER_ADDR: &str = "memory:frame-size-compressed";
+
+    let server = TransportConfig::new()
+        .with_compression(Compression::Zstd)
+        .with_max_frame_size(64 * 1024);
+    let _handle = mux_server::run_with(SERVER_ADDR, Repeat, &server)
+        .await
+        .unwrap();
+
+    // Compresses to well below the limit, but not once decompressed.
+    let client = TransportConfig::new().with_compression(Compression::Zstd);
+    let mut client = Client::new_with(SERVER_ADDR, &client).await.unwrap();
+    assert!(call(&mut client, 10, 1024 * 1024).await.is_err());
+}
@@ -0,0 +1,144 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures::Future;
+use leaning_tower::{
+    mux_client::MuxClient,
+    mux_server,
+    transport::{HandshakeError, TransportConfig, PROTOCOL_VERSION},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tower::{BoxError, Service, ServiceExt};
+
+const HELLO: &[u8] = b"\0leaning-tower-hello\0";
+
+struct Echo;
+
+impl Service<String> for Echo {
+    type Response = String;
+    type Error = BoxError;
+    #[allow(clippy::type_complexity)]
+    type Future =
+        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + 'static + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Ok(()).into()
+    }
+
+    fn call(&mut self, req: String) -> Self::Future {
+        Box::pin(async move { Ok(req) })
+    }
+}
+
+async fn connect(
+    addr: &str,
+    config: &TransportConfig,
+) -> Result<MuxClient<String, String>, BoxError> {
+    MuxClient::new_with(addr, config).await
+}
+
+fn handshake_error(result: Result<MuxClient<String, String>, BoxError>) -> HandshakeError {
+    *result.unwrap_err().downcast::<HandshakeError>().unwrap()
+}
+
+#[tokio::test]
+async fn test_fingerprint() {
+    const SERVER_ADDR: &str = "memory:handshake-fingerprint";
+
+    let server = TransportConfig::new().with_fingerprint("echo/2");
+    let _handle = mux_server::run_with(SERVER_ADDR, Echo, &server)
+        .await
+        .unwrap();
+
+    let mut client = connect(SERVER_ADDR, &server).await.unwrap();
+    let response = client
+        .ready()
+        .await
+        .unwrap()
+        .call("hello".to_string())
+        .await
+        .unwrap();
+    assert_eq!(response, "hello");
+
+    let drifted = TransportConfig::new().with_fingerprint("echo/1");
+    assert_eq!(
+        handshake_error(connect(SERVER_ADDR, &drifted).await),
+        HandshakeError::Fingerprint {
+            ours: Some("echo/1".to_string()),
+            theirs: Some("echo/2".to_string()),
+        }
+    );
+
+    let error = handshake_error(connect(SERVER_ADDR, &TransportConfig::new()).await);
+    assert_eq!(
+        error.to_string(),
+        "peer has fingerprint \"echo/2\", but this end has none"
+    );
+}
+
+#[tokio::test]
+async fn test_protocol_version() {
+    const SERVER_ADDR: &str = "127.0.0.1:5594";
+    const NEWER_SERVER_ADDR: &str = "127.0.0.1:5595";
+
+    let _handle = mux_server::run(SERVER_ADDR, Echo).await.unwrap();
+
+    // A client from the future is told which version the server speaks, and hung up on.
+    let mut stream = TcpStream::connect(SERVER_ADDR).await.unwrap();
+    let hello = [HELLO, &99u32.to_be_bytes(), b"whatever comes next"].concat();
+    stream
+        .write_all(&(hello.len() as u32).to_be_bytes())
+        .await
+        .unwrap();
+    stream.write_all(&hello).await.unwrap();
+    let mut reply = vec![];
+    stream.read_to_end(&mut reply).await.unwrap();
+    let expected = [HELLO, &PROTOCOL_VERSION.to_be_bytes()].concat();
+    assert_eq!(&reply[4..4 + expected.len()], expected);
+
+    // A server from the future answers the same way.
+    let newer = TcpListener::bind(NEWER_SERVER_ADDR).await.unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = newer.accept().await.unwrap();
+        let reply = [HELLO, &99u32.to_be_bytes()].concat();
+        stream
+            .write_all(&(reply.len() as u32).to_be_bytes())
+            .await
+            .unwrap();
+        stream.write_all(&reply).await.unwrap();
+        let _ = stream.read_to_end(&mut vec![]).await;
+    });
+    assert_eq!(
+        handshake_error(connect(NEWER_SERVER_ADDR, &TransportConfig::new()).await),
+        HandshakeError::Version {
+            ours: PROTOCOL_VERSION,
+            theirs: 99
+        }
+    );
+}
+
+#[tokio::test]
+async fn test_server_without_handshake() {
+    const OLD_SERVER_ADDR: &str = "127.0.0.1:5596";
+
+    // Hangs up on anyone saying hello, like servers from before handshakes do.
+    let old = TcpListener::bind(OLD_SERVER_ADDR).await.unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = old.accept().await.unwrap();
+            let _ = stream.read(&mut [0; 1024]).await;
+        }
+    });
+
+    // Those can't check fingerprints.
+    let config = TransportConfig::new().with_fingerprint("echo/2");
+    assert_eq!(
+        handshake_error(connect(OLD_SERVER_ADDR, &config).await),
+        HandshakeError::NoHandshake
+    );
+}
@@ -0,0 +1,138 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::Future;
+use leaning_tower::{
+    allocator::{
+        Allocation, AllocationOptions, AllocatorError, AllocatorReply, AllocatorRequest,
+        AllocatorService, CloseReason,
+    },
+    allocator_client::AllocatorClientService,
+    mux_client::MuxClient,
+    mux_server,
+    resource_filter::Describable,
+};
+use tower::{BoxError, Service, ServiceExt};
+
+const SERVER_ADDR: &str = "0.0.0.0:5587";
+
+/// Prints for the given number of milliseconds.
+struct Printer;
+
+impl Service<u64> for Printer {
+    type Response = ();
+    type Error = BoxError;
+    #[allow(clippy::type_complexity)]
+    type Future =
+        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + 'static + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Ok(()).into()
+    }
+
+    fn call(&mut self, millis: u64) -> Self::Future {
+        Box::pin(async move {
+            tokio::time::sleep(Duration::from_millis(millis)).await;
+            Ok(())
+        })
+    }
+}
+
+impl Describable<usize> for Printer {
+    fn describe(&self) -> usize {
+        0
+    }
+}
+
+#[tokio::test]
+async fn test_idle_lease_reclaimed() {
+    let idle_timeout = Duration::from_millis(200);
+    let service = AllocatorService::new(vec![Printer]).with_idle_timeout(idle_timeout);
+    let _handle = mux_server::run(SERVER_ADDR, service).await.unwrap();
+
+    let mut allocator: AllocatorClientService<_, Printer, u64> =
+        AllocatorClientService::new(SERVER_ADDR).await.unwrap();
+    let mut lease = allocator
+        .ready()
+        .await
+        .unwrap()
+        .call(0usize)
+        .await
+        .unwrap()
+        .unwrap();
+
+    // Regular traffic keeps the lease.
+    for _ in 0..5 {
+        lease.ready().await.unwrap().call(0).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    // So does a long running request.
+    lease.ready().await.unwrap().call(400).await.unwrap();
+
+    // Forgetting about it does not.
+    tokio::time::sleep(Duration::from_millis(400)).await;
+    assert_eq!(allocator.descriptions().await.unwrap()[0].free, 1);
+
+    let e = lease.ready().await.unwrap().call(0).await.unwrap_err();
+    match e.downcast_ref::<AllocatorError>() {
+        Some(AllocatorError::LeaseClosed(reason)) => {
+            assert_eq!(*reason, CloseReason::Idle(idle_timeout))
+        }
+        _ => panic!("Expected the lease to be closed, got {e:?}"),
+    }
+}
+
+#[tokio::test]
+async fn test_idle_from_connect() {
+    let idle_timeout = Duration::from_millis(200);
+    let mut service = AllocatorService::new(vec![Printer])
+        .with_lease_bind("memory:0")
+        .with_idle_timeout(idle_timeout);
+
+    let allocation = AllocatorRequest::Allocate(Allocation {
+        id: 0,
+        description: 0usize,
+        client: None,
+        options: AllocationOptions::default(),
+    });
+    let grant = match service.ready().await.unwrap().call(allocation).await {
+        Ok(Ok(AllocatorReply::Granted(grant))) => grant,
+        other => panic!("Expected a grant, got {other:?}"),
+    };
+
+    // Taking longer than the idle timeout to connect is fine, as long as it's in time to be accepted.
+    tokio::time::sleep(Duration::from_millis(400)).await;
+    let mut lease: MuxClient<u64, ()> = MuxClient::new(&grant.address.unwrap()).await.unwrap();
+    lease.ready().await.unwrap().call(0).await.unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_idle_in_paused_time() {
+    const ALLOCATOR_ADDR: &str = "memory:idle-paused";
+
+    let idle_timeout = Duration::from_secs(10);
+    let service = AllocatorService::new(vec![Printer])
+        .with_lease_bind("memory:0")
+        .with_idle_timeout(idle_timeout);
+    let _handle = mux_server::run(ALLOCATOR_ADDR, service).await.unwrap();
+
+    let mut allocator: AllocatorClientService<_, Printer, u64> =
+        AllocatorClientService::new(ALLOCATOR_ADDR).await.unwrap();
+    let mut lease = allocator
+        .ready()
+        .await
+        .unwrap()
+        .call(0usize)
+        .await
+        .unwrap()
+        .unwrap();
+    lease.ready().await.unwrap().call(0).await.unwrap();
+
+    // Idleness is measured in the same time it is waited in.
+    tokio::time::sleep(Duration::from_secs(60)).await;
+    assert_eq!(allocator.descriptions().await.unwrap()[0].free, 1);
+}
@@ -0,0 +1,194 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::Future;
+use leaning_tower::{
+    allocator::AllocatorService,
+    allocator_client::AllocatorClientService,
+    journal::{self, Event, Journal},
+    mux_server,
+    resource_filter::Describable,
+    transport::TransportConfig,
+};
+use tower::{BoxError, Service, ServiceExt};
+
+const SERVER_ADDR: &str = "0.0.0.0:5568";
+
+struct EchoService(usize);
+
+impl Service<String> for EchoService {
+    type Response = String;
+    type Error = BoxError;
+    #[allow(clippy::type_complexity)]
+    type Future =
+        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + 'static + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Ok(()).into()
+    }
+
+    fn call(&mut self, req: String) -> Self::Future {
+        Box::pin(async move { Ok(req) })
+    }
+}
+
+impl Describable<usize> for EchoService {
+    fn describe(&self) -> usize {
+        self.0
+    }
+}
+
+#[tokio::test]
+async fn test_journal_summary() {
+    let path = std::env::temp_dir().join(format!(
+        "leaning-tower-journal-{}.jsonl",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&path);
+
+    let service = AllocatorService::new(vec![EchoService(0), EchoService(1)])
+        .with_journal(Journal::open(&path).unwrap());
+    let _handle = mux_server::run(SERVER_ADDR, service).await.unwrap();
+
+    let mut allocator: AllocatorClientService<_, EchoService, _> =
+        AllocatorClientService::new_labelled(SERVER_ADDR, "journal-test")
+            .await
+            .unwrap();
+
+    for _ in 0..2 {
+        let mut svc = allocator
+            .ready()
+            .await
+            .unwrap()
+            .call(1usize)
+            .await
+            .unwrap()
+            .unwrap();
+        let response = svc
+            .ready()
+            .await
+            .unwrap()
+            .call("hello".to_string())
+            .await
+            .unwrap();
+        assert_eq!(response, "hello");
+    }
+
+    // Give the allocator a moment to notice the leases ended.
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let entries = journal::replay(&path).unwrap();
+    assert!(entries
+        .iter()
+        .any(|entry| matches!(&entry.event, Event::Connected { resource, .. } if resource == "1")));
+
+    let summary = journal::summarize(&path).unwrap();
+    assert_eq!(summary.per_resource["1"].leases, 2);
+    assert!(!summary.per_resource.contains_key("0"));
+    assert_eq!(
+        summary.per_client[&Some("journal-test".to_string())].leases,
+        2
+    );
+
+    let _ = std::fs::remove_file(&path);
+}
+
+#[tokio::test]
+async fn test_journal_failed_session() {
+    const SERVER_ADDR: &str = "memory:journal-failed";
+
+    let path = std::env::temp_dir().join(format!(
+        "leaning-tower-journal-failed-{}.jsonl",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&path);
+
+    // The lease server hangs up on requests this large.
+    let service = AllocatorService::new(vec![EchoService(0)])
+        .with_lease_bind("memory:0")
+        .with_lease_transport(TransportConfig::new().with_max_frame_size(64))
+        .with_journal(Journal::open(&path).unwrap());
+    let _handle = mux_server::run(SERVER_ADDR, service).await.unwrap();
+
+    let mut allocator: AllocatorClientService<_, EchoService, _> =
+        AllocatorClientService::new(SERVER_ADDR).await.unwrap();
+    let mut svc = allocator
+        .ready()
+        .await
+        .unwrap()
+        .call(0usize)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(svc
+        .ready()
+        .await
+        .unwrap()
+        .call("x".repeat(1000))
+        .await
+        .is_err());
+
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let entries = journal::replay(&path).unwrap();
+    assert!(
+        entries.iter().any(|entry| matches!(
+            &entry.event,
+            Event::SessionFailed { reason, .. } if reason.contains("larger than the maximum")
+        )),
+        "{entries:?}"
+    );
+    assert!(!entries
+        .iter()
+        .any(|entry| matches!(&entry.event, Event::Released { .. })));
+    assert_eq!(
+        journal::summarize(&path).unwrap().per_resource["0"].leases,
+        1
+    );
+
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn test_summary_transfer() {
+    let at = |time, event| journal::Entry { time, event };
+    let entries = [
+        at(
+            1000,
+            Event::Granted {
+                request: 0,
+                client: Some("first".to_string()),
+                resource: "0".to_string(),
+                port: 1,
+            },
+        ),
+        at(
+            4000,
+            Event::Transferred {
+                request: 0,
+                client: Some("second".to_string()),
+                resource: "0".to_string(),
+                port: 2,
+            },
+        ),
+        at(
+            10000,
+            Event::Released {
+                request: 0,
+                resource: "0".to_string(),
+            },
+        ),
+    ];
+
+    // The resource was leased out once, to each client for as long as they held it.
+    let summary = journal::Summary::from_entries(&entries);
+    assert_eq!(summary.per_resource["0"].leases, 1);
+    assert_eq!(summary.per_resource["0"].held, Duration::from_secs(9));
+    let first = &summary.per_client[&Some("first".to_string())];
+    assert_eq!((first.leases, first.held), (1, Duration::from_secs(3)));
+    let second = &summary.per_client[&Some("second".to_string())];
+    assert_eq!((second.leases, second.held), (1, Duration::from_secs(6)));
+}
@@ -0,0 +1,233 @@
+use std::{
+    pin::Pin,
+    sync::mpsc,
+    task::{Context, Poll},
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use futures::Future;
+use leaning_tower::{
+    allocator::AllocatorService, allocator_client::AllocatorClientService, mux_client::MuxClient,
+    mux_server, resource_filter::Describable, transport::TransportConfig,
+};
+use tokio::{sync::oneshot, time::Sleep};
+use tower::{BoxError, Service, ServiceExt};
+
+#[derive(Clone)]
+struct Echo;
+
+impl Service<String> for Echo {
+    type Response = String;
+    type Error = BoxError;
+    #[allow(clippy::type_complexity)]
+    type Future =
+        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + 'static + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Ok(()).into()
+    }
+
+    fn call(&mut self, req: String) -> Self::Future {
+        Box::pin(async move { Ok(req) })
+    }
+}
+
+impl Describable<usize> for Echo {
+    fn describe(&self) -> usize {
+        0
+    }
+}
+
+/// Echoes, but is not ready for a while at first.
+struct Sluggish(Pin<Box<Sleep>>);
+
+impl Service<String> for Sluggish {
+    type Response = String;
+    type Error = BoxError;
+    #[allow(clippy::type_complexity)]
+    type Future =
+        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + 'static + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.0.as_mut().poll(cx).map(Ok)
+    }
+
+    fn call(&mut self, req: String) -> Self::Future {
+        Box::pin(async move { Ok(req) })
+    }
+}
+
+fn keepalive() -> TransportConfig {
+    TransportConfig::new().with_keepalive(Duration::from_millis(20), 2)
+}
+
+async fn echo(client: &mut MuxClient<String, String>) -> Result<String, BoxError> {
+    client.ready().await?.call("hello".to_string()).await
+}
+
+/// A runtime which stopped dead, see [`fall_asleep_after`].
+/// It is shut down when dropped, along with whatever it kept.
+struct Sleeper {
+    fell_asleep: Option<oneshot::Receiver<()>>,
+    shut_down: Option<mpsc::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Sleeper {
+    /// Wait until the runtime is done and asleep.
+    async fn asleep(&mut self) {
+        if let Some(fell_asleep) = self.fell_asleep.take() {
+            fell_asleep.await.unwrap();
+        }
+    }
+}
+
+impl Drop for Sleeper {
+    fn drop(&mut self) {
+        drop(self.shut_down.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Runs `awake` on a runtime of its own, which stops dead once it is done,
+/// like a laptop going to sleep: connections are not closed, but nothing answers on them.
+fn fall_asleep_after<F>(awake: F) -> Sleeper
+where
+    F: Future + Send + 'static,
+    F::Output: Send,
+{
+    let (asleep, fell_asleep) = oneshot::channel();
+    let (shut_down, shutting_down) = mpsc::channel::<()>();
+    let thread = std::thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let kept = runtime.block_on(awake);
+        let _ = asleep.send(());
+        // Nothing runs on the runtime until it is shut down.
+        let _ = shutting_down.recv();
+        drop(kept);
+        drop(runtime);
+    });
+    Sleeper {
+        fell_asleep: Some(fell_asleep),
+        shut_down: Some(shut_down),
+        thread: Some(thread),
+    }
+}
+
+#[tokio::test]
+async fn test_idle_peers_are_kept() {
+    const SERVER_ADDR: &str = "memory:keepalive-idle";
+
+    let _handle = mux_server::run_with(SERVER_ADDR, Echo, &keepalive())
+        .await
+        .unwrap();
+
+    // Idle clients answer pings, whether or not they send any.
+    let mut pinging = MuxClient::new_with(SERVER_ADDR, &keepalive())
+        .await
+        .unwrap();
+    let mut quiet = MuxClient::new(SERVER_ADDR).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    assert_eq!(echo(&mut pinging).await.unwrap(), "hello");
+    assert_eq!(echo(&mut quiet).await.unwrap(), "hello");
+}
+
+#[tokio::test]
+async fn test_busy_peers_are_kept() {
+    const SERVER_ADDR: &str = "memory:keepalive-busy";
+
+    let sluggish = Sluggish(Box::pin(tokio::time::sleep(Duration::from_millis(300))));
+    let _handle = mux_server::run_with(SERVER_ADDR, sluggish, &keepalive())
+        .await
+        .unwrap();
+
+    // More requests than the server takes in while it is not ready,
+    // its answers to pings are not stuck behind them.
+    let mut client: MuxClient<String, String> = MuxClient::new_with(SERVER_ADDR, &keepalive())
+        .await
+        .unwrap();
+    let mut requests = vec![];
+    for i in 0..100 {
+        requests.push(client.ready().await.unwrap().call(i.to_string()));
+    }
+    for (i, request) in requests.into_iter().enumerate() {
+        assert_eq!(request.await.unwrap(), i.to_string());
+    }
+}
+
+#[tokio::test]
+async fn test_sleeping_client_frees_lease() {
+    const ALLOCATOR_ADDR: &str = "memory:keepalive-allocator";
+
+    let service = AllocatorService::new(vec![Echo])
+        .with_lease_bind("memory:0")
+        .with_lease_transport(keepalive());
+    let _handle = mux_server::run(ALLOCATOR_ADDR, service).await.unwrap();
+
+    let mut sleeper = fall_asleep_after(async move {
+        let mut allocator: AllocatorClientService<_, Echo, String> =
+            AllocatorClientService::new(ALLOCATOR_ADDR).await.unwrap();
+        let lease = allocator
+            .ready()
+            .await
+            .unwrap()
+            .call(0usize)
+            .await
+            .unwrap()
+            .unwrap();
+        (allocator, lease)
+    });
+    sleeper.asleep().await;
+
+    // The only resource is leased out to a client which no longer answers,
+    // until the allocator gives up on it.
+    let mut allocator: AllocatorClientService<_, Echo, String> =
+        AllocatorClientService::new(ALLOCATOR_ADDR).await.unwrap();
+    let mut lease = tokio::time::timeout(Duration::from_secs(2), async {
+        allocator
+            .ready()
+            .await
+            .unwrap()
+            .call(0usize)
+            .await
+            .unwrap()
+            .unwrap()
+    })
+    .await
+    .unwrap();
+    assert_eq!(echo(&mut lease).await.unwrap(), "hello");
+}
+
+#[tokio::test]
+async fn test_sleeping_server() {
+    const SERVER_ADDR: &str = "memory:keepalive-asleep";
+
+    let (serving, served) = oneshot::channel();
+    let (sleep, bedtime) = oneshot::channel::<()>();
+    let mut sleeper = fall_asleep_after(async move {
+        let _handle = mux_server::run(SERVER_ADDR, Echo).await.unwrap();
+        serving.send(()).unwrap();
+        let _ = bedtime.await;
+    });
+    served.await.unwrap();
+
+    let mut client = MuxClient::new_with(SERVER_ADDR, &keepalive())
+        .await
+        .unwrap();
+    assert_eq!(echo(&mut client).await.unwrap(), "hello");
+    sleep.send(()).unwrap();
+    sleeper.asleep().await;
+
+    let e = tokio::time::timeout(Duration::from_secs(2), echo(&mut client))
+        .await
+        .unwrap()
+        .unwrap_err();
+    assert!(format!("{e:?}").contains("did not answer 2 pings"), "{e:?}");
+}
@@ -0,0 +1,183 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures::Future;
+use leaning_tower::{
+    allocator::AllocatorService,
+    allocator_client::AllocatorClientService,
+    labels::{Labelled, Labels, Selector},
+    metrics, mux_server,
+};
+use tower::{BoxError, Service, ServiceExt};
+
+const SERVER_ADDR: &str = "0.0.0.0:5579";
+
+struct Board {
+    serial: &'static str,
+    labels: &'static str,
+}
+
+impl Service<()> for Board {
+    type Response = ();
+    type Error = BoxError;
+    #[allow(clippy::type_complexity)]
+    type Future =
+        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + 'static + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Ok(()).into()
+    }
+
+    fn call(&mut self, _req: ()) -> Self::Future {
+        Box::pin(async move { Ok(()) })
+    }
+}
+
+impl Labelled for Board {
+    fn labels(&self) -> Labels {
+        Labels::parse_literal(self.labels).unwrap()
+    }
+
+    fn id(&self) -> Option<String> {
+        Some(self.serial.to_string())
+    }
+}
+
+fn labels(s: &str) -> Labels {
+    s.parse().unwrap()
+}
+
+#[test]
+fn test_selectors() {
+    let board = Labels::parse_literal("site=osl, rack=3, type=nrf52, fw=1.4.2").unwrap();
+
+    assert!(labels("").selects(&board));
+    assert!(labels("site=osl, type=nrf52").selects(&board));
+    assert!(!labels("site=trd").selects(&board));
+
+    assert!(labels("fw=1.*").selects(&board));
+    assert!(labels("fw=*.2").selects(&board));
+    assert!(!labels("fw=2.*").selects(&board));
+
+    assert!(labels("rack in (2, 3)").selects(&board));
+    assert!(!labels("rack notin (2, 3)").selects(&board));
+    assert!(labels("type!=nrf53").selects(&board));
+    assert!(!labels("type!=nrf52").selects(&board));
+
+    // Several selectors on the same label must all hold.
+    assert!(labels("rack!=4, rack!=5").selects(&board));
+    assert!(!labels("rack!=3, rack!=4").selects(&board));
+    assert!(!labels("rack!=4, rack!=3").selects(&board));
+    assert!(labels("fw=1.*, fw=*.2").selects(&board));
+    assert!(!labels("fw=1.*, fw=*.3").selects(&board));
+
+    assert!(labels("rack").selects(&board));
+    assert!(labels("!broken").selects(&board));
+    assert!(!labels("gpu").selects(&board));
+
+    assert!("rack in (2, 3".parse::<Labels>().is_err());
+    assert!("=osl".parse::<Labels>().is_err());
+
+    // Resource labels are what they say, even with a `*` in them.
+    let odd = Labels::parse_literal("name=*star*, fw=1.*").unwrap();
+    assert_eq!(odd.get("name"), Some("*star*"));
+    assert!(labels("fw=1.*").selects(&odd));
+    assert!(Labels::new()
+        .with_selector("name", Selector::exact("*star*"))
+        .selects(&odd));
+    assert!(!labels("name=*moon*").selects(&odd));
+    assert!(Labels::parse_literal("rack in (3, 4)").is_err());
+
+    // The order labels are given in does not matter.
+    assert_eq!(labels("site=osl, rack=3"), labels("rack=3, site=osl"));
+
+    // Selectors print the way they are parsed.
+    let query = "fw=1.*, rack in (2, 3), site!=trd, !broken";
+    assert_eq!(
+        labels(query),
+        Labels::new()
+            .with_selector("fw", Selector::wildcard("1.*"))
+            .with_selector("rack", Selector::any_of(["2", "3"]))
+            .with_selector("site", Selector::not(Selector::exact("trd")))
+            .with_selector("broken", Selector::absent())
+    );
+    assert_eq!(
+        labels(query).to_string(),
+        "!broken, fw=1.*, rack in (2, 3), site!=trd"
+    );
+}
+
+#[tokio::test]
+async fn test_allocate_by_labels() {
+    let service = AllocatorService::new(vec![
+        Board {
+            serial: "a",
+            labels: "site=osl, rack=3, type=nrf52, fw=1.4.2",
+        },
+        Board {
+            serial: "b",
+            labels: "site=osl, rack=4, type=nrf53, fw=2.0.0",
+        },
+        Board {
+            serial: "c",
+            labels: "site=trd, rack=1, type=nrf52, fw=1.5.0",
+        },
+    ]);
+    let _handle = mux_server::run(SERVER_ADDR, service).await.unwrap();
+
+    let allocator: AllocatorClientService<Labels, Board, ()> =
+        AllocatorClientService::new(SERVER_ADDR).await.unwrap();
+
+    let allocate = |query: &str| {
+        let query = labels(query);
+        let mut allocator = allocator.clone();
+        async move { allocator.ready().await.unwrap().call(query).await.unwrap() }
+    };
+
+    let lease = allocate("type=nrf52, site!=osl").await.unwrap();
+    assert_eq!(lease.resource(), Some("c"));
+
+    let lease = allocate("fw=2.*").await.unwrap();
+    assert_eq!(lease.resource(), Some("b"));
+
+    let lease = allocate("site=osl, rack in (3, 5)").await.unwrap();
+    assert_eq!(lease.resource(), Some("a"));
+
+    assert!(allocate("type=nrf91").await.is_none());
+}
+
+#[tokio::test]
+async fn test_lease_metrics_by_resource() {
+    const ALLOCATOR_ADDR: &str = "memory:labels-metrics";
+    const BOARD: &str = "site=metrics, rack=3, type=nrf52";
+
+    let service = AllocatorService::new(vec![Board {
+        serial: "m",
+        labels: BOARD,
+    }])
+    .with_lease_bind("memory:0");
+    let _handle = mux_server::run(ALLOCATOR_ADDR, service).await.unwrap();
+    let mut allocator: AllocatorClientService<Labels, Board, ()> =
+        AllocatorClientService::new(ALLOCATOR_ADDR).await.unwrap();
+
+    // Asked for by some of its labels, the lease is counted against all of them,
+    // like the resource itself is.
+    let _lease = allocator
+        .ready()
+        .await
+        .unwrap()
+        .call(labels("site=metrics"))
+        .await
+        .unwrap()
+        .unwrap();
+    let description = format!("{:?}", Labels::parse_literal(BOARD).unwrap()).replace('"', "\\\"");
+    let rendered = metrics::render();
+    assert!(
+        rendered.contains(&format!(
+            "leaning_tower_resource_utilization{{description=\"{description}\"}} 1"
+        )),
+        "{rendered}"
+    );
+}
@@ -0,0 +1,108 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::Future;
+use leaning_tower::{
+    allocator::AllocatorService, allocator_client::AllocatorClientService, mux_client::MuxClient,
+    mux_server, resource_filter::Describable,
+};
+use tower::{BoxError, Service, ServiceExt};
+
+struct Uppercase;
+
+impl Service<String> for Uppercase {
+    type Response = String;
+    type Error = BoxError;
+    #[allow(clippy::type_complexity)]
+    type Future =
+        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + 'static + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Ok(()).into()
+    }
+
+    fn call(&mut self, req: String) -> Self::Future {
+        Box::pin(async move { Ok(req.to_ascii_uppercase()) })
+    }
+}
+
+impl Describable<usize> for Uppercase {
+    fn describe(&self) -> usize {
+        0
+    }
+}
+
+#[tokio::test]
+async fn test_memory_server() {
+    const SERVER_ADDR: &str = "memory:uppercase";
+
+    let _handle = mux_server::run(SERVER_ADDR, Uppercase).await.unwrap();
+    assert!(mux_server::run(SERVER_ADDR, Uppercase).await.is_err());
+
+    let mut client: MuxClient<String, String> = MuxClient::new(SERVER_ADDR).await.unwrap();
+    let response = client
+        .ready()
+        .await
+        .unwrap()
+        .call("hello".to_string())
+        .await
+        .unwrap();
+    assert_eq!(response, "HELLO");
+
+    assert!(MuxClient::<String, String>::new("memory:nobody")
+        .await
+        .is_err());
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_memory_allocator_paused() {
+    const SERVER_ADDR: &str = "memory:allocator";
+
+    let service = AllocatorService::new(vec![Uppercase]).with_lease_bind("memory:0");
+    let _handle = mux_server::run(SERVER_ADDR, service).await.unwrap();
+
+    let allocator: AllocatorClientService<_, Uppercase, String> =
+        AllocatorClientService::new(SERVER_ADDR).await.unwrap();
+    let allocate = || {
+        let mut allocator = allocator.clone();
+        async move {
+            allocator
+                .ready()
+                .await
+                .unwrap()
+                .call(0usize)
+                .await
+                .unwrap()
+                .unwrap()
+        }
+    };
+
+    let mut lease = allocate().await;
+    let waiting = tokio::spawn(allocate());
+
+    // An hour passes in no time, and the lease is held all along.
+    tokio::time::sleep(Duration::from_secs(3600)).await;
+    assert!(!waiting.is_finished());
+    let response = lease
+        .ready()
+        .await
+        .unwrap()
+        .call("still mine".to_string())
+        .await
+        .unwrap();
+    assert_eq!(response, "STILL MINE");
+
+    drop(lease);
+    let mut lease = waiting.await.unwrap();
+    let response = lease
+        .ready()
+        .await
+        .unwrap()
+        .call("mine now".to_string())
+        .await
+        .unwrap();
+    assert_eq!(response, "MINE NOW");
+}
@@ -0,0 +1,135 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::Future;
+use leaning_tower::{
+    allocator::AllocatorService, allocator_client::AllocatorClientService, metrics, mux_server,
+    resource_filter::Describable,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tower::{BoxError, Service, ServiceExt};
+
+const SERVER_ADDR: &str = "0.0.0.0:5569";
+const METRICS_ADDR: &str = "127.0.0.1:5570";
+
+struct EchoService(usize);
+
+impl Service<String> for EchoService {
+    type Response = String;
+    type Error = BoxError;
+    #[allow(clippy::type_complexity)]
+    type Future =
+        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + 'static + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Ok(()).into()
+    }
+
+    fn call(&mut self, req: String) -> Self::Future {
+        Box::pin(async move { Ok(req) })
+    }
+}
+
+impl Describable<usize> for EchoService {
+    fn describe(&self) -> usize {
+        self.0
+    }
+}
+
+#[tokio::test]
+async fn test_metrics_endpoint() {
+    let service = AllocatorService::new(vec![EchoService(7), EchoService(7)]);
+    let _handle = mux_server::run(SERVER_ADDR, service).await.unwrap();
+    let _metrics = metrics::serve(METRICS_ADDR).await.unwrap();
+
+    let mut allocator: AllocatorClientService<_, EchoService, _> =
+        AllocatorClientService::new_labelled(SERVER_ADDR, "metrics-test")
+            .await
+            .unwrap();
+    let mut svc = allocator
+        .ready()
+        .await
+        .unwrap()
+        .call(7usize)
+        .await
+        .unwrap()
+        .unwrap();
+    svc.ready()
+        .await
+        .unwrap()
+        .call("hello".to_string())
+        .await
+        .unwrap();
+
+    let mut stream = tokio::net::TcpStream::connect(METRICS_ADDR).await.unwrap();
+    stream
+        .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.contains("leaning_tower_allocation_requests_total{description=\"7\"} 1"));
+    assert!(response.contains("leaning_tower_resources_leased{description=\"7\"} 1"));
+    assert!(response.contains("leaning_tower_resource_utilization{description=\"7\"} 0.5"));
+    assert!(response.contains("leaning_tower_allocation_wait_seconds_count{description=\"7\"} 1"));
+    assert!(response.contains("# TYPE leaning_tower_mux_request_duration_seconds histogram"));
+    assert!(
+        response.contains("leaning_tower_mux_sent_bytes_total{side=\"client\",transport=\"tcp\"}")
+    );
+}
+
+#[tokio::test]
+async fn test_dropped_allocator_resources() {
+    let service = AllocatorService::<_, String, _>::new(vec![EchoService(9), EchoService(9)]);
+    assert!(metrics::render().contains("leaning_tower_resources{description=\"9\"} 2"));
+
+    drop(service);
+    assert!(metrics::render().contains("leaning_tower_resources{description=\"9\"} 0"));
+}
+
+#[tokio::test]
+async fn test_queue_depth() {
+    const ALLOCATOR_ADDR: &str = "memory:metrics-queue";
+    let depth =
+        |n: usize| format!("leaning_tower_allocation_queue_depth{{description=\"11\"}} {n}");
+
+    let service = AllocatorService::new(vec![EchoService(11)]).with_lease_bind("memory:0");
+    let _handle = mux_server::run(ALLOCATOR_ADDR, service).await.unwrap();
+    let allocator: AllocatorClientService<_, EchoService, String> =
+        AllocatorClientService::new(ALLOCATOR_ADDR).await.unwrap();
+
+    // Granted right away, so it never waited.
+    let lease = allocator
+        .clone()
+        .ready()
+        .await
+        .unwrap()
+        .call(11usize)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(metrics::render().contains(&depth(0)));
+
+    let mut waiting = allocator.clone();
+    let waiting = tokio::spawn(async move {
+        waiting
+            .ready()
+            .await
+            .unwrap()
+            .call(11usize)
+            .await
+            .unwrap()
+            .unwrap()
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(metrics::render().contains(&depth(1)));
+
+    drop(lease);
+    let _lease = waiting.await.unwrap();
+    assert!(metrics::render().contains(&depth(0)));
+}
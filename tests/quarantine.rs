@@ -0,0 +1,110 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::{Future, StreamExt};
+use leaning_tower::{
+    allocator::{AllocatorError, AllocatorEvent, AllocatorService},
+    allocator_client::AllocatorClientService,
+    mux_server,
+    resource_filter::Describable,
+};
+use tokio::net::TcpListener;
+use tower::{BoxError, Service, ServiceExt};
+
+const SERVER_ADDR: &str = "memory:quarantine";
+const LEASE_ADDR: &str = "127.0.0.1:5597";
+
+#[derive(Clone)]
+struct Printer;
+
+impl Service<()> for Printer {
+    type Response = ();
+    type Error = BoxError;
+    #[allow(clippy::type_complexity)]
+    type Future =
+        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + 'static + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Ok(()).into()
+    }
+
+    fn call(&mut self, _req: ()) -> Self::Future {
+        Box::pin(async move { Ok(()) })
+    }
+}
+
+impl Describable<usize> for Printer {
+    fn describe(&self) -> usize {
+        0
+    }
+
+    fn id(&self) -> Option<String> {
+        Some("printer".to_string())
+    }
+}
+
+#[tokio::test]
+async fn test_lease_server_failure() {
+    // Leases can't be served while something else holds the port.
+    let squatter = TcpListener::bind(LEASE_ADDR).await.unwrap();
+
+    let service = AllocatorService::new(vec![Printer])
+        .with_lease_bind(LEASE_ADDR)
+        .with_lease_retries(2)
+        .with_quarantine(Duration::from_millis(300));
+    let _handle = mux_server::run(SERVER_ADDR, service).await.unwrap();
+
+    let allocator: AllocatorClientService<_, Printer, ()> =
+        AllocatorClientService::new(SERVER_ADDR).await.unwrap();
+    let mut events = Box::pin(allocator.subscribe(None).await.unwrap());
+
+    // The client is told, after the retries are used up.
+    let failed = allocator
+        .clone()
+        .ready()
+        .await
+        .unwrap()
+        .call(0usize)
+        .await
+        .map_err(|e| e.downcast::<AllocatorError>());
+    assert!(
+        matches!(&failed, Err(Ok(e)) if matches!(**e, AllocatorError::ListenerProblem(_))),
+        "{failed:?}"
+    );
+    drop(squatter);
+
+    let quarantined = tokio::time::timeout(Duration::from_secs(1), async {
+        loop {
+            match events.next().await.unwrap().unwrap() {
+                AllocatorEvent::Quarantined { resource, .. } => break resource,
+                _ => continue,
+            }
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(quarantined, "printer");
+
+    // The resource sits out the quarantine, even though it could be served again.
+    let allocate = || async {
+        allocator
+            .clone()
+            .ready()
+            .await
+            .unwrap()
+            .call(0usize)
+            .await
+            .unwrap()
+            .unwrap()
+    };
+    assert!(tokio::time::timeout(Duration::from_millis(100), allocate())
+        .await
+        .is_err());
+    let mut lease = tokio::time::timeout(Duration::from_secs(1), allocate())
+        .await
+        .unwrap();
+    lease.ready().await.unwrap().call(()).await.unwrap();
+}
@@ -0,0 +1,180 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::Future;
+use leaning_tower::{
+    allocator::{
+        Affinity, Allocation, AllocationOptions, AllocatorError, AllocatorRequest, AllocatorService,
+    },
+    allocator_client::AllocatorClientService,
+    mux_client::MuxClient,
+    mux_server,
+    resource_filter::Describable,
+};
+use tokio::sync::mpsc;
+use tower::{BoxError, Service, ServiceExt};
+
+struct Printer(usize);
+
+impl Service<()> for Printer {
+    type Response = ();
+    type Error = BoxError;
+    #[allow(clippy::type_complexity)]
+    type Future =
+        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + 'static + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Ok(()).into()
+    }
+
+    fn call(&mut self, _req: ()) -> Self::Future {
+        Box::pin(async move { Ok(()) })
+    }
+}
+
+impl Describable<usize> for Printer {
+    fn describe(&self) -> usize {
+        self.0
+    }
+}
+
+type Client = AllocatorClientService<usize, Printer, ()>;
+
+async fn allocate(allocator: &Client, description: usize) -> Result<MuxClient<(), ()>, BoxError> {
+    let mut allocator = allocator.clone();
+    Ok(allocator
+        .ready()
+        .await?
+        .call(description)
+        .await?
+        .expect("matching resource"))
+}
+
+fn is_queue_full(result: &Result<MuxClient<(), ()>, BoxError>) -> bool {
+    matches!(
+        result
+            .as_ref()
+            .map_err(|e| e.downcast_ref::<AllocatorError>()),
+        Err(Some(AllocatorError::QueueFull))
+    )
+}
+
+/// Start waiting for an allocation in the background.
+async fn wait_for(allocator: &Client, description: usize) {
+    let allocator = allocator.clone();
+    tokio::spawn(async move {
+        let _lease = allocate(&allocator, description).await;
+    });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+}
+
+#[tokio::test]
+async fn test_max_waiters() {
+    const SERVER_ADDR: &str = "0.0.0.0:5581";
+
+    let service = AllocatorService::new(vec![Printer(0), Printer(1)]).with_max_waiters(1);
+    let _handle = mux_server::run(SERVER_ADDR, service).await.unwrap();
+    let allocator = Client::new(SERVER_ADDR).await.unwrap();
+
+    let _first = allocate(&allocator, 0).await.unwrap();
+    wait_for(&allocator, 0).await;
+
+    // The queue is full, but allocations which don't have to wait are fine.
+    assert!(is_queue_full(&allocate(&allocator, 0).await));
+    let _other = allocate(&allocator, 1).await.unwrap();
+    assert!(is_queue_full(&allocate(&allocator, 1).await));
+}
+
+#[tokio::test]
+async fn test_max_waiters_per_description() {
+    const SERVER_ADDR: &str = "0.0.0.0:5582";
+
+    let service =
+        AllocatorService::new(vec![Printer(0), Printer(1)]).with_max_waiters_per_description(1);
+    let _handle = mux_server::run(SERVER_ADDR, service).await.unwrap();
+    let allocator = Client::new(SERVER_ADDR).await.unwrap();
+
+    let _first = allocate(&allocator, 0).await.unwrap();
+    let _second = allocate(&allocator, 1).await.unwrap();
+
+    wait_for(&allocator, 0).await;
+    assert!(is_queue_full(&allocate(&allocator, 0).await));
+
+    // Another description has a queue of its own.
+    wait_for(&allocator, 1).await;
+    assert!(is_queue_full(&allocate(&allocator, 1).await));
+}
+
+#[tokio::test]
+async fn test_burst() {
+    let mut service: AllocatorService<Printer, (), usize> = AllocatorService::new(vec![Printer(0)])
+        .with_lease_bind("memory:0")
+        .with_max_waiters(2);
+
+    // Asked for all at once, before any of them has been granted.
+    let mut burst = vec![];
+    for id in 0..10 {
+        let allocation = AllocatorRequest::Allocate(Allocation {
+            id,
+            description: 0,
+            client: None,
+            options: AllocationOptions::default(),
+        });
+        burst.push(service.ready().await.unwrap().call(allocation));
+    }
+
+    let (results, mut answered) = mpsc::unbounded_channel();
+    for allocation in burst {
+        let results = results.clone();
+        tokio::spawn(async move {
+            let queue_full = matches!(allocation.await, Ok(Err(AllocatorError::QueueFull)));
+            results.send(queue_full).unwrap();
+        });
+    }
+
+    // One is granted, two wait in the queue, and the rest are turned away.
+    let mut queue_full = 0;
+    for _ in 0..8 {
+        queue_full += usize::from(answered.recv().await.unwrap());
+    }
+    assert_eq!(queue_full, 7);
+    assert!(
+        tokio::time::timeout(Duration::from_millis(200), answered.recv())
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn test_waiting_for_preferred() {
+    const SERVER_ADDR: &str = "memory:queue-preferred";
+
+    let service = AllocatorService::new(vec![Printer(0), Printer(0), Printer(1)])
+        .with_lease_bind("memory:0")
+        .with_max_waiters(1);
+    let _handle = mux_server::run(SERVER_ADDR, service).await.unwrap();
+    let allocator = Client::new(SERVER_ADDR).await.unwrap();
+
+    // The queue is full of someone waiting for another description.
+    let _other = allocate(&allocator, 1).await.unwrap();
+    wait_for(&allocator, 1).await;
+
+    let first = allocate(&allocator, 0).await.unwrap();
+    let preferring_first = AllocationOptions {
+        affinity: Some(Affinity {
+            resource: first.resource().unwrap().to_string(),
+            fallback_after: Duration::from_secs(10),
+        }),
+        ..Default::default()
+    };
+
+    // Another one is free, but waiting for the preferred one is waiting all the same.
+    let preferring = allocator
+        .allocate_with(0, preferring_first)
+        .await
+        .map(|lease| lease.unwrap());
+    assert!(is_queue_full(&preferring));
+}
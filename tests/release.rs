@@ -0,0 +1,67 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures::Future;
+use leaning_tower::{
+    allocator::AllocatorService, allocator_client::AllocatorClientService, mux_client::MuxClient,
+    mux_server, resource_filter::Describable,
+};
+use tower::{BoxError, Service, ServiceExt};
+
+const SERVER_ADDR: &str = "0.0.0.0:5586";
+
+struct Printer;
+
+impl Service<()> for Printer {
+    type Response = ();
+    type Error = BoxError;
+    #[allow(clippy::type_complexity)]
+    type Future =
+        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + 'static + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Ok(()).into()
+    }
+
+    fn call(&mut self, _req: ()) -> Self::Future {
+        Box::pin(async move { Ok(()) })
+    }
+}
+
+impl Describable<usize> for Printer {
+    fn describe(&self) -> usize {
+        0
+    }
+}
+
+#[tokio::test]
+async fn test_release() {
+    let service = AllocatorService::new(vec![Printer]);
+    let _handle = mux_server::run(SERVER_ADDR, service).await.unwrap();
+
+    let mut allocator: AllocatorClientService<_, Printer, ()> =
+        AllocatorClientService::new(SERVER_ADDR).await.unwrap();
+
+    for _ in 0..3 {
+        let mut lease = allocator
+            .ready()
+            .await
+            .unwrap()
+            .call(0usize)
+            .await
+            .unwrap()
+            .unwrap();
+        lease.ready().await.unwrap().call(()).await.unwrap();
+        assert_eq!(allocator.descriptions().await.unwrap()[0].free, 0);
+
+        // No need to wait for the allocator to notice.
+        lease.release().await.unwrap();
+        assert_eq!(allocator.descriptions().await.unwrap()[0].free, 1);
+    }
+
+    // Only leases can be released.
+    let client: MuxClient<usize, ()> = MuxClient::new(SERVER_ADDR).await.unwrap();
+    assert!(client.release().await.is_err());
+}
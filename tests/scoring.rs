@@ -0,0 +1,134 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::Future;
+use leaning_tower::{
+    allocator::{AllocationOptions, AllocatorService, MatchPolicy},
+    allocator_client::AllocatorClientService,
+    mux_server,
+    resource_filter::Describable,
+};
+use tower::{BoxError, Service, ServiceExt};
+
+const SERVER_ADDR: &str = "0.0.0.0:5578";
+
+/// A machine with some amount of memory, in GB.
+struct Machine(usize);
+
+impl Service<()> for Machine {
+    type Response = ();
+    type Error = BoxError;
+    #[allow(clippy::type_complexity)]
+    type Future =
+        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + 'static + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Ok(()).into()
+    }
+
+    fn call(&mut self, _req: ()) -> Self::Future {
+        Box::pin(async move { Ok(()) })
+    }
+}
+
+impl Describable<usize> for Machine {
+    fn describe(&self) -> usize {
+        self.0
+    }
+
+    fn id(&self) -> Option<String> {
+        Some(format!("{}gb", self.0))
+    }
+
+    /// Enough memory is acceptable, the less excess the better.
+    fn score(description: &usize, request: &usize) -> Option<u32> {
+        let excess = description.checked_sub(*request)?;
+        Some(u32::MAX - excess as u32)
+    }
+}
+
+fn policy(match_policy: MatchPolicy) -> AllocationOptions {
+    AllocationOptions {
+        match_policy: Some(match_policy),
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn test_best_match() {
+    let service = AllocatorService::new(vec![Machine(32), Machine(8), Machine(16)]);
+    let _handle = mux_server::run(SERVER_ADDR, service).await.unwrap();
+
+    let mut allocator: AllocatorClientService<_, Machine, _> =
+        AllocatorClientService::new(SERVER_ADDR).await.unwrap();
+
+    // The closest fit is granted, even though others match too.
+    let best = allocator
+        .ready()
+        .await
+        .unwrap()
+        .call(10usize)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(best.resource(), Some("16gb"));
+
+    // The closest fit is busy, by default an acceptable one is taken right away.
+    let acceptable = allocator
+        .ready()
+        .await
+        .unwrap()
+        .call(10usize)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(acceptable.resource(), Some("32gb"));
+    drop(acceptable);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    // Insisting on the best match means waiting for it.
+    let best_only = allocator.allocate_with(10usize, policy(MatchPolicy::BestOnly));
+    assert!(tokio::time::timeout(Duration::from_millis(200), best_only)
+        .await
+        .is_err());
+
+    // Waiting a while for the best match, but settling when it's not freed.
+    let settled = allocator
+        .allocate_with(
+            10usize,
+            policy(MatchPolicy::PreferBest {
+                settle_after: Duration::from_millis(100),
+            }),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(settled.resource(), Some("32gb"));
+    drop(settled);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    // Waiting a while for the best match, which is freed in time.
+    let waiting = tokio::spawn(allocator.allocate_with(
+        10usize,
+        policy(MatchPolicy::PreferBest {
+            settle_after: Duration::from_secs(5),
+        }),
+    ));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    drop(best);
+    let best = waiting.await.unwrap().unwrap().unwrap();
+    assert_eq!(best.resource(), Some("16gb"));
+
+    // Nothing has enough memory.
+    let none = allocator
+        .ready()
+        .await
+        .unwrap()
+        .call(64usize)
+        .await
+        .unwrap();
+    assert!(none.is_none());
+}
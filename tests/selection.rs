@@ -0,0 +1,138 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::{Future, StreamExt};
+use leaning_tower::{
+    allocator::{AllocatorEvent, AllocatorService},
+    allocator_client::AllocatorClientService,
+    mux_server,
+    resource_filter::Describable,
+    selection::{
+        FirstFree, LeastRecentlyUsed, LeastTotalUsage, Random, RoundRobin, SelectionStrategy,
+    },
+};
+use tower::{BoxError, Service, ServiceExt};
+
+struct Printer;
+
+impl Service<()> for Printer {
+    type Response = ();
+    type Error = BoxError;
+    #[allow(clippy::type_complexity)]
+    type Future =
+        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + 'static + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Ok(()).into()
+    }
+
+    fn call(&mut self, _req: ()) -> Self::Future {
+        Box::pin(async move { Ok(()) })
+    }
+}
+
+impl Describable<usize> for Printer {
+    fn describe(&self) -> usize {
+        0
+    }
+}
+
+/// Allocate one resource at a time, holding each for the given number of seconds,
+/// returning the order they were handed out in.
+async fn allocation_order(
+    addr: &str,
+    strategy: impl SelectionStrategy + 'static,
+    holds: &[u64],
+) -> Vec<String> {
+    let service = AllocatorService::new_with_strategy(vec![Printer, Printer, Printer], strategy)
+        .with_lease_bind("memory:0");
+    let _handle = mux_server::run(addr, service).await.unwrap();
+
+    let mut allocator: AllocatorClientService<_, Printer, _> =
+        AllocatorClientService::new(addr).await.unwrap();
+    let mut events = Box::pin(allocator.subscribe(None).await.unwrap());
+
+    let mut order = vec![];
+    for hold in holds {
+        let lease = allocator
+            .ready()
+            .await
+            .unwrap()
+            .call(0usize)
+            .await
+            .unwrap()
+            .unwrap();
+        order.push(lease.resource().unwrap().to_string());
+        tokio::time::sleep(Duration::from_secs(*hold)).await;
+        drop(lease);
+
+        // Let the allocator notice the lease ended.
+        while !matches!(
+            events.next().await.unwrap().unwrap(),
+            AllocatorEvent::Released { .. }
+        ) {}
+    }
+
+    order
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_first_free() {
+    assert_eq!(
+        allocation_order("memory:selection-first-free", FirstFree, &[1, 1, 1]).await,
+        ["0", "0", "0"]
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_round_robin() {
+    assert_eq!(
+        allocation_order(
+            "memory:selection-round-robin",
+            RoundRobin::default(),
+            &[1, 1, 1, 1]
+        )
+        .await,
+        ["0", "1", "2", "0"]
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_least_recently_used() {
+    assert_eq!(
+        allocation_order(
+            "memory:selection-least-recently-used",
+            LeastRecentlyUsed,
+            &[1, 1, 1, 1]
+        )
+        .await,
+        ["0", "1", "2", "0"]
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_least_total_usage() {
+    // Held 10, 5 and 1 seconds, after which the last one is the least used,
+    // until it's held for another 10.
+    assert_eq!(
+        allocation_order(
+            "memory:selection-least-total-usage",
+            LeastTotalUsage,
+            &[10, 5, 1, 10, 1]
+        )
+        .await,
+        ["0", "1", "2", "2", "1"]
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_random() {
+    // Each resource is left out by chance once in about 40 billion runs.
+    let order = allocation_order("memory:selection-random", Random, &[0; 60]).await;
+    for resource in ["0", "1", "2"] {
+        assert!(order.iter().any(|chosen| chosen == resource), "{order:?}");
+    }
+}
@@ -0,0 +1,129 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::Future;
+use leaning_tower::{
+    allocator::{AllocatorError, AllocatorService, CloseReason},
+    allocator_client::AllocatorClientService,
+    mux_client::MuxClient,
+    mux_server,
+    resource_filter::Describable,
+};
+use tower::{BoxError, Service, ServiceExt};
+
+const SERVER_ADDR: &str = "0.0.0.0:5583";
+
+struct Printer;
+
+impl Service<()> for Printer {
+    type Response = ();
+    type Error = BoxError;
+    #[allow(clippy::type_complexity)]
+    type Future =
+        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + 'static + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Ok(()).into()
+    }
+
+    fn call(&mut self, _req: ()) -> Self::Future {
+        Box::pin(async move { Ok(()) })
+    }
+}
+
+impl Describable<usize> for Printer {
+    fn describe(&self) -> usize {
+        0
+    }
+}
+
+type Client = AllocatorClientService<usize, Printer, ()>;
+
+async fn allocate(allocator: &Client) -> Result<MuxClient<(), ()>, BoxError> {
+    let mut allocator = allocator.clone();
+    Ok(allocator
+        .ready()
+        .await?
+        .call(0usize)
+        .await?
+        .expect("matching resource"))
+}
+
+fn is_shutting_down(result: Result<MuxClient<(), ()>, BoxError>) -> bool {
+    matches!(
+        result.map_err(|e| e.downcast::<AllocatorError>()),
+        Err(Ok(e)) if matches!(*e, AllocatorError::ShuttingDown)
+    )
+}
+
+async fn use_lease(lease: &mut MuxClient<(), ()>) -> Result<(), BoxError> {
+    lease.ready().await?.call(()).await
+}
+
+#[tokio::test]
+async fn test_graceful_shutdown() {
+    let service = AllocatorService::new(vec![Printer, Printer]);
+    let shutdown = service.shutdown_handle();
+    let _handle = mux_server::run(SERVER_ADDR, service).await.unwrap();
+    let allocator = Client::new(SERVER_ADDR).await.unwrap();
+
+    let mut long_lived = allocate(&allocator).await.unwrap();
+    let short_lived = allocate(&allocator).await.unwrap();
+    use_lease(&mut long_lived).await.unwrap();
+
+    let waiting = tokio::spawn({
+        let allocator = allocator.clone();
+        async move { allocate(&allocator).await }
+    });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let shutting_down = tokio::spawn({
+        let shutdown = shutdown.clone();
+        async move { shutdown.shutdown(Duration::from_millis(300)).await }
+    });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(shutdown.is_shutting_down());
+
+    // Waiting and new allocations are told about the shutdown.
+    assert!(is_shutting_down(waiting.await.unwrap()));
+    assert!(is_shutting_down(allocate(&allocator).await));
+
+    // Active leases may be used during the grace period.
+    use_lease(&mut long_lived).await.unwrap();
+    drop(short_lived);
+
+    // The lease which did not finish in time is closed.
+    assert_eq!(shutting_down.await.unwrap(), 1);
+    let e = use_lease(&mut long_lived).await.unwrap_err();
+    assert!(matches!(
+        e.downcast_ref::<AllocatorError>(),
+        Some(AllocatorError::LeaseClosed(CloseReason::ShuttingDown))
+    ));
+}
+
+#[tokio::test]
+async fn test_rebind_after_shutdown() {
+    const ALLOCATOR_ADDR: &str = "127.0.0.1:5576";
+
+    let service = AllocatorService::new(vec![Printer]);
+    let shutdown = service.shutdown_handle();
+    let handle = mux_server::run(ALLOCATOR_ADDR, service).await.unwrap();
+    let allocator = Client::new(ALLOCATOR_ADDR).await.unwrap();
+    drop(allocate(&allocator).await.unwrap());
+
+    // The listener is only closed by aborting it.
+    assert_eq!(shutdown.shutdown(Duration::from_millis(100)).await, 0);
+    handle.abort();
+    assert!(handle.await.unwrap_err().is_cancelled());
+
+    let _handle = mux_server::run(ALLOCATOR_ADDR, AllocatorService::new(vec![Printer]))
+        .await
+        .unwrap();
+    let allocator = Client::new(ALLOCATOR_ADDR).await.unwrap();
+    use_lease(&mut allocate(&allocator).await.unwrap())
+        .await
+        .unwrap();
+}
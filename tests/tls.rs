@@ -0,0 +1,318 @@
+#![cfg(feature = "tls")]
+
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+};
+
+use futures::Future;
+use leaning_tower::{
+    allocator::{AllocatorError, AllocatorService},
+    allocator_client::AllocatorClientService,
+    allocator_federation::FederatedAllocatorClientService,
+    mux_client::MuxClient,
+    mux_server,
+    resource_filter::Describable,
+    tls::{TlsClient, TlsServer},
+    transport::{PeerIdentity, TransportConfig},
+};
+use tower::{BoxError, Service, ServiceExt};
+
+/// Answers with the common name of the client, as seen by the server.
+#[derive(Clone)]
+struct WhoAmI;
+
+impl Service<()> for WhoAmI {
+    type Response = Option<String>;
+    type Error = BoxError;
+    #[allow(clippy::type_complexity)]
+    type Future =
+        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + 'static + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Ok(()).into()
+    }
+
+    fn call(&mut self, _req: ()) -> Self::Future {
+        Box::pin(async move { Ok(PeerIdentity::current().and_then(|peer| peer.common_name)) })
+    }
+}
+
+impl Describable<usize> for WhoAmI {
+    fn describe(&self) -> usize {
+        0
+    }
+}
+
+fn cert(name: &str) -> String {
+    format!("{}/tests/certs/{name}", env!("CARGO_MANIFEST_DIR"))
+}
+
+fn server_config(mutual: bool) -> TransportConfig {
+    let mut tls = TlsServer::from_pem(cert("server.pem"), cert("server.key")).unwrap();
+    if mutual {
+        tls = tls.with_client_ca(cert("ca.pem")).unwrap();
+    }
+    TransportConfig::new().with_tls_server(tls)
+}
+
+fn client_config(identity: Option<&str>) -> TransportConfig {
+    let mut tls = TlsClient::from_pem(cert("ca.pem")).unwrap();
+    if let Some(identity) = identity {
+        tls = tls
+            .with_identity(
+                cert(&format!("{identity}.pem")),
+                cert(&format!("{identity}.key")),
+            )
+            .unwrap();
+    }
+    TransportConfig::new().with_tls_client(tls)
+}
+
+async fn who_am_i(client: &mut MuxClient<(), Option<String>>) -> Result<Option<String>, BoxError> {
+    client.ready().await?.call(()).await
+}
+
+#[tokio::test]
+async fn test_tls_server() {
+    const SERVER_ADDR: &str = "127.0.0.1:5590";
+
+    let _handle = mux_server::run_with(SERVER_ADDR, WhoAmI, &server_config(false))
+        .await
+        .unwrap();
+
+    // The certificate is checked against the address connected to.
+    let mut client = MuxClient::new_with(SERVER_ADDR, &client_config(None))
+        .await
+        .unwrap();
+    assert_eq!(who_am_i(&mut client).await.unwrap(), None);
+
+    // Servers signed by some other CA are not trusted.
+    let untrusted =
+        TransportConfig::new().with_tls_client(TlsClient::from_pem(cert("intruder.pem")).unwrap());
+    assert!(
+        MuxClient::<(), Option<String>>::new_with(SERVER_ADDR, &untrusted)
+            .await
+            .is_err()
+    );
+
+    // Nor is a certificate for another name.
+    let misnamed = TransportConfig::new().with_tls_client(
+        TlsClient::from_pem(cert("ca.pem"))
+            .unwrap()
+            .with_server_name("printer.example")
+            .unwrap(),
+    );
+    assert!(
+        MuxClient::<(), Option<String>>::new_with(SERVER_ADDR, &misnamed)
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn test_mutual_tls() {
+    const SERVER_ADDR: &str = "memory:tls-mutual";
+
+    let _handle = mux_server::run_with(SERVER_ADDR, WhoAmI, &server_config(true))
+        .await
+        .unwrap();
+
+    let mut client = MuxClient::new_with(SERVER_ADDR, &client_config(Some("client")))
+        .await
+        .unwrap();
+    assert_eq!(
+        who_am_i(&mut client).await.unwrap().as_deref(),
+        Some("build-bot")
+    );
+
+    // The server rejects clients it does not trust, which they may only notice
+    // once they send something.
+    for identity in [None, Some("intruder")] {
+        let rejected = async {
+            let mut client = MuxClient::new_with(SERVER_ADDR, &client_config(identity)).await?;
+            who_am_i(&mut client).await
+        };
+        assert!(rejected.await.is_err(), "{identity:?} was let in");
+    }
+}
+
+#[tokio::test]
+async fn test_allocator_access_policy() {
+    const SERVER_ADDR: &str = "memory:tls-allocator";
+
+    let seen = Arc::new(Mutex::new(vec![]));
+    let service = AllocatorService::new(vec![WhoAmI])
+        .with_lease_bind("memory:0")
+        .with_lease_transport(server_config(true))
+        .with_access_policy({
+            let seen = seen.clone();
+            move |peer, description| {
+                let name = peer.and_then(|peer| peer.common_name.clone());
+                seen.lock().unwrap().push(name.clone());
+                name.as_deref() == Some("build-bot") && *description == 0
+            }
+        });
+    let _handle = mux_server::run_with(SERVER_ADDR, service, &server_config(true))
+        .await
+        .unwrap();
+
+    let allocator: AllocatorClientService<_, WhoAmI, ()> =
+        AllocatorClientService::new_with(SERVER_ADDR, client_config(Some("client")))
+            .await
+            .unwrap();
+
+    // The lease is served over mutual TLS too.
+    let mut lease = allocator
+        .clone()
+        .ready()
+        .await
+        .unwrap()
+        .call(0usize)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        who_am_i(&mut lease).await.unwrap().as_deref(),
+        Some("build-bot")
+    );
+
+    let denied = allocator
+        .clone()
+        .ready()
+        .await
+        .unwrap()
+        .call(1usize)
+        .await
+        .map_err(|e| e.downcast::<AllocatorError>());
+    assert!(matches!(denied, Err(Ok(e)) if matches!(*e, AllocatorError::AccessDenied)));
+
+    assert_eq!(
+        *seen.lock().unwrap(),
+        vec![Some("build-bot".to_string()), Some("build-bot".to_string())]
+    );
+}
+
+#[tokio::test]
+async fn test_access_policy_on_leases() {
+    const SERVER_ADDR: &str = "memory:tls-lease-access";
+
+    let revoked = Arc::new(AtomicBool::new(false));
+    let service = AllocatorService::new(vec![WhoAmI])
+        .with_lease_bind("memory:0")
+        .with_lease_transport(server_config(true))
+        .with_access_policy({
+            let revoked = revoked.clone();
+            move |_, _| !revoked.load(Ordering::SeqCst)
+        });
+    let _handle = mux_server::run_with(SERVER_ADDR, service, &server_config(true))
+        .await
+        .unwrap();
+
+    let allocator: AllocatorClientService<_, WhoAmI, ()> =
+        AllocatorClientService::new_with(SERVER_ADDR, client_config(Some("client")))
+            .await
+            .unwrap();
+    let allocate = || async {
+        revoked.store(false, Ordering::SeqCst);
+        let lease = allocator
+            .clone()
+            .ready()
+            .await
+            .unwrap()
+            .call(0usize)
+            .await
+            .unwrap()
+            .unwrap();
+        revoked.store(true, Ordering::SeqCst);
+        lease
+    };
+    let is_denied = |e: BoxError| matches!(e.downcast::<AllocatorError>(), Ok(e) if matches!(*e, AllocatorError::AccessDenied));
+
+    let released = allocate().await.release().await;
+    assert!(released.is_err_and(is_denied));
+
+    let transferred = allocate().await.transfer().await;
+    assert!(transferred.is_err_and(is_denied));
+
+    let lease = allocate().await;
+    revoked.store(false, Ordering::SeqCst);
+    let ticket = lease.transfer().await.unwrap();
+    revoked.store(true, Ordering::SeqCst);
+    let redeemed = allocator.redeem(&ticket).await;
+    assert!(redeemed.is_err_and(is_denied));
+
+    // The ticket is kept for a client which may redeem it.
+    revoked.store(false, Ordering::SeqCst);
+    let mut lease = allocator.redeem(&ticket).await.unwrap();
+    assert_eq!(
+        who_am_i(&mut lease).await.unwrap().as_deref(),
+        Some("build-bot")
+    );
+}
+
+#[tokio::test]
+async fn test_tls_lease_over_tcp() {
+    const SERVER_ADDR: &str = "127.0.0.1:5600";
+
+    let service = AllocatorService::new(vec![WhoAmI])
+        .with_lease_bind("127.0.0.1:0")
+        .with_lease_transport(server_config(false));
+    let _handle = mux_server::run_with(SERVER_ADDR, service, &server_config(false))
+        .await
+        .unwrap();
+
+    // The lease is found on the allocator's host, and its certificate checked against that.
+    let mut allocator: AllocatorClientService<_, WhoAmI, ()> =
+        AllocatorClientService::new_with(SERVER_ADDR, client_config(None))
+            .await
+            .unwrap();
+    let mut lease = allocator
+        .ready()
+        .await
+        .unwrap()
+        .call(0usize)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(who_am_i(&mut lease).await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn test_tls_federation() {
+    const ALLOCATOR_A: &str = "memory:tls-federation-a";
+    const ALLOCATOR_B: &str = "memory:tls-federation-b";
+
+    for addr in [ALLOCATOR_A, ALLOCATOR_B] {
+        let service = AllocatorService::new(vec![WhoAmI])
+            .with_lease_bind("memory:0")
+            .with_lease_transport(server_config(true));
+        mux_server::run_with(addr, service, &server_config(true))
+            .await
+            .unwrap();
+    }
+
+    let mut federation: FederatedAllocatorClientService<_, WhoAmI, ()> =
+        FederatedAllocatorClientService::new_with(
+            &[ALLOCATOR_A, ALLOCATOR_B],
+            client_config(Some("client")),
+        )
+        .await
+        .unwrap();
+    let mut lease = federation
+        .ready()
+        .await
+        .unwrap()
+        .call(0usize)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        who_am_i(&mut lease).await.unwrap().as_deref(),
+        Some("build-bot")
+    );
+}
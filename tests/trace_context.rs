@@ -0,0 +1,151 @@
+use std::{
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use futures::Future;
+use leaning_tower::{mux_client::MuxClient, mux_server, tagged::TraceContext};
+use tower::{BoxError, Service, ServiceExt};
+use tracing::{info_span, span, Instrument, Subscriber};
+use tracing_subscriber::{layer::SubscriberExt, registry::LookupSpan, Layer};
+
+const SERVER_ADDR: &str = "0.0.0.0:5571";
+
+/// Responds with the trace the request was served within.
+struct TraceService;
+
+impl Service<()> for TraceService {
+    type Response = Option<u64>;
+    type Error = BoxError;
+    #[allow(clippy::type_complexity)]
+    type Future =
+        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + 'static + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Ok(()).into()
+    }
+
+    fn call(&mut self, _req: ()) -> Self::Future {
+        Box::pin(async move { Ok(TraceContext::current().map(|trace| trace.trace_id)) })
+    }
+}
+
+/// Asks another server, and responds with the trace it was served within
+/// along with the trace the other server saw.
+struct ForwardingService(&'static str);
+
+impl Service<()> for ForwardingService {
+    type Response = (Option<u64>, Option<u64>);
+    type Error = BoxError;
+    #[allow(clippy::type_complexity)]
+    type Future =
+        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + 'static + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Ok(()).into()
+    }
+
+    fn call(&mut self, _req: ()) -> Self::Future {
+        let addr = self.0;
+        Box::pin(async move {
+            let ours = TraceContext::current().map(|trace| trace.trace_id);
+            let mut client: MuxClient<(), Option<u64>> = MuxClient::new(addr).await?;
+            let theirs = client.ready().await?.call(()).await?;
+            Ok((ours, theirs))
+        })
+    }
+}
+
+/// Notes the parent of each span created.
+#[allow(clippy::type_complexity)]
+#[derive(Clone, Default)]
+struct Parents(Arc<Mutex<Vec<(&'static str, Option<&'static str>)>>>);
+
+impl<S> Layer<S> for Parents
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(
+        &self,
+        _attrs: &span::Attributes<'_>,
+        id: &span::Id,
+        ctx: tracing_subscriber::layer::Context<'_, S>,
+    ) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let parent = span.parent().map(|parent| parent.name());
+        self.0.lock().unwrap().push((span.name(), parent));
+    }
+}
+
+#[tokio::test]
+async fn test_trace_reaches_server() {
+    let parents = Parents::default();
+    let _guard = tracing::subscriber::set_default(
+        tracing_subscriber::registry()
+            .with(tracing_subscriber::fmt::layer())
+            .with(parents.clone()),
+    );
+
+    let _handle = mux_server::run(SERVER_ADDR, TraceService).await.unwrap();
+    let mut client: MuxClient<(), Option<u64>> = MuxClient::new(SERVER_ADDR).await.unwrap();
+
+    // Requests sent within the same trace are part of it.
+    let work = info_span!("work");
+    let trace = work.in_scope(TraceContext::start);
+    trace
+        .scope(async {
+            let ours = TraceContext::current().unwrap().trace_id;
+            let first = client.ready().await.unwrap().call(()).await.unwrap();
+            let second = client.ready().await.unwrap().call(()).await.unwrap();
+            assert_eq!(first, Some(ours));
+            assert_eq!(second, Some(ours));
+        })
+        .instrument(work)
+        .await;
+
+    // Root requests sent from within a span each start a trace of their own.
+    let (first, second) = async {
+        assert!(TraceContext::current().is_none());
+        let first = client.ready().await.unwrap().call(()).await.unwrap();
+        let second = client.ready().await.unwrap().call(()).await.unwrap();
+        (first, second)
+    }
+    .instrument(info_span!("work"))
+    .await;
+    assert_ne!(first, second);
+
+    // Each root request outside of any span starts its own trace.
+    let first = client.ready().await.unwrap().call(()).await.unwrap();
+    let second = client.ready().await.unwrap().call(()).await.unwrap();
+    assert!(first.is_some());
+    assert!(second.is_some());
+    assert_ne!(first, second);
+
+    // The client is in this process, so the server works within a child of its span.
+    assert!(parents
+        .0
+        .lock()
+        .unwrap()
+        .contains(&("remote-request", Some("mux-client-call"))));
+}
+
+#[tokio::test]
+async fn test_trace_propagated_untraced() {
+    const FIRST_ADDR: &str = "memory:trace-first";
+    const SECOND_ADDR: &str = "memory:trace-second";
+
+    // Nobody is tracing, the trace goes along all the same.
+    let _second = mux_server::run(SECOND_ADDR, TraceService).await.unwrap();
+    let _first = mux_server::run(FIRST_ADDR, ForwardingService(SECOND_ADDR))
+        .await
+        .unwrap();
+
+    let mut client: MuxClient<(), (Option<u64>, Option<u64>)> =
+        MuxClient::new(FIRST_ADDR).await.unwrap();
+    let (first, second) = client.ready().await.unwrap().call(()).await.unwrap();
+    assert!(first.is_some());
+    assert_eq!(first, second);
+}
@@ -0,0 +1,125 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::Future;
+use leaning_tower::{
+    allocator::{AllocatorError, AllocatorService, TransferTicket},
+    allocator_client::AllocatorClientService,
+    mux_client::MuxClient,
+    mux_server,
+    resource_filter::Describable,
+};
+use tower::{BoxError, Service, ServiceExt};
+
+struct Device;
+
+impl Service<()> for Device {
+    type Response = ();
+    type Error = BoxError;
+    #[allow(clippy::type_complexity)]
+    type Future =
+        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + 'static + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Ok(()).into()
+    }
+
+    fn call(&mut self, _req: ()) -> Self::Future {
+        Box::pin(async move { Ok(()) })
+    }
+}
+
+impl Describable<usize> for Device {
+    fn describe(&self) -> usize {
+        0
+    }
+
+    fn id(&self) -> Option<String> {
+        Some("dev".to_string())
+    }
+}
+
+type Client = AllocatorClientService<usize, Device, ()>;
+
+async fn allocate(allocator: &Client) -> MuxClient<(), ()> {
+    let mut allocator = allocator.clone();
+    allocator
+        .ready()
+        .await
+        .unwrap()
+        .call(0usize)
+        .await
+        .unwrap()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_transfer() {
+    const SERVER_ADDR: &str = "0.0.0.0:5584";
+
+    let service = AllocatorService::new(vec![Device]);
+    let _handle = mux_server::run(SERVER_ADDR, service).await.unwrap();
+
+    let build_step = Client::new_labelled(SERVER_ADDR, "build").await.unwrap();
+    let test_step = Client::new_labelled(SERVER_ADDR, "test").await.unwrap();
+    let bystander = Client::new_labelled(SERVER_ADDR, "bystander")
+        .await
+        .unwrap();
+
+    let lease = allocate(&build_step).await;
+    let waiting = tokio::spawn(async move { allocate(&bystander).await });
+
+    // The ticket survives being passed around as a string.
+    let ticket = lease.transfer().await.unwrap();
+    let ticket: TransferTicket = ticket.to_string().parse().unwrap();
+    assert_eq!(ticket.resource, "dev");
+
+    // The holder has left, but the resource is kept for the ticket.
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(!waiting.is_finished());
+
+    let mut lease = test_step.redeem(&ticket).await.unwrap();
+    assert_eq!(lease.resource(), Some("dev"));
+    lease.ready().await.unwrap().call(()).await.unwrap();
+
+    // A ticket is only good once.
+    let e = test_step.redeem(&ticket).await.unwrap_err();
+    assert!(matches!(
+        e.downcast_ref::<AllocatorError>(),
+        Some(AllocatorError::UnknownTicket)
+    ));
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(!waiting.is_finished());
+
+    // Released for real this time.
+    drop(lease);
+    let lease = tokio::time::timeout(Duration::from_secs(1), waiting)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(lease.resource(), Some("dev"));
+}
+
+#[tokio::test]
+async fn test_transfer_timeout() {
+    const SERVER_ADDR: &str = "0.0.0.0:5585";
+
+    let service =
+        AllocatorService::new(vec![Device]).with_transfer_timeout(Duration::from_millis(100));
+    let _handle = mux_server::run(SERVER_ADDR, service).await.unwrap();
+    let allocator = Client::new(SERVER_ADDR).await.unwrap();
+
+    let ticket = allocate(&allocator).await.transfer().await.unwrap();
+
+    // Nobody redeemed the ticket in time, so the resource is free again.
+    let lease = tokio::time::timeout(Duration::from_secs(1), allocate(&allocator))
+        .await
+        .unwrap();
+    assert_eq!(lease.resource(), Some("dev"));
+
+    assert!(allocator.redeem(&ticket).await.is_err());
+}
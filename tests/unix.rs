@@ -0,0 +1,82 @@
+#![cfg(unix)]
+
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures::Future;
+use leaning_tower::{
+    allocator::AllocatorService, allocator_client::AllocatorClientService, mux_server,
+    resource_filter::Describable,
+};
+use tower::{BoxError, Service, ServiceExt};
+
+struct Uppercase;
+
+impl Service<String> for Uppercase {
+    type Response = String;
+    type Error = BoxError;
+    #[allow(clippy::type_complexity)]
+    type Future =
+        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + 'static + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Ok(()).into()
+    }
+
+    fn call(&mut self, req: String) -> Self::Future {
+        Box::pin(async move { Ok(req.to_ascii_uppercase()) })
+    }
+}
+
+impl Describable<usize> for Uppercase {
+    fn describe(&self) -> usize {
+        0
+    }
+}
+
+fn sockets(dir: &std::path::Path) -> usize {
+    std::fs::read_dir(dir).unwrap().count()
+}
+
+#[tokio::test]
+async fn test_unix_sockets() {
+    let dir = std::env::temp_dir().join(format!("leaning-tower-unix-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let server_addr = format!("unix:{}", dir.join("allocator.sock").display());
+
+    let service =
+        AllocatorService::new(vec![Uppercase]).with_lease_bind(&format!("unix:{}", dir.display()));
+    let handle = mux_server::run(&server_addr, service).await.unwrap();
+
+    let mut allocator: AllocatorClientService<_, Uppercase, String> =
+        AllocatorClientService::new(&server_addr).await.unwrap();
+    let mut lease = allocator
+        .ready()
+        .await
+        .unwrap()
+        .call(0usize)
+        .await
+        .unwrap()
+        .unwrap();
+    let response = lease
+        .ready()
+        .await
+        .unwrap()
+        .call("hello".to_string())
+        .await
+        .unwrap();
+    assert_eq!(response, "HELLO");
+
+    // The allocator's socket, and the lease's.
+    assert_eq!(sockets(&dir), 2);
+    lease.release().await.unwrap();
+    assert_eq!(sockets(&dir), 1);
+
+    handle.abort();
+    let _ = handle.await;
+    assert_eq!(sockets(&dir), 0);
+    std::fs::remove_dir(&dir).unwrap();
+}